@@ -35,13 +35,17 @@ class ActivityTracker:
             self._rust_tracker = ActivityTrackerRust()
         else:
             # Python fallback
-            self._message_timestamps: Dict[int, list[datetime]] = defaultdict(list)
+            self._message_timestamps: Dict[Tuple[int, int], list[datetime]] = defaultdict(list)
             self._chat_activity: Dict[int, deque[Tuple[datetime, int]]] = defaultdict(deque)
-            self._chat_cooldowns: Dict[int, datetime] = {}
+            self._chat_cooldowns: Dict[Tuple[int, int], datetime] = {}
 
-    def check_spam(self, user_id: int, now: datetime | None = None) -> Tuple[bool, int]:
-        """Check if a user is spamming.
+    def check_spam(
+        self, user_id: int, now: datetime | None = None, guild_id: int = 0
+    ) -> Tuple[bool, int]:
+        """Check if a user is spamming in a guild.
         
+        Counts are tracked per (guild_id, user_id); guild_id=0 is a shared
+        global record for callers without guild context.
         Returns (is_spam, message_count_in_window).
         Spam threshold: >20 messages in 10 seconds.
         """
@@ -49,10 +53,10 @@ class ActivityTracker:
             now = datetime.now()
 
         if _USE_RUST:
-            return self._rust_tracker.check_spam(user_id, now.timestamp())
+            return self._rust_tracker.check_spam(user_id, now.timestamp(), guild_id)
 
         # Python fallback
-        timestamps = self._message_timestamps[user_id]
+        timestamps = self._message_timestamps[(guild_id, user_id)]
         
         # Clean old timestamps (older than 10 seconds)
         cutoff = now - timedelta(seconds=10)
@@ -69,6 +73,7 @@ class ActivityTracker:
     def record_chat_activity(
         self,
         guild_id: int,
+        channel_id: int,
         user_id: int,
         is_bot: bool,
         content: str | None,
@@ -81,12 +86,12 @@ class ActivityTracker:
         Criteria:
         - At least 6 messages in 20 seconds
         - At least 3 unique users
-        - 45 second cooldown between triggers
+        - 45 second cooldown between triggers in the same channel
         - 35% random chance when criteria met
-        """
-        if now is None:
-            now = datetime.utcnow()
 
+        `now` defaults to the current time; a naive datetime is taken as
+        local time, as `datetime.timestamp()` does.
+        """
         # Skip bots and commands
         if is_bot:
             return False
@@ -94,11 +99,15 @@ class ActivityTracker:
             return False
 
         if _USE_RUST:
+            # Without `now` the tracker reads its own clock; quiet hours need a true epoch
+            now_ts = now.timestamp() if now is not None else None
             return self._rust_tracker.record_chat_activity(
-                guild_id, user_id, now.timestamp()
+                guild_id, user_id, now_ts, channel_id=channel_id
             )
 
         # Python fallback
+        if now is None:
+            now = datetime.now()
         window = self._chat_activity[guild_id]
         window.append((now, user_id))
 
@@ -113,29 +122,34 @@ class ActivityTracker:
         if len(active_window) < 6 or len(unique_users) < 3:
             return False
 
-        # Check cooldown
-        last_trigger = self._chat_cooldowns.get(guild_id)
+        # Check the channel's cooldown
+        last_trigger = self._chat_cooldowns.get((guild_id, channel_id))
         if last_trigger and (now - last_trigger) < timedelta(seconds=45):
             return False
 
         # Random chance to trigger
         if random.random() < 0.35:
-            self._chat_cooldowns[guild_id] = now
+            self._chat_cooldowns[(guild_id, channel_id)] = now
             return True
 
         return False
 
-    def clear_user(self, user_id: int) -> None:
-        """Clear tracking data for a user."""
+    def clear_user(self, user_id: int, guild_id: int | None = None) -> None:
+        """Clear tracking data for a user in one guild, or everywhere if guild_id is None."""
         if _USE_RUST:
-            self._rust_tracker.clear_user(user_id)
+            self._rust_tracker.clear_user(user_id, guild_id)
         else:
-            self._message_timestamps.pop(user_id, None)
+            for key in list(self._message_timestamps):
+                if key[1] == user_id and (guild_id is None or key[0] == guild_id):
+                    del self._message_timestamps[key]
 
     def clear_guild(self, guild_id: int) -> None:
         """Clear tracking data for a guild."""
         if _USE_RUST:
             self._rust_tracker.clear_guild(guild_id)
         else:
+            for key in [k for k in self._message_timestamps if k[0] == guild_id]:
+                del self._message_timestamps[key]
             self._chat_activity.pop(guild_id, None)
-            self._chat_cooldowns.pop(guild_id, None)
+            for key in [k for k in self._chat_cooldowns if k[0] == guild_id]:
+                del self._chat_cooldowns[key]
@@ -0,0 +1,45 @@
+"""Benchmark text_contains_phrase on a 2000-character message and a 20-character phrase.
+
+Build the extension in release mode first (``maturin develop --release``), then run:
+
+    python benches/text_contains_phrase.py
+
+Release build, one core; per call, including Python call overhead:
+
+    case                 lowercase copies   fold as you go
+    ascii miss                0.32 us           0.33 us
+    ascii hit at end          0.32 us           0.33 us
+    unicode miss             15.42 us          15.39 us
+    unicode hit at end       15.14 us          11.37 us
+
+The old implementation missed "unicode hit at end" ("GROSSE STRASSE" vs "große straße").
+ASCII text takes a separate path that lowercases stack-sized chunks and runs
+a SIMD memmem over them; the ASCII rows are mostly Python call overhead (in
+Rust alone a miss is about 0.31 us against 0.42 us for lowercase copies).
+"""
+
+from __future__ import annotations
+
+import timeit
+
+from guildest_core import text_contains_phrase
+
+FILLER = "the quick brown fox jumps over the lazy dog "
+CASES = {
+    "ascii miss": ((FILLER * 50)[:2000], "absolutely not there"),
+    "ascii hit at end": ((FILLER * 50)[:1980] + "Free Nitro Giveaway!", "free nitro giveaway!"),
+    "unicode miss": (("Straße ΟΔΟΣ İstanbul café " * 80)[:2000], "nothing like this ok"),
+    "unicode hit at end": (("Straße ΟΔΟΣ İstanbul café " * 80)[:1980] + "GROSSE STRASSE ΟΔΟΣ!", "große straße οδος!"[:20]),
+}
+
+
+def main() -> None:
+    number = 20_000
+    for name, (text, phrase) in CASES.items():
+        assert len(text) == 2000 and len(phrase) <= 20
+        total = timeit.timeit(lambda: text_contains_phrase(text, phrase), number=number)
+        print(f"{name:<20} {total / number * 1e6:8.2f} us/call  -> {text_contains_phrase(text, phrase)}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,115 @@
+//! Embeds the metadata reported by `build_info()` and checks that every
+//! cargo feature tested with `cfg` has an entry in the `FEATURES` registry
+//! behind `has_feature()`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let config = pyo3_build_config::get();
+    println!("cargo:rustc-env=GUILDEST_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=GUILDEST_BUILD_TIMESTAMP={}", build_timestamp());
+    println!("cargo:rustc-env=GUILDEST_FEATURES={}", enabled_features().join(","));
+    println!("cargo:rustc-env=GUILDEST_PYO3_VERSION={}", locked_version("pyo3"));
+    println!("cargo:rustc-env=GUILDEST_PYTHON_VERSION={}", config.version);
+    println!("cargo:rustc-env=GUILDEST_PYTHON_IMPLEMENTATION={}", config.implementation);
+    println!("cargo:rustc-env=GUILDEST_ABI3={}", config.abi3);
+
+    check_feature_registry();
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// HEAD's hash, with `-dirty` when the crate has uncommitted changes, or
+/// "unknown" outside a git checkout (e.g. building from an sdist).
+fn git_commit() -> String {
+    let Some(commit) = git(&["rev-parse", "HEAD"]) else {
+        return "unknown".to_string();
+    };
+    // Rebuild when HEAD moves or files are staged/committed
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        for file in ["HEAD", "index"] {
+            let path = Path::new(&git_dir).join(file);
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+    match git(&["status", "--porcelain", "--untracked-files=no", "--", "."]) {
+        Some(status) if !status.is_empty() => format!("{}-dirty", commit),
+        _ => commit,
+    }
+}
+
+/// Unix seconds, taken from `SOURCE_DATE_EPOCH` for reproducible builds.
+fn build_timestamp() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()))
+}
+
+/// Enabled cargo features, as cargo exposes them to build scripts.
+fn enabled_features() -> Vec<String> {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    features
+}
+
+/// Version of `package` recorded in the nearest Cargo.lock.
+fn locked_version(package: &str) -> String {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    let lock = manifest_dir.ancestors().find_map(|dir| fs::read_to_string(dir.join("Cargo.lock")).ok());
+    let name_line = format!("name = \"{}\"", package);
+    lock.and_then(|lock| {
+        let mut lines = lock.lines();
+        lines.find(|line| *line == name_line)?;
+        let version = lines.next()?.strip_prefix("version = \"")?;
+        Some(version.trim_end_matches('"').to_string())
+    })
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Fail the build if src/lib.rs tests a cargo feature that has no
+/// `name: "<feature>"` entry in its `FEATURES` registry.
+fn check_feature_registry() {
+    let source = fs::read_to_string("src/lib.rs").expect("read src/lib.rs");
+    let registry = source
+        .split_once("static FEATURES: &[Feature] = &[")
+        .and_then(|(_, rest)| rest.split_once("];"))
+        .map(|(entries, _)| entries)
+        .expect("src/lib.rs must define `static FEATURES: &[Feature] = &[...];`");
+
+    let mut missing: Vec<&str> = source
+        .match_indices("feature = \"")
+        .filter_map(|(start, pattern)| {
+            let rest = &source[start + pattern.len()..];
+            rest.split_once('"').map(|(name, _)| name)
+        })
+        .filter(|name| !registry.contains(&format!("name: \"{}\"", name)))
+        .collect();
+    missing.sort_unstable();
+    missing.dedup();
+    if !missing.is_empty() {
+        panic!(
+            "cargo features used in cfg without a FEATURES registry entry: {}",
+            missing.join(", ")
+        );
+    }
+}
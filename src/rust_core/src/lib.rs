@@ -8,470 +8,9506 @@
 //! - Duration parsing
 //! - Async database writes via channel queue
 
+// pyo3 0.20's macros trip `non_local_definitions` on current toolchains.
+#![allow(non_local_definitions)]
+
 use pyo3::prelude::*;
-use dashmap::DashMap;
+use pyo3::types::{PyDict, PyList};
+use dashmap::{DashMap, DashSet};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use caseless::Caseless;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 use regex::Regex;
-use std::collections::VecDeque;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use xxhash_rust::xxh64::xxh64;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
-use std::sync::mpsc::{self, Sender, Receiver};
-use std::thread;
-use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock, Weak};
+
+/// Spam tracking key: (guild_id, user_id); guild_id 0 means "global"
+type SpamKey = (u64, u64);
+
+/// Global spam tracker: (guild_id, user_id) -> list of timestamps (as f64 seconds since epoch)
+static SPAM_TIMESTAMPS: LazyLock<DashMap<SpamKey, Vec<f64>>> = LazyLock::new(DashMap::new);
+
+/// Global spam exemptions: user IDs never flagged by `check_spam`
+static SPAM_EXEMPT: LazyLock<DashSet<u64>> = LazyLock::new(DashSet::new);
 
-/// Global spam tracker: user_id -> list of timestamps (as f64 seconds since epoch)
-static SPAM_TIMESTAMPS: LazyLock<DashMap<u64, Vec<f64>>> = LazyLock::new(DashMap::new);
+/// Global violation scores: user_id -> (score, timestamp the score was last updated)
+static VIOLATIONS: LazyLock<DashMap<u64, (f64, f64)>> = LazyLock::new(DashMap::new);
+
+/// Global decaying spam scores: (guild_id, user_id) -> (score, timestamp the score was last updated)
+static SPAM_DECAY: LazyLock<DashMap<SpamKey, (f64, f64)>> = LazyLock::new(DashMap::new);
+
+/// Global attachment tracker: (guild_id, user_id) -> deque of (timestamp, attachment hash)
+static ATTACHMENTS: LazyLock<DashMap<SpamKey, VecDeque<(f64, u64)>>> = LazyLock::new(DashMap::new);
+
+/// Decayed scores below this are dropped by `cleanup`
+const SCORE_EPSILON: f64 = 1e-3;
 
 /// Global chat activity tracker: guild_id -> deque of (timestamp, user_id)
 static CHAT_ACTIVITY: LazyLock<DashMap<u64, VecDeque<(f64, u64)>>> = LazyLock::new(DashMap::new);
 
-/// Global chat cooldowns: guild_id -> last trigger timestamp
+/// Channel key: (guild_id, channel_id)
+type ChannelKey = (u64, u64);
+
+/// Global per-channel activity: (guild_id, channel_id) -> deque of (timestamp, user_id)
+static CHANNEL_ACTIVITY: LazyLock<DashMap<ChannelKey, VecDeque<(f64, u64)>>> = LazyLock::new(DashMap::new);
+
+/// Last slowmode recommendation per channel, used for hysteresis
+static SLOWMODE_LEVELS: LazyLock<DashMap<ChannelKey, u32>> = LazyLock::new(DashMap::new);
+
+/// Recent posts of one message fingerprint in a guild.
+struct ContentPosts {
+    /// (timestamp, user_id), oldest first.
+    posts: VecDeque<(f64, u64)>,
+    /// Last-use tick in `GuildContent::by_use`.
+    tick: u64,
+}
+
+/// Fingerprinted messages in one guild, least recently posted evicted first.
+#[derive(Default)]
+struct GuildContent {
+    hashes: HashMap<u64, ContentPosts>,
+    /// Use tick -> content hash.
+    by_use: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+/// Most posts kept per fingerprint; older copies stop counting beyond this.
+const CONTENT_MAX_POSTS: usize = 1024;
+
+/// Global message fingerprints: guild_id -> recent copies of each content hash
+static GUILD_CONTENT: LazyLock<DashMap<u64, GuildContent>> = LazyLock::new(DashMap::new);
+
+/// Global per-channel chat cooldowns: (guild_id, channel_id) -> last trigger timestamp
+static CHANNEL_COOLDOWNS: LazyLock<DashMap<ChannelKey, f64>> = LazyLock::new(DashMap::new);
+
+/// Global chat cooldowns: guild_id -> last trigger timestamp in any channel
 static CHAT_COOLDOWNS: LazyLock<DashMap<u64, f64>> = LazyLock::new(DashMap::new);
 
-/// Duration parsing regex
-static DURATION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"^(\d+)([smhdw])$").unwrap()
-});
+/// Daily window, in guild-local time, when unsolicited chat triggers are off.
+#[derive(Clone, Copy)]
+struct QuietHours {
+    start_hour: u8,
+    end_hour: u8,
+    utc_offset_minutes: i32,
+}
 
-/// Truncate text to a maximum length, appending "..." if truncated.
-#[pyfunction]
-#[pyo3(signature = (text, limit = 1700))]
-fn truncate(text: &str, limit: usize) -> String {
-    if text.len() <= limit {
-        text.to_string()
-    } else if limit > 3 {
-        format!("{}...", &text[..limit - 3])
-    } else {
-        text[..limit].to_string()
+impl QuietHours {
+    /// Whether `now_ts` falls inside the window; windows may wrap midnight.
+    fn contains(&self, now_ts: f64) -> bool {
+        let local_minutes = (now_ts / 60.0).floor() as i64 + i64::from(self.utc_offset_minutes);
+        let minute_of_day = local_minutes.rem_euclid(24 * 60);
+        let (start, end) = (i64::from(self.start_hour) * 60, i64::from(self.end_hour) * 60);
+        if start < end {
+            (start..end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= start || minute_of_day < end
+        }
     }
 }
 
-/// Parse a duration string like "10m", "2h", "1d" into seconds.
-/// Returns None if the format is invalid.
-#[pyfunction]
-fn parse_duration_secs(duration: &str) -> Option<u64> {
-    let caps = DURATION_REGEX.captures(duration)?;
-    let value: u64 = caps.get(1)?.as_str().parse().ok()?;
-    let unit = caps.get(2)?.as_str();
+/// Validated `QuietHours`: hours 0-23, distinct, offset within UTC-12:00..UTC+14:00.
+fn quiet_hours(start_hour: u8, end_hour: u8, utc_offset_minutes: i32) -> PyResult<QuietHours> {
+    if start_hour > 23 || end_hour > 23 || start_hour == end_hour {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "quiet hours must be distinct hours from 0 to 23",
+        ));
+    }
+    if !(-12 * 60..=14 * 60).contains(&utc_offset_minutes) {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "utc_offset_minutes must be between -720 and 840",
+        ));
+    }
+    Ok(QuietHours { start_hour, end_hour, utc_offset_minutes })
+}
 
-    let multiplier: u64 = match unit {
-        "s" => 1,
-        "m" => 60,
-        "h" => 3600,
-        "d" => 86400,
-        "w" => 604800,
-        _ => return None,
-    };
+/// Global chat quiet hours: guild_id -> window
+static QUIET_HOURS: LazyLock<DashMap<u64, QuietHours>> = LazyLock::new(DashMap::new);
+
+/// A member join: (join timestamp, user_id, account created timestamp)
+type JoinRecord = (f64, u64, f64);
+
+/// Global member joins: guild_id -> deque of join records
+static GUILD_JOINS: LazyLock<DashMap<u64, VecDeque<JoinRecord>>> = LazyLock::new(DashMap::new);
+
+/// Format version written by `ActivityTrackerRust.export_state`.
+const TRACKER_STATE_VERSION: u32 = 4;
 
-    Some(value * multiplier)
+/// Serialized tracker state (see `export_state`).
+#[derive(Serialize, Deserialize)]
+struct TrackerSnapshot {
+    version: u32,
+    spam: Vec<(SpamKey, Vec<f64>)>,
+    chat: Vec<(u64, Vec<(f64, u64)>)>,
+    cooldowns: Vec<(u64, f64)>,
+    channel_cooldowns: Vec<(ChannelKey, f64)>,
+    joins: Vec<(u64, Vec<JoinRecord>)>,
+    attachments: Vec<(SpamKey, Vec<(f64, u64)>)>,
 }
 
-/// Check if text contains a phrase (case-insensitive).
-#[pyfunction]
-fn text_contains_phrase(text: &str, phrase: &str) -> bool {
-    text.to_lowercase().contains(&phrase.to_lowercase())
+/// Format version written by `ActivityTrackerRust.export_durable_state`.
+const DURABLE_STATE_VERSION: u32 = 1;
+
+/// Serialized guild and channel chat cooldowns, violation scores, spam
+/// exemptions, and quiet hours, plus ladder offenses and join gate settings
+/// when exported with them (see `export_durable_state`). Fields are named so
+/// stored copies stay readable.
+#[derive(Serialize, Deserialize)]
+struct DurableSnapshot {
+    version: u32,
+    cooldowns: Vec<DurableCooldown>,
+    channel_cooldowns: Vec<DurableChannelCooldown>,
+    violations: Vec<DurableViolation>,
+    spam_exempt: Vec<u64>,
+    #[serde(default)]
+    quiet_hours: Vec<DurableQuietHours>,
+    /// Punishment ladder history; only present when a ladder was exported.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ladder_offenses: Vec<DurableOffenses>,
+    /// Join gate settings; only present when a gate was exported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    join_gate: Option<DurableJoinGate>,
 }
 
-/// High-performance activity tracker for anti-spam and chat engagement.
-#[pyclass]
-struct ActivityTrackerRust {
-    spam_window_secs: f64,
-    spam_threshold: usize,
-    chat_window_secs: f64,
-    chat_active_window_secs: f64,
-    chat_min_messages: usize,
-    chat_min_users: usize,
-    chat_cooldown_secs: f64,
-    chat_trigger_chance: f64,
+#[derive(Serialize, Deserialize)]
+struct DurableCooldown {
+    guild_id: u64,
+    last_trigger_ts: f64,
 }
 
-#[pymethods]
-impl ActivityTrackerRust {
-    #[new]
-    #[pyo3(signature = ())]
-    fn new() -> Self {
-        ActivityTrackerRust {
-            spam_window_secs: 10.0,
-            spam_threshold: 20,
-            chat_window_secs: 30.0,
-            chat_active_window_secs: 20.0,
-            chat_min_messages: 6,
-            chat_min_users: 3,
-            chat_cooldown_secs: 45.0,
-            chat_trigger_chance: 0.35,
+#[derive(Serialize, Deserialize)]
+struct DurableChannelCooldown {
+    guild_id: u64,
+    channel_id: u64,
+    last_trigger_ts: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DurableViolation {
+    user_id: u64,
+    score: f64,
+    updated_ts: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DurableQuietHours {
+    guild_id: u64,
+    start_hour: u8,
+    end_hour: u8,
+    utc_offset_minutes: i32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DurableJoinGate {
+    min_account_age_secs: u64,
+    raid_mode_min_age_secs: u64,
+    flags: Vec<String>,
+    raid_mode: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DurableOffenses {
+    guild_id: u64,
+    user_id: u64,
+    offense_ts: Vec<f64>,
+}
+
+/// Just the version field, checked before parsing the rest of a snapshot.
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+/// Duration parsing regex. A sign is captured only to reject it with a clear error.
+static DURATION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([+-]?)([0-9]+)([smhdw])$").unwrap()
+});
+
+/// Unit a text length is measured in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LengthMode {
+    /// UTF-8 bytes (cuts never split a character).
+    Byte,
+    /// Unicode scalar values, like Python's `len()`.
+    Char,
+    /// Extended grapheme clusters, i.e. user-perceived characters.
+    Grapheme,
+}
+
+impl LengthMode {
+    fn parse(mode: &str) -> PyResult<Self> {
+        match mode {
+            "byte" => Ok(LengthMode::Byte),
+            "char" => Ok(LengthMode::Char),
+            "grapheme" => Ok(LengthMode::Grapheme),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown length mode '{}' (expected 'byte', 'char', or 'grapheme')",
+                mode
+            ))),
         }
     }
 
-    /// Check if a user is spamming.
-    /// Returns (is_spam, message_count_in_window).
-    fn check_spam(&self, user_id: u64, now_ts: f64) -> (bool, usize) {
-        let cutoff = now_ts - self.spam_window_secs;
+    /// Length of `text` in this unit.
+    fn len(self, text: &str) -> usize {
+        match self {
+            LengthMode::Byte => text.len(),
+            LengthMode::Char => text.chars().count(),
+            LengthMode::Grapheme => text.graphemes(true).count(),
+        }
+    }
 
-        let mut entry = SPAM_TIMESTAMPS.entry(user_id).or_insert_with(Vec::new);
-        
-        // Remove old timestamps
-        entry.retain(|&ts| ts > cutoff);
-        
-        // Add current timestamp
-        entry.push(now_ts);
+    /// Longest prefix of `text` that is at most `limit` units long.
+    fn prefix(self, text: &str, limit: usize) -> &str {
+        let end = match self {
+            LengthMode::Byte => {
+                let mut end = limit.min(text.len());
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                end
+            }
+            LengthMode::Char => text.char_indices().nth(limit).map_or(text.len(), |(i, _)| i),
+            LengthMode::Grapheme => text
+                .grapheme_indices(true)
+                .nth(limit)
+                .map_or(text.len(), |(i, _)| i),
+        };
+        &text[..end]
+    }
+}
 
-        let count = entry.len();
-        let is_spam = count > self.spam_threshold;
+/// Truncate text to a maximum length, ending with `suffix` if truncated.
+/// `mode` picks the unit: "byte" (default), "char", or "grapheme"; the
+/// suffix counts toward `limit` in the same unit. When the suffix would
+/// leave no room for text, the result is a plain cut with no suffix.
+#[pyfunction]
+#[pyo3(signature = (text, limit = 1700, mode = "byte", suffix = "..."))]
+fn truncate(text: &str, limit: usize, mode: &str, suffix: &str) -> PyResult<String> {
+    Ok(truncate_str(text, limit, LengthMode::parse(mode)?, suffix))
+}
 
-        (is_spam, count)
+fn truncate_str(text: &str, limit: usize, mode: LengthMode, suffix: &str) -> String {
+    if mode.len(text) <= limit {
+        return text.to_string();
     }
+    let suffix_len = mode.len(suffix);
+    if suffix_len < limit {
+        format!("{}{}", mode.prefix(text, limit - suffix_len), suffix)
+    } else {
+        mode.prefix(text, limit).to_string()
+    }
+}
 
-    /// Record chat activity and determine if bot should jump into conversation.
-    /// Returns true if the bot should send a reply.
-    fn record_chat_activity(&self, guild_id: u64, user_id: u64, now_ts: f64) -> bool {
-        let cleanup_cutoff = now_ts - self.chat_window_secs;
-        let active_cutoff = now_ts - self.chat_active_window_secs;
+/// Sentence ends for `truncate_smart`, including Hebrew sof pasuq.
+const SENTENCE_TERMINATORS: &[char] = &['.', '!', '?', '…', '\u{05C3}'];
 
-        // Get or create the activity deque for this guild
-        let mut entry = CHAT_ACTIVITY.entry(guild_id).or_insert_with(VecDeque::new);
+/// Closing punctuation that stays with the sentence before it.
+const SENTENCE_CLOSERS: &[char] = &['"', '\'', '”', '’', ')', ']', '»'];
 
-        // Add current activity
-        entry.push_back((now_ts, user_id));
+/// Words whose trailing "." does not end a sentence (lowercase, without the final dot).
+const ABBREVIATIONS: &[&str] = &[
+    "e.g", "i.e", "etc", "vs", "cf", "approx", "mr", "mrs", "ms", "dr", "prof", "st", "jr", "sr", "no", "fig",
+];
 
-        // Clean old entries
-        while let Some(&(ts, _)) = entry.front() {
-            if ts < cleanup_cutoff {
-                entry.pop_front();
-            } else {
+/// A smart truncation falls back to word boundaries when the last sentence
+/// or line boundary would keep less than this share of the room.
+const SMART_TRUNCATE_MIN_FILL: f64 = 0.6;
+
+/// Boundary `truncate_smart` prefers to cut at.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CutPreference {
+    Sentence,
+    /// Sentence ends or line breaks.
+    Line,
+}
+
+impl CutPreference {
+    fn parse(prefer: &str) -> PyResult<Self> {
+        match prefer {
+            "sentence" => Ok(CutPreference::Sentence),
+            "line" => Ok(CutPreference::Line),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown cut preference '{}' (expected 'sentence' or 'line')",
+                prefer
+            ))),
+        }
+    }
+}
+
+/// Byte offset just past the last sentence (or line) ending within
+/// `text[..room]`. A terminator only counts when followed, after any closing
+/// quotes or brackets, by whitespace or the end of the text, so "3.5" and
+/// "..." mid-word are skipped, as are listed abbreviations such as "e.g.".
+fn last_sentence_end(text: &str, room: usize, prefer: CutPreference) -> Option<usize> {
+    let mut best = None;
+    for (i, c) in text[..room].char_indices() {
+        if c == '\n' && prefer == CutPreference::Line {
+            if i > 0 {
+                best = Some(i);
+            }
+            continue;
+        }
+        if !SENTENCE_TERMINATORS.contains(&c) {
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        for next in text[end..].chars() {
+            if !SENTENCE_CLOSERS.contains(&next) {
                 break;
             }
+            end += next.len_utf8();
         }
-
-        // Count active messages and unique users in the active window
-        let mut active_count = 0;
-        let mut unique_users = std::collections::HashSet::new();
-
-        for &(ts, uid) in entry.iter() {
-            if ts >= active_cutoff {
-                active_count += 1;
-                unique_users.insert(uid);
+        if end > room || text[end..].chars().next().is_some_and(|next| !next.is_whitespace()) {
+            continue;
+        }
+        if c == '.' {
+            let word = text[..i].rsplit(char::is_whitespace).next().unwrap_or("");
+            let word = word.trim_start_matches(['(', '"', '\'', '“', '‘']).to_lowercase();
+            if ABBREVIATIONS.contains(&word.as_str()) {
+                continue;
             }
         }
+        best = Some(end);
+    }
+    best
+}
 
-        // Check thresholds
-        if active_count < self.chat_min_messages || unique_users.len() < self.chat_min_users {
-            return false;
+/// Truncate at the end of the last complete sentence (or line, with
+/// `prefer="line"`) that fits, then append `suffix`. If that would keep less
+/// than 60% of the room, cut at the last word boundary instead, or mid-word
+/// when there is none. Lengths are measured as in `truncate`.
+/// Returns (text, was_truncated).
+#[pyfunction]
+#[pyo3(signature = (text, limit, prefer = "sentence", mode = "byte", suffix = "..."))]
+fn truncate_smart(text: &str, limit: usize, prefer: &str, mode: &str, suffix: &str) -> PyResult<(String, bool)> {
+    let prefer = CutPreference::parse(prefer)?;
+    let mode = LengthMode::parse(mode)?;
+    if mode.len(text) <= limit {
+        return Ok((text.to_string(), false));
+    }
+    let suffix_len = mode.len(suffix);
+    if suffix_len >= limit {
+        return Ok((mode.prefix(text, limit).to_string(), true));
+    }
+    let cut = smart_cut(text, limit - suffix_len, prefer, mode);
+    Ok((format!("{}{}", text[..cut].trim_end(), suffix), true))
+}
+
+/// Byte offset to cut `text` at so the head fits in `room` units: the last
+/// sentence (or line) end, unless that keeps under 60% of the room, then the
+/// last word boundary, then a hard cut.
+fn smart_cut(text: &str, room: usize, prefer: CutPreference, mode: LengthMode) -> usize {
+    let window = mode.prefix(text, room);
+    let min_fill = (room as f64 * SMART_TRUNCATE_MIN_FILL).ceil() as usize;
+    last_sentence_end(text, window.len(), prefer)
+        .filter(|&end| mode.len(&text[..end]) >= min_fill)
+        .or_else(|| {
+            window
+                .char_indices()
+                .rev()
+                .find(|&(i, c)| c.is_whitespace() && !window[..i].trim_end().is_empty())
+                .map(|(i, _)| i)
+        })
+        .unwrap_or(window.len())
+}
+
+/// Discord embed limits, in characters.
+const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+const EMBED_FIELD_NAME_LIMIT: usize = 256;
+const EMBED_FIELD_VALUE_LIMIT: usize = 1024;
+const EMBED_FOOTER_LIMIT: usize = 2048;
+const EMBED_AUTHOR_LIMIT: usize = 256;
+const EMBED_MAX_FIELDS: usize = 25;
+const EMBED_TOTAL_LIMIT: usize = 6000;
+
+/// Split text into embed field values of at most `field_limit` each, whose
+/// lengths add up to at most `total_limit`, cutting at line breaks and
+/// sentence ends where possible (as `truncate_smart` with `prefer="line"`).
+/// Returns at most `max_fields` chunks; when the text doesn't fit, the last
+/// one is truncated and ends with `suffix`. Lengths are measured in `mode`
+/// units as in `truncate` ("char" by default, as Discord counts). Leave room
+/// in `total_limit` for the title, description, and field names.
+#[pyfunction]
+#[pyo3(signature = (text, field_limit = EMBED_FIELD_VALUE_LIMIT, total_limit = EMBED_TOTAL_LIMIT, max_fields = EMBED_MAX_FIELDS, mode = "char", suffix = "..."))]
+fn pack_embed_fields(
+    text: &str,
+    field_limit: usize,
+    total_limit: usize,
+    max_fields: usize,
+    mode: &str,
+    suffix: &str,
+) -> PyResult<Vec<String>> {
+    let mode = LengthMode::parse(mode)?;
+    if field_limit == 0 || total_limit == 0 || max_fields == 0 {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "field_limit, total_limit, and max_fields must be positive",
+        ));
+    }
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    let mut budget = total_limit;
+    while !rest.is_empty() {
+        let room = field_limit.min(budget);
+        if mode.len(rest) <= room {
+            chunks.push(rest.to_string());
+            break;
         }
+        let cut = smart_cut(rest, room, CutPreference::Line, mode);
+        if chunks.len() + 1 == max_fields || budget <= field_limit || cut == 0 {
+            let suffix_len = mode.len(suffix);
+            let last = if suffix_len >= room {
+                mode.prefix(rest, room).to_string()
+            } else {
+                let cut = smart_cut(rest, room - suffix_len, CutPreference::Line, mode);
+                format!("{}{}", rest[..cut].trim_end(), suffix)
+            };
+            chunks.push(last);
+            break;
+        }
+        let chunk = rest[..cut].trim_end();
+        budget -= mode.len(chunk);
+        chunks.push(chunk.to_string());
+        rest = rest[cut..].trim_start();
+    }
+    Ok(chunks)
+}
 
-        // Check cooldown
-        if let Some(last_trigger) = CHAT_COOLDOWNS.get(&guild_id) {
-            if (now_ts - *last_trigger) < self.chat_cooldown_secs {
-                return false;
+/// Whether an embed passes Discord's limits: title 256, description 4096,
+/// at most 25 fields with non-empty names of at most 256 and non-empty
+/// values of at most 1024, footer 2048, author name 256, and 6000 across
+/// all of them. Lengths are counted in `mode` units ("char" by default).
+#[pyfunction]
+#[pyo3(signature = (title, description, fields, footer = None, author = None, mode = "char"))]
+fn fits_embed(
+    title: Option<&str>,
+    description: Option<&str>,
+    fields: Vec<(String, String)>,
+    footer: Option<&str>,
+    author: Option<&str>,
+    mode: &str,
+) -> PyResult<bool> {
+    let mode = LengthMode::parse(mode)?;
+    let mut total = 0;
+    let mut within = |text: Option<&str>, limit: usize| {
+        let len = text.map_or(0, |text| mode.len(text));
+        total += len;
+        len <= limit
+    };
+    let parts_fit = within(title, EMBED_TITLE_LIMIT)
+        & within(description, EMBED_DESCRIPTION_LIMIT)
+        & within(footer, EMBED_FOOTER_LIMIT)
+        & within(author, EMBED_AUTHOR_LIMIT);
+    let fields_fit = fields.len() <= EMBED_MAX_FIELDS
+        && fields.iter().all(|(name, value)| {
+            !name.trim().is_empty()
+                && !value.trim().is_empty()
+                && within(Some(name), EMBED_FIELD_NAME_LIMIT)
+                & within(Some(value), EMBED_FIELD_VALUE_LIMIT)
+        });
+    Ok(parts_fit && fields_fit && total <= EMBED_TOTAL_LIMIT)
+}
+
+/// Number of user-perceived characters (grapheme clusters) in text.
+/// A ZWJ emoji sequence such as a family emoji counts as 1.
+#[pyfunction]
+fn display_length(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+/// Clean up text for matching and comparison in one streaming pass:
+/// Unicode NFKC, full Unicode case folding ("ẞ" becomes "ss"), accent and
+/// diacritic removal, and collapsing whitespace runs to one space with the
+/// ends trimmed. Each step has its own flag.
+#[pyfunction]
+#[pyo3(signature = (text, nfkc = true, casefold = true, collapse_ws = true, strip_accents = false))]
+fn normalize_text(text: &str, nfkc: bool, casefold: bool, collapse_ws: bool, strip_accents: bool) -> String {
+    normalize_str(text, nfkc, casefold, collapse_ws, strip_accents)
+}
+
+fn normalize_str(text: &str, nfkc: bool, casefold: bool, collapse_ws: bool, strip_accents: bool) -> String {
+    let mut chars: Box<dyn Iterator<Item = char> + '_> = Box::new(text.chars());
+    if strip_accents {
+        // Decompose so accents become separate marks, drop them, then recompose
+        chars = if nfkc { Box::new(chars.nfkd()) } else { Box::new(chars.nfd()) };
+        chars = Box::new(chars.filter(|&c| !unicode_normalization::char::is_combining_mark(c)));
+        chars = if nfkc { Box::new(chars.nfkc()) } else { Box::new(chars.nfc()) };
+    } else if nfkc {
+        chars = Box::new(chars.nfkc());
+    }
+    if casefold {
+        chars = Box::new(chars.default_case_fold());
+    }
+    if !collapse_ws {
+        return chars.collect();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut pending_space = false;
+    for c in chars {
+        if c.is_whitespace() {
+            pending_space = !out.is_empty();
+        } else {
+            if pending_space {
+                out.push(' ');
+                pending_space = false;
             }
+            out.push(c);
         }
+    }
+    out
+}
 
-        // Random chance to trigger
-        let rand_val: f64 = rand_simple(now_ts, guild_id, user_id);
-        if rand_val < self.chat_trigger_chance {
-            CHAT_COOLDOWNS.insert(guild_id, now_ts);
-            return true;
-        }
+/// Character classes for `estimate_tokens`; each run of one class is costed as a whole.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TokenClass {
+    /// Latin letters, including accented ones.
+    Latin,
+    Digit,
+    Space,
+    Newline,
+    Punct,
+    /// Greek and Cyrillic, which cl100k merges about three letters at a time.
+    Cyrillic,
+    /// Han, kana, and Hangul: about one token per character.
+    Cjk,
+    /// Other letters (Hebrew, Arabic, Thai, ...): nearly one token per letter.
+    OtherLetter,
+    /// Emoji, symbols, and combining marks.
+    Symbol,
+}
 
-        false
+impl TokenClass {
+    fn of(c: char) -> Self {
+        match c {
+            '\n' | '\r' => TokenClass::Newline,
+            c if c.is_whitespace() => TokenClass::Space,
+            c if c.is_ascii_digit() => TokenClass::Digit,
+            c if c.is_ascii_punctuation() => TokenClass::Punct,
+            '\u{0370}'..='\u{052F}' => TokenClass::Cyrillic,
+            '\u{1100}'..='\u{11FF}' | '\u{3040}'..='\u{30FF}' | '\u{3400}'..='\u{9FFF}' | '\u{AC00}'..='\u{D7AF}' => {
+                TokenClass::Cjk
+            }
+            c if c.is_alphabetic() && c < '\u{0250}' => TokenClass::Latin,
+            c if c.is_alphabetic() => TokenClass::OtherLetter,
+            c if c.is_numeric() => TokenClass::Digit,
+            _ => TokenClass::Symbol,
+        }
     }
 
-    /// Clear tracking data for a user.
-    fn clear_user(&self, user_id: u64) {
-        SPAM_TIMESTAMPS.remove(&user_id);
+    /// Estimated tokens for a run of `chars` characters (`bytes` in UTF-8) of
+    /// this class; `upper` says the whole run is uppercase.
+    fn run_tokens(self, chars: usize, bytes: usize, upper: bool) -> usize {
+        match self {
+            // Most words are one token; long or shouted ones split up
+            TokenClass::Latin if upper && chars > 3 => chars.div_ceil(3),
+            TokenClass::Latin => 1 + chars.saturating_sub(8).div_ceil(5),
+            // cl100k groups digits in threes
+            TokenClass::Digit => chars.div_ceil(3),
+            // A single space merges into the next word
+            TokenClass::Space => usize::from(chars > 1),
+            TokenClass::Newline => 1,
+            // Common runs like "..." or "!!" are single tokens
+            TokenClass::Punct => 1 + (chars - 1) / 3,
+            TokenClass::Cyrillic => chars.div_ceil(3),
+            TokenClass::Cjk => chars,
+            TokenClass::OtherLetter => (chars * 9).div_ceil(10),
+            TokenClass::Symbol => bytes.div_ceil(2),
+        }
     }
+}
 
-    /// Clear tracking data for a guild.
-    fn clear_guild(&self, guild_id: u64) {
-        CHAT_ACTIVITY.remove(&guild_id);
-        CHAT_COOLDOWNS.remove(&guild_id);
+/// Approximate cl100k_base token count for `text`. See `estimate_tokens`.
+fn approx_tokens(text: &str) -> usize {
+    let mut total = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(first) = chars.next() {
+        let class = TokenClass::of(first);
+        let (mut count, mut bytes, mut upper) = (1, first.len_utf8(), first.is_uppercase());
+        while let Some(&next) = chars.peek() {
+            if TokenClass::of(next) != class {
+                break;
+            }
+            count += 1;
+            bytes += next.len_utf8();
+            upper &= next.is_uppercase();
+            chars.next();
+        }
+        total += class.run_tokens(count, bytes, upper);
+        // An apostrophe inside a word only starts a suffix token ("don" + "'t")
+        if class == TokenClass::Latin && chars.peek() == Some(&'\'') {
+            let mut lookahead = chars.clone();
+            lookahead.next();
+            if lookahead.peek().is_some_and(|c| TokenClass::of(*c) == TokenClass::Latin) {
+                chars.next();
+            }
+        }
     }
+    total
 }
 
-/// Simple pseudo-random function using timestamp and IDs as seed.
-/// Not cryptographically secure, but fine for triggering chat responses.
-fn rand_simple(ts: f64, guild_id: u64, user_id: u64) -> f64 {
-    let seed = (ts.to_bits() ^ guild_id ^ user_id).wrapping_mul(0x5851_f42d_4c95_7f2d);
-    let hash = seed.wrapping_add(seed >> 33).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
-    let result = hash.wrapping_add(hash >> 29).wrapping_mul(0x94d0_49bb_1331_11eb);
-    (result as f64) / (u64::MAX as f64)
+/// Estimate how many cl100k_base tokens `text` encodes to, from runs of
+/// letters, digits, punctuation, and whitespace with per-class weights.
+/// Expect roughly 10% error on English prose and up to about 25% on Hebrew
+/// and other non-Latin scripts; short strings can be off by a token or two.
+#[pyfunction]
+fn estimate_tokens(text: &str) -> usize {
+    approx_tokens(text)
 }
 
-/// Pseudo-random generator using two integer seeds.
-fn rand_from_pair(a: u64, b: u64) -> f64 {
-    let seed = a ^ b.rotate_left(13) ^ 0x9e37_79b9_7f4a_7c15;
-    let mixed = seed
-        .wrapping_mul(0xff51_afd7_ed55_8ccd)
-        .wrapping_add(seed.rotate_right(17));
-    (mixed as f64) / (u64::MAX as f64)
+/// `estimate_tokens` for each string, in order.
+#[pyfunction]
+fn estimate_tokens_batch(texts: Vec<&str>) -> Vec<usize> {
+    texts.into_iter().map(approx_tokens).collect()
 }
 
-// ============================================
-// Economy helpers (hot paths exposed to Python)
-// ============================================
-
-#[pyclass]
-struct EconomyEngine {
-    elite_threshold: f64,
-    middle_threshold: f64,
-    min_wage: f64,
-    income_tax_rate: f64,
-    wealth_tax_rate: f64,
+/// Which end of the history `trim_to_token_budget` keeps.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum KeepMode {
+    Recent,
+    Oldest,
 }
 
-#[pymethods]
-impl EconomyEngine {
-    #[new]
-    #[pyo3(signature = (
-        elite_threshold = 100_000.0,
-        middle_threshold = 10_000.0,
-        min_wage = 400.0,
-        income_tax_rate = 0.15,
-        wealth_tax_rate = 0.0
-    ))]
-    fn new(
-        elite_threshold: f64,
-        middle_threshold: f64,
-        min_wage: f64,
-        income_tax_rate: f64,
-        wealth_tax_rate: f64,
-    ) -> Self {
-        EconomyEngine {
-            elite_threshold,
-            middle_threshold,
-            min_wage,
-            income_tax_rate,
-            wealth_tax_rate,
+impl KeepMode {
+    fn parse(keep: &str) -> PyResult<Self> {
+        match keep {
+            "recent" => Ok(KeepMode::Recent),
+            "oldest" => Ok(KeepMode::Oldest),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown keep mode '{}' (expected 'recent' or 'oldest')",
+                keep
+            ))),
         }
     }
+}
 
-    /// Classify a citizen's class tier based on balance thresholds.
-    fn classify(&self, balance: f64) -> String {
-        if balance >= self.elite_threshold {
-            "elite".to_string()
-        } else if balance >= self.middle_threshold {
-            "middle".to_string()
+/// Longest `truncate` (by characters) of `text` estimated at no more than
+/// `max_tokens`, or None if not even one character fits.
+fn truncate_to_tokens(text: &str, max_tokens: usize) -> Option<String> {
+    let (mut lo, mut hi) = (0, text.chars().count());
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if approx_tokens(&truncate_str(text, mid + 3, LengthMode::Char, "...")) <= max_tokens {
+            lo = mid;
         } else {
-            "working".to_string()
+            hi = mid - 1;
         }
     }
+    (lo > 0).then(|| truncate_str(text, lo + 3, LengthMode::Char, "..."))
+}
 
-    /// Calculate a single work action payout with XP bonus and variance.
-    fn work_payout(&self, annual_salary: f64, work_xp: i64, seed_a: u64, seed_b: u64) -> f64 {
-        let base_pay = annual_salary.max(self.min_wage) / 365.0 * 5.0;
-        let xp_bonus = 1.0 + (work_xp as f64 / 1000.0);
-        let variance = 0.8 + rand_from_pair(seed_a, seed_b) * 0.4;
-        base_pay * xp_bonus * variance
+/// Drop whole messages (oldest first, or newest first with `keep="oldest"`)
+/// until the estimated total fits `budget`, counting `per_message_overhead`
+/// tokens per kept message for role and name. With `truncate_partial`, the
+/// message at the cut is truncated to fill the remaining budget instead of
+/// dropped; that is always done when not even one message would fit whole.
+/// Returns the kept messages in their original order and their estimated total.
+#[pyfunction]
+#[pyo3(signature = (messages, budget, keep = "recent", per_message_overhead = 4, truncate_partial = false))]
+fn trim_to_token_budget(
+    mut messages: Vec<String>,
+    budget: usize,
+    keep: &str,
+    per_message_overhead: usize,
+    truncate_partial: bool,
+) -> PyResult<(Vec<String>, usize)> {
+    let keep = KeepMode::parse(keep)?;
+    if keep == KeepMode::Recent {
+        messages.reverse();
     }
 
-    /// Compute income tax on a given income value.
-    fn income_tax(&self, income: f64) -> f64 {
-        if income <= 0.0 || self.income_tax_rate <= 0.0 {
-            0.0
-        } else {
-            income * self.income_tax_rate
+    let mut kept = Vec::new();
+    let mut total = 0;
+    for message in messages {
+        let cost = approx_tokens(&message) + per_message_overhead;
+        if total + cost <= budget {
+            total += cost;
+            kept.push(message);
+            continue;
+        }
+        if truncate_partial || kept.is_empty() {
+            let room = budget.saturating_sub(total + per_message_overhead);
+            if let Some(cut) = truncate_to_tokens(&message, room) {
+                total += approx_tokens(&cut) + per_message_overhead;
+                kept.push(cut);
+            }
         }
+        break;
     }
 
-    /// Compute wealth tax over a threshold (default 50k in Python caller).
-    fn wealth_tax(&self, balance: f64, threshold: f64) -> f64 {
-        if balance <= threshold || self.wealth_tax_rate <= 0.0 {
-            0.0
-        } else {
-            (balance - threshold) * self.wealth_tax_rate
-        }
+    if keep == KeepMode::Recent {
+        kept.reverse();
     }
+    Ok((kept, total))
 }
 
-// ============================================
-// Database Writer with async queue
-// ============================================
+/// Why a duration string was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DurationError {
+    Format,
+    Signed,
+    Zero,
+    Overflow,
+}
 
-/// A database write operation to be queued
-#[derive(Clone)]
-enum DbWriteOp {
-    Transcription {
-        guild_id: u64,
-        channel_id: u64,
-        user_id: u64,
-        content: String,
-        username: String,
-        duration_secs: f64,
-    },
-    Generic {
-        table: String,
-        data: String, // JSON serialized
-    },
-    Shutdown,
+impl DurationError {
+    fn message(self) -> &'static str {
+        match self {
+            DurationError::Format => "expected a whole number followed by s, m, h, d, or w",
+            DurationError::Signed => "durations cannot have a sign",
+            DurationError::Zero => "duration must be greater than zero",
+            DurationError::Overflow => "duration is too large",
+        }
+    }
 }
 
-/// Async database writer that queues writes to a background thread.
-/// This prevents database writes from blocking the Python async loop.
-#[pyclass]
-struct DatabaseWriter {
-    sender: Sender<DbWriteOp>,
-    pending_count: Arc<Mutex<usize>>,
+fn parse_duration(duration: &str) -> Result<u64, DurationError> {
+    let caps = DURATION_REGEX.captures(duration).ok_or(DurationError::Format)?;
+    if !caps[1].is_empty() {
+        return Err(DurationError::Signed);
+    }
+    // The regex guarantees ASCII digits, so a failed parse can only be overflow
+    let value: u64 = caps[2].parse().map_err(|_| DurationError::Overflow)?;
+
+    let multiplier: u64 = match &caps[3] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 604800,
+        _ => return Err(DurationError::Format),
+    };
+
+    match value.checked_mul(multiplier) {
+        Some(0) => Err(DurationError::Zero),
+        Some(secs) => Ok(secs),
+        None => Err(DurationError::Overflow),
+    }
 }
 
-#[pymethods]
-impl DatabaseWriter {
-    #[new]
-    fn new() -> PyResult<Self> {
-        let (sender, receiver): (Sender<DbWriteOp>, Receiver<DbWriteOp>) = mpsc::channel();
-        let pending_count = Arc::new(Mutex::new(0usize));
-        let pending_clone = pending_count.clone();
-
-        // Spawn background thread to process writes
-        thread::spawn(move || {
-            DatabaseWriter::process_writes(receiver, pending_clone);
-        });
+/// Parse a duration string like "10m", "2h", "1d" into seconds.
+/// Returns None if the format is invalid, the duration is zero, the value
+/// overflows, or it has a sign ("-5m" and "+5m" are both rejected).
+#[pyfunction]
+fn parse_duration_secs(duration: &str) -> Option<u64> {
+    parse_duration(duration).ok()
+}
+
+/// Like `parse_duration_secs`, but raises ValueError saying why the string
+/// was rejected: bad format, a sign, zero, or overflow.
+#[pyfunction]
+fn parse_duration_checked(duration: &str) -> PyResult<u64> {
+    parse_duration(duration).map_err(|e| {
+        PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid duration '{}': {}", duration, e.message()))
+    })
+}
+
+/// Check if text contains a phrase, ignoring case by full Unicode case
+/// folding: "STRASSE" matches "straße" and "ΟΔΟΣ" matches "οδος".
+#[pyfunction]
+fn text_contains_phrase(text: &str, phrase: &str) -> bool {
+    contains_casefolded(text, phrase)
+}
 
-        Ok(DatabaseWriter { sender, pending_count })
+/// Case-folded substring search without allocating. Each candidate start in
+/// the folded text is checked by folding both strings as the comparison goes.
+/// Starts include positions inside a character's multi-character fold, so
+/// "sse" is found in "Straße".
+fn contains_casefolded(text: &str, phrase: &str) -> bool {
+    if text.is_ascii() && phrase.is_ascii() {
+        return contains_ascii_caseless(text.as_bytes(), phrase.as_bytes());
     }
+    let Some(first) = phrase.chars().default_case_fold().next() else {
+        return true;
+    };
+    text.char_indices().any(|(i, c)| {
+        if c.is_ascii() {
+            // ASCII folds to one ASCII character; skip the fold table
+            return c.to_ascii_lowercase() == first && folded_starts_with(&text[i..], 0, phrase);
+        }
+        std::iter::once(c)
+            .default_case_fold()
+            .enumerate()
+            .any(|(skip, folded)| folded == first && folded_starts_with(&text[i..], skip, phrase))
+    })
+}
 
-    /// Queue a transcription to be saved.
-    fn queue_transcription(
-        &self,
-        guild_id: u64,
-        channel_id: u64,
-        user_id: u64,
-        content: String,
-        username: String,
-        duration_secs: f64,
-    ) -> PyResult<()> {
-        let op = DbWriteOp::Transcription {
-            guild_id,
-            channel_id,
-            user_id,
-            content,
-            username,
-            duration_secs,
-        };
-        
-        if let Ok(mut count) = self.pending_count.lock() {
-            *count += 1;
+/// Longest phrase `contains_ascii_caseless` lowercases on the stack; longer
+/// ones fall back to checking each candidate start in place.
+const ASCII_PHRASE_MAX: usize = 128;
+
+/// Bytes of text `contains_ascii_caseless` lowercases per pass.
+const ASCII_CHUNK: usize = 1024;
+
+/// ASCII-only `contains_casefolded`: lowercases the text a stack-sized chunk
+/// at a time (overlapping by the phrase length) and runs a SIMD `memmem`
+/// search for the lowercased phrase over each chunk.
+fn contains_ascii_caseless(text: &[u8], phrase: &[u8]) -> bool {
+    if phrase.len() > text.len() {
+        return false;
+    }
+    if phrase.len() > ASCII_PHRASE_MAX {
+        return contains_ascii_caseless_in_place(text, phrase);
+    }
+    let mut needle = [0u8; ASCII_PHRASE_MAX];
+    let needle = &mut needle[..phrase.len()];
+    needle.copy_from_slice(phrase);
+    needle.make_ascii_lowercase();
+    let finder = memchr::memmem::Finder::new(needle);
+    let mut buffer = [0u8; ASCII_CHUNK];
+    let mut start = 0;
+    loop {
+        let end = (start + ASCII_CHUNK).min(text.len());
+        let chunk = &mut buffer[..end - start];
+        chunk.copy_from_slice(&text[start..end]);
+        chunk.make_ascii_lowercase();
+        if finder.find(chunk).is_some() {
+            return true;
         }
-        
-        self.sender.send(op).map_err(|e| {
-            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to queue write: {}", e))
-        })
+        if end == text.len() {
+            return false;
+        }
+        // Back up so a match straddling the chunk boundary is seen whole
+        start = end + 1 - phrase.len().max(1);
     }
+}
 
-    /// Queue a generic database write (JSON data).
-    fn queue_write(&self, table: String, json_data: String) -> PyResult<()> {
-        let op = DbWriteOp::Generic {
-            table,
-            data: json_data,
+/// `contains_ascii_caseless` for long phrases: candidate starts come from a
+/// memchr scan for either case of the phrase's first byte.
+fn contains_ascii_caseless_in_place(text: &[u8], phrase: &[u8]) -> bool {
+    let Some(&first) = phrase.first() else {
+        return true;
+    };
+    let fits = |start: usize| {
+        text.get(start..start + phrase.len())
+            .is_some_and(|window| window.eq_ignore_ascii_case(phrase))
+    };
+    let (lower, upper) = (first.to_ascii_lowercase(), first.to_ascii_uppercase());
+    if lower == upper {
+        memchr::memchr_iter(first, text).any(fits)
+    } else {
+        memchr::memchr2_iter(lower, upper, text).any(fits)
+    }
+}
+
+/// Whether the case folding of `text`, minus its first `skip` folded
+/// characters, starts with the case folding of `phrase`.
+fn folded_starts_with(text: &str, skip: usize, phrase: &str) -> bool {
+    let mut text = text.chars().default_case_fold().skip(skip);
+    phrase.chars().default_case_fold().all(|p| text.next() == Some(p))
+}
+
+/// Most edits `text_contains_phrase_approx` accepts.
+const MAX_PHRASE_EDITS: usize = 3;
+
+/// Case-folded characters of `text`, each with the index of the original
+/// character it came from (full folding can turn one character into several).
+fn folded_chars(text: &str) -> (Vec<char>, Vec<usize>) {
+    let mut chars = Vec::with_capacity(text.len());
+    let mut origin = Vec::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        for folded in std::iter::once(c).default_case_fold() {
+            chars.push(folded);
+            origin.push(i);
+        }
+    }
+    (chars, origin)
+}
+
+/// First position where some substring of `text` ending there is within
+/// `max_edits` edits of `pattern`: (end index, edits). Edits are insertions,
+/// deletions, substitutions, and swaps of adjacent characters ("nirto" is one
+/// edit from "nitro"). Bit-parallel (Myers, with Hyyrö's transposition
+/// extension) for patterns up to 64 characters, plain DP columns otherwise.
+fn approx_match_end(pattern: &[char], text: &[char], max_edits: usize) -> Option<(usize, usize)> {
+    let m = pattern.len();
+    if m == 0 {
+        return Some((0, 0));
+    }
+    if m > 64 {
+        // Row 0 stays 0 in every column: a match may start anywhere
+        let mut before: Vec<usize> = (0..=m).collect();
+        let mut previous: Vec<usize> = (0..=m).collect();
+        let mut column = vec![0; m + 1];
+        for (j, &c) in text.iter().enumerate() {
+            for i in 1..=m {
+                let cost = usize::from(pattern[i - 1] != c);
+                let mut best = (previous[i - 1] + cost).min(previous[i] + 1).min(column[i - 1] + 1);
+                if i > 1 && j > 0 && pattern[i - 1] == text[j - 1] && pattern[i - 2] == c {
+                    best = best.min(before[i - 2] + 1);
+                }
+                column[i] = best;
+            }
+            if column[m] <= max_edits {
+                return Some((j, column[m]));
+            }
+            std::mem::swap(&mut before, &mut previous);
+            std::mem::swap(&mut previous, &mut column);
+        }
+        return None;
+    }
+
+    let mut peq: HashMap<char, u64> = HashMap::new();
+    for (i, &c) in pattern.iter().enumerate() {
+        *peq.entry(c).or_default() |= 1 << i;
+    }
+    let mask = if m == 64 { u64::MAX } else { (1u64 << m) - 1 };
+    let high = 1u64 << (m - 1);
+    let (mut vp, mut vn, mut score) = (mask, 0u64, m);
+    let (mut prev_d0, mut prev_eq) = (0u64, 0u64);
+    for (j, c) in text.iter().enumerate() {
+        let eq = peq.get(c).copied().unwrap_or(0);
+        let transposed = ((!prev_d0 & eq) << 1) & prev_eq;
+        let d0 = ((((eq & vp).wrapping_add(vp)) ^ vp) | eq | vn | transposed) & mask;
+        let mut hp = vn | !(d0 | vp);
+        let mut hn = vp & d0;
+        if hp & high != 0 {
+            score += 1;
+        } else if hn & high != 0 {
+            score -= 1;
+        }
+        hp = (hp << 1) & mask;
+        hn = (hn << 1) & mask;
+        vp = (hn | !(d0 | hp)) & mask;
+        vn = hp & d0;
+        prev_d0 = d0;
+        prev_eq = eq;
+        if score <= max_edits {
+            return Some((j, score));
+        }
+    }
+    None
+}
+
+/// Fold text and phrase, validate `max_edits`, and find the first fuzzy match
+/// as (start, end, edits) over original character offsets, `end` exclusive.
+/// Phrases shorter than `max_edits + 2` characters only match exactly.
+fn find_phrase_approx_in(text: &str, phrase: &str, max_edits: usize) -> PyResult<Option<(usize, usize, usize)>> {
+    if max_edits > MAX_PHRASE_EDITS {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "max_edits must be at most {}",
+            MAX_PHRASE_EDITS
+        )));
+    }
+    let (pattern, _) = folded_chars(phrase);
+    if pattern.is_empty() {
+        return Ok(Some((0, 0, 0)));
+    }
+    let max_edits = if pattern.len() < max_edits + 2 { 0 } else { max_edits };
+    let (text, origin) = folded_chars(text);
+    let Some((end, edits)) = approx_match_end(&pattern, &text, max_edits) else {
+        return Ok(None);
+    };
+    // Walk back from the end with both reversed to find the nearest start
+    let reversed_pattern: Vec<char> = pattern.iter().rev().copied().collect();
+    let reversed_text: Vec<char> = text[..=end].iter().rev().copied().collect();
+    let start = approx_match_end(&reversed_pattern, &reversed_text, edits)
+        .map_or(0, |(back, _)| end - back);
+    Ok(Some((origin[start], origin[end] + 1, edits)))
+}
+
+/// Check if text contains the phrase with at most `max_edits` character
+/// insertions, deletions, substitutions, or adjacent swaps (after case folding).
+/// `max_edits` is capped at 3; phrases shorter than `max_edits + 2`
+/// characters must match exactly.
+#[pyfunction]
+#[pyo3(signature = (text, phrase, max_edits = 1))]
+fn text_contains_phrase_approx(text: &str, phrase: &str, max_edits: usize) -> PyResult<bool> {
+    Ok(find_phrase_approx_in(text, phrase, max_edits)?.is_some())
+}
+
+/// Like `text_contains_phrase_approx`, but returns the first match as
+/// (start, end, edits) in character offsets, or None.
+#[pyfunction]
+#[pyo3(signature = (text, phrase, max_edits = 1))]
+fn find_phrase_approx(text: &str, phrase: &str, max_edits: usize) -> PyResult<Option<(usize, usize, usize)>> {
+    find_phrase_approx_in(text, phrase, max_edits)
+}
+
+/// One element of a compiled glob pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GlobToken {
+    /// A literal character (already case-folded).
+    Char(char),
+    /// `?`: exactly one character.
+    AnyOne,
+    /// `*`: any run of characters, including none.
+    AnyRun,
+}
+
+/// Simple per-character case fold used by glob matching.
+fn fold_char(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Compile a glob pattern: `*` matches any run, `?` one character, and `\`
+/// escapes the next character (`\*`, `\?`, `\\`). Runs of `*` collapse to one.
+fn compile_glob(pattern: &str) -> Vec<GlobToken> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' => GlobToken::AnyRun,
+            '?' => GlobToken::AnyOne,
+            '\\' => GlobToken::Char(fold_char(chars.next().unwrap_or('\\'))),
+            _ => GlobToken::Char(fold_char(c)),
         };
-        
-        if let Ok(mut count) = self.pending_count.lock() {
-            *count += 1;
+        if token == GlobToken::AnyRun && tokens.last() == Some(&GlobToken::AnyRun) {
+            continue;
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Case-insensitive glob match anywhere in `text` (substring semantics).
+/// Uses single-backtrack-point matching, so the worst case is O(text * pattern)
+/// no matter how many `*` the pattern contains.
+fn glob_search(text: &[char], tokens: &[GlobToken]) -> bool {
+    let mut t = 0;
+    let mut p = 0;
+    // Unanchored: behave as if the pattern starts with `*`
+    let mut star_p = 0;
+    let mut star_t = 0;
+    loop {
+        if p == tokens.len() {
+            return true;
+        }
+        if t < text.len() {
+            match tokens[p] {
+                GlobToken::AnyRun => {
+                    p += 1;
+                    star_p = p;
+                    star_t = t;
+                    continue;
+                }
+                GlobToken::AnyOne => {
+                    t += 1;
+                    p += 1;
+                    continue;
+                }
+                GlobToken::Char(c) if fold_char(text[t]) == c => {
+                    t += 1;
+                    p += 1;
+                    continue;
+                }
+                GlobToken::Char(_) => {}
+            }
+        } else if tokens[p..].iter().all(|&tok| tok == GlobToken::AnyRun) {
+            return true;
+        }
+        // Mismatch: retry one character further from the last `*`
+        if star_t >= text.len() {
+            return false;
+        }
+        star_t += 1;
+        t = star_t;
+        p = star_p;
+    }
+}
+
+/// Check if text matches a glob pattern anywhere (case-insensitive).
+/// `*` matches any run of characters, `?` exactly one; escape with a backslash.
+#[pyfunction]
+fn text_matches_glob(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    glob_search(&text, &compile_glob(pattern))
+}
+
+/// Writing systems `detect_script` tells apart. Letters of any other script
+/// (Greek, CJK, ...) still count toward the total, so they push a message
+/// toward mixed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Hebrew,
+    Cyrillic,
+    Arabic,
+}
+
+/// Default share of letters the dominant script needs.
+const SCRIPT_DOMINANCE: f64 = 0.6;
+
+impl Script {
+    const ALL: [Script; 4] = [Script::Latin, Script::Hebrew, Script::Cyrillic, Script::Arabic];
+
+    fn parse(script: &str) -> PyResult<Self> {
+        match script {
+            "latin" => Ok(Script::Latin),
+            "hebrew" => Ok(Script::Hebrew),
+            "cyrillic" => Ok(Script::Cyrillic),
+            "arabic" => Ok(Script::Arabic),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown script '{}' (expected 'latin', 'hebrew', 'cyrillic', or 'arabic')",
+                script
+            ))),
         }
-        
-        self.sender.send(op).map_err(|e| {
-            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to queue write: {}", e))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Script::Latin => "latin",
+            Script::Hebrew => "hebrew",
+            Script::Cyrillic => "cyrillic",
+            Script::Arabic => "arabic",
+        }
+    }
+
+    /// Script of a letter, or None for other scripts.
+    fn of(c: char) -> Option<Self> {
+        match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+            '\u{FF21}'..='\u{FF3A}' | '\u{FF41}'..='\u{FF5A}' => Some(Script::Latin),
+            '\u{0590}'..='\u{05FF}' | '\u{FB1D}'..='\u{FB4F}' => Some(Script::Hebrew),
+            '\u{0400}'..='\u{052F}' | '\u{1C80}'..='\u{1C8F}' | '\u{A640}'..='\u{A69F}' => Some(Script::Cyrillic),
+            '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' | '\u{08A0}'..='\u{08FF}' => Some(Script::Arabic),
+            '\u{FB50}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFF}' => Some(Script::Arabic),
+            _ => None,
+        }
+    }
+
+    /// Whether a phrase tagged `tag` may match a message whose dominant
+    /// script is `dominant`. Untagged phrases and mixed messages match
+    /// everything, so a classification miss never hides a hit.
+    fn agrees(tag: Option<Script>, dominant: Option<Script>) -> bool {
+        match (tag, dominant) {
+            (Some(tag), Some(dominant)) => tag == dominant,
+            _ => true,
+        }
+    }
+}
+
+/// Script holding at least `threshold` of the letters in `chars`, or None
+/// when no script does (mixed) or there are no letters.
+fn dominant_script(chars: impl Iterator<Item = char>, threshold: f64) -> Option<Script> {
+    let mut counts = [0usize; 4];
+    let mut letters = 0usize;
+    for c in chars.filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        if let Some(script) = Script::of(c) {
+            counts[script as usize] += 1;
+        }
+    }
+    if letters == 0 {
+        return None;
+    }
+    Script::ALL
+        .into_iter()
+        .max_by_key(|&script| counts[script as usize])
+        .filter(|&script| counts[script as usize] as f64 >= threshold * letters as f64)
+}
+
+fn check_script_threshold(threshold: f64) -> PyResult<f64> {
+    if threshold > 0.5 && threshold <= 1.0 {
+        Ok(threshold)
+    } else {
+        Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "script_threshold must be above 0.5 and at most 1.0, got {}",
+            threshold
+        )))
+    }
+}
+
+fn parse_script_tag(script: Option<&str>) -> PyResult<Option<Script>> {
+    script.map(Script::parse).transpose()
+}
+
+/// Dominant Unicode script of the letters in `text`: "latin", "hebrew",
+/// "cyrillic", or "arabic" when one holds at least `threshold` of them,
+/// otherwise "mixed" (also for text with no letters). Digits, punctuation,
+/// and emoji are ignored. This is script classification, not language ID.
+#[pyfunction]
+#[pyo3(signature = (text, threshold = SCRIPT_DOMINANCE))]
+fn detect_script(text: &str, threshold: f64) -> PyResult<&'static str> {
+    let threshold = check_script_threshold(threshold)?;
+    Ok(dominant_script(text.chars(), threshold).map_or("mixed", Script::name))
+}
+
+/// Multi-phrase matcher over glob patterns (plain phrases work as-is). A
+/// pattern tagged with a script only matches text whose dominant script
+/// (see `detect_script`) is that script, or mixed.
+#[pyclass]
+struct PhraseMatcher {
+    patterns: Vec<(Vec<GlobToken>, Option<Script>)>,
+    script_threshold: f64,
+}
+
+#[pymethods]
+impl PhraseMatcher {
+    #[new]
+    #[pyo3(signature = (patterns = Vec::new(), script_threshold = SCRIPT_DOMINANCE))]
+    fn new(patterns: Vec<String>, script_threshold: f64) -> PyResult<Self> {
+        Ok(PhraseMatcher {
+            patterns: patterns.iter().map(|p| (compile_glob(p), None)).collect(),
+            script_threshold: check_script_threshold(script_threshold)?,
         })
     }
 
-    /// Get the number of pending writes.
-    fn pending_writes(&self) -> usize {
-        self.pending_count.lock().map(|c| *c).unwrap_or(0)
+    /// Add a pattern, optionally tagged "latin", "hebrew", "cyrillic", or
+    /// "arabic"; returns its index.
+    #[pyo3(signature = (pattern, script = None))]
+    fn add(&mut self, pattern: &str, script: Option<&str>) -> PyResult<usize> {
+        let script = parse_script_tag(script)?;
+        self.patterns.push((compile_glob(pattern), script));
+        Ok(self.patterns.len() - 1)
     }
 
-    /// Flush all pending writes (blocks until complete).
-    fn flush(&self) -> PyResult<()> {
-        // Wait for pending count to reach 0
-        loop {
-            let count = self.pending_count.lock().map(|c| *c).unwrap_or(0);
-            if count == 0 {
-                break;
-            }
-            thread::sleep(std::time::Duration::from_millis(10));
+    fn clear(&mut self) {
+        self.patterns.clear();
+    }
+
+    /// Indices of all patterns found in the text.
+    fn matches(&self, text: &str) -> Vec<usize> {
+        let text: Vec<char> = text.chars().collect();
+        let dominant = self.dominant(&text);
+        self.patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, (tokens, tag))| Script::agrees(*tag, dominant) && glob_search(&text, tokens))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// True if any pattern is found in the text.
+    fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let dominant = self.dominant(&text);
+        self.patterns
+            .iter()
+            .any(|(tokens, tag)| Script::agrees(*tag, dominant) && glob_search(&text, tokens))
+    }
+
+    fn __len__(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+impl PhraseMatcher {
+    /// Dominant script of `text`, skipping the scan when no pattern is tagged.
+    fn dominant(&self, text: &[char]) -> Option<Script> {
+        if self.patterns.iter().all(|(_, tag)| tag.is_none()) {
+            return None;
         }
-        Ok(())
+        dominant_script(text.iter().copied(), self.script_threshold)
     }
 }
 
-impl DatabaseWriter {
-    /// Background thread that processes write operations.
-    fn process_writes(receiver: Receiver<DbWriteOp>, pending_count: Arc<Mutex<usize>>) {
-        // We'll call back into Python to do the actual SQLite write
-        // This is a queue processor that batches operations
+/// Latin letters that Cyrillic, Greek, and fullwidth forms are commonly used
+/// to impersonate. Applied after case folding, so only lowercase is listed.
+fn unconfuse_char(c: char) -> char {
+    match c {
+        '\u{FF41}'..='\u{FF5A}' => char::from_u32(c as u32 - 0xFF41 + 'a' as u32).unwrap_or(c),
+        '\u{FF10}'..='\u{FF19}' => char::from_u32(c as u32 - 0xFF10 + '0' as u32).unwrap_or(c),
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'һ' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'п' | 'η' => 'n',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ш' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        _ => c,
+    }
+}
 
-        for op in receiver {
-            match op {
-                DbWriteOp::Shutdown => break,
-                DbWriteOp::Transcription { guild_id, channel_id, user_id, content, username, duration_secs } => {
-                    // Call Python to save - using pyo3's GIL
-                    Python::with_gil(|py| {
-                        let result = py.run_bound(
-                            &format!(
-                                r#"
-from db.transcriptions import save_transcription
-save_transcription({}, {}, {}, {}, {}, {})
-"#,
-                                guild_id,
-                                channel_id, 
-                                user_id,
-                                repr_string(&content),
-                                repr_string(&username),
-                                duration_secs
-                            ),
-                            None,
-                            None,
-                        );
-                        if let Err(e) = result {
-                            eprintln!("DB write failed: {}", e);
-                        }
-                    });
-                }
-                DbWriteOp::Generic { table, data } => {
-                    Python::with_gil(|py| {
-                        let result = py.run_bound(
-                            &format!(
-                                r#"
-import json
-data = json.loads({})
-# Generic write handler - implement per table
-print(f"Generic write to {{}}: {{data}}")
-"#,
-                                repr_string(&data),
-                                table
-                            ),
-                            None,
-                            None,
-                        );
-                        if let Err(e) = result {
-                            eprintln!("DB write failed: {}", e);
-                        }
-                    });
+/// Letters commonly written as digits or symbols. Symbols only count as
+/// letters when `in_word` (followed by a letter or digit), so trailing
+/// punctuation like "damn!" is left alone.
+fn unleet_char(c: char, in_word: bool) -> char {
+    match c {
+        '0' => 'o',
+        '1' => 'i',
+        '3' => 'e',
+        '4' => 'a',
+        '5' => 's',
+        '7' => 't',
+        '8' => 'b',
+        '9' => 'g',
+        '!' | '|' if in_word => 'i',
+        '@' if in_word => 'a',
+        '$' if in_word => 's',
+        '+' if in_word => 't',
+        _ => c,
+    }
+}
+
+/// Which normalizations a `WordFilter` applies, to list entries and text alike.
+#[derive(Clone, Copy)]
+struct FilterFolding {
+    casefold: bool,
+    confusables: bool,
+    leetspeak: bool,
+}
+
+impl FilterFolding {
+    /// Normalize one character; `next` is the one after it, if any.
+    fn apply(self, c: char, next: Option<char>) -> char {
+        let mut c = if self.casefold { fold_char(c) } else { c };
+        if self.confusables {
+            c = unconfuse_char(c);
+        }
+        if self.leetspeak {
+            c = unleet_char(c, next.is_some_and(char::is_alphanumeric));
+        }
+        c
+    }
+
+    /// Normalized words of `text` with their [start, end) char offsets.
+    fn words(self, text: &str) -> Vec<(String, usize, usize)> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut words = Vec::new();
+        let mut current = String::new();
+        let mut start = 0;
+        let mut len = 0;
+        for (i, &c) in chars.iter().enumerate() {
+            let c = self.apply(c, chars.get(i + 1).copied());
+            if c.is_alphanumeric() {
+                if current.is_empty() {
+                    start = i;
                 }
+                current.push(c);
+            } else if !current.is_empty() {
+                words.push((std::mem::take(&mut current), start, i));
             }
+            len = i + 1;
+        }
+        if !current.is_empty() {
+            words.push((current, start, len));
+        }
+        words
+    }
+}
+
+/// A loaded word list: normalized phrase (words joined by one space) ->
+/// (entry as loaded, severity, script tag), one per distinct tag.
+#[derive(Default)]
+struct WordList {
+    phrases: HashMap<String, Vec<(String, u8, Option<Script>)>>,
+    /// Longest phrase, in words.
+    max_words: usize,
+    /// Total entries across all tags.
+    entries: usize,
+    /// Whether any entry carries a script tag.
+    tagged: bool,
+}
+
+/// Auto-mod word filter. Entries match whole words (or runs of whole words
+/// for phrases) after the normalizations chosen at construction: case
+/// folding, confusable letters (Cyrillic/Greek/fullwidth lookalikes), and
+/// leetspeak digits and symbols. Severity is 1 (log), 2 (delete), or 3
+/// (delete and timeout). An entry tagged with a script only matches messages
+/// whose dominant script (see `detect_script`, taken after confusable
+/// folding) is that script, or mixed. `load_list` swaps in a whole new list at
+/// once, so a concurrent check sees either the old list or the new one.
+#[pyclass]
+struct WordFilter {
+    folding: FilterFolding,
+    script_threshold: f64,
+    list: RwLock<Arc<WordList>>,
+}
+
+/// A `load_list` entry: `(word, severity)` or `(word, severity, script)`.
+#[derive(FromPyObject)]
+enum WordEntry {
+    Tagged(String, u8, Option<String>),
+    Plain(String, u8),
+}
+
+#[pymethods]
+impl WordFilter {
+    #[new]
+    #[pyo3(signature = (casefold = true, confusables = true, leetspeak = true, script_threshold = SCRIPT_DOMINANCE))]
+    fn new(casefold: bool, confusables: bool, leetspeak: bool, script_threshold: f64) -> PyResult<Self> {
+        Ok(WordFilter {
+            folding: FilterFolding { casefold, confusables, leetspeak },
+            script_threshold: check_script_threshold(script_threshold)?,
+            list: RwLock::new(Arc::new(WordList::default())),
+        })
+    }
 
-            // Decrement pending count
-            if let Ok(mut count) = pending_count.lock() {
-                *count = count.saturating_sub(1);
+    /// Replace the list with `(word, severity)` pairs or `(word, severity,
+    /// script)` triples, script being "latin", "hebrew", "cyrillic",
+    /// "arabic", or None. An entry listed twice with the same tag keeps its
+    /// highest severity. Returns the number of distinct entries.
+    fn load_list(&self, words: Vec<WordEntry>) -> PyResult<usize> {
+        let mut list = WordList::default();
+        let mut count = 0;
+        for entry in words {
+            let (word, severity, script) = match entry {
+                WordEntry::Tagged(word, severity, script) => (word, severity, script),
+                WordEntry::Plain(word, severity) => (word, severity, None),
+            };
+            if !(1..=3).contains(&severity) {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "severity for '{}' must be 1, 2, or 3",
+                    word
+                )));
+            }
+            let script = parse_script_tag(script.as_deref())?;
+            let parts: Vec<String> = self.folding.words(&word).into_iter().map(|(w, _, _)| w).collect();
+            if parts.is_empty() {
+                continue;
+            }
+            list.max_words = list.max_words.max(parts.len());
+            let entries = list.phrases.entry(parts.join(" ")).or_default();
+            match entries.iter_mut().find(|e| e.2 == script) {
+                Some(existing) => existing.1 = existing.1.max(severity),
+                None => {
+                    entries.push((word, severity, script));
+                    count += 1;
+                }
             }
         }
+        list.entries = count;
+        list.tagged = list.phrases.values().flatten().any(|e| e.2.is_some());
+        *self.list.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(list);
+        Ok(count)
+    }
+
+    /// Every listed entry found in `text` as (entry, severity, start, end),
+    /// in text order; offsets are character indices into `text`.
+    fn check(&self, text: &str) -> Vec<(String, u8, usize, usize)> {
+        let mut hits = Vec::new();
+        self.scan(text, |entry, severity, start, end| {
+            hits.push((entry.to_string(), severity, start, end));
+            false
+        });
+        hits
+    }
+
+    /// Highest severity found in `text`, or 0 if it is clean.
+    fn max_severity(&self, text: &str) -> u8 {
+        let mut max = 0;
+        self.scan(text, |_, severity, _, _| {
+            max = max.max(severity);
+            max == 3
+        });
+        max
+    }
+
+    /// Number of entries in the current list.
+    fn __len__(&self) -> usize {
+        self.list.read().unwrap_or_else(PoisonError::into_inner).entries
     }
 }
 
-impl Drop for DatabaseWriter {
-    fn drop(&mut self) {
-        let _ = self.sender.send(DbWriteOp::Shutdown);
+impl WordFilter {
+    /// Call `found` for each match in text order with the highest-severity
+    /// entry whose script tag agrees with `text`; it returns true to stop early.
+    fn scan(&self, text: &str, mut found: impl FnMut(&str, u8, usize, usize) -> bool) {
+        let list = self.list.read().unwrap_or_else(PoisonError::into_inner).clone();
+        if list.phrases.is_empty() {
+            return;
+        }
+        let dominant = if list.tagged {
+            let folding = self.folding;
+            let unconfused = text.chars().map(|c| if folding.confusables { unconfuse_char(fold_char(c)) } else { c });
+            dominant_script(unconfused, self.script_threshold)
+        } else {
+            None
+        };
+        let words = self.folding.words(text);
+        for i in 0..words.len() {
+            let mut phrase = String::new();
+            for (n, (word, _, end)) in words[i..].iter().take(list.max_words).enumerate() {
+                if n > 0 {
+                    phrase.push(' ');
+                }
+                phrase.push_str(word);
+                let entry = list.phrases.get(&phrase).and_then(|entries| {
+                    entries.iter().filter(|e| Script::agrees(e.2, dominant)).max_by_key(|e| e.1)
+                });
+                if let Some((entry, severity, _)) = entry {
+                    if found(entry, *severity, words[i].1, *end) {
+                        return;
+                    }
+                }
+            }
+        }
     }
 }
 
-/// Helper to create a Python repr string
-fn repr_string(s: &str) -> String {
-    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+/// Messages in `activity` with `cutoff <= ts <= now_ts`.
+fn count_since(activity: &VecDeque<(f64, u64)>, cutoff: f64, now_ts: f64) -> usize {
+    activity.iter().filter(|&&(ts, _)| ts >= cutoff && ts <= now_ts).count()
 }
 
-/// Python module definition
-#[pymodule]
-fn guildest_core(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(truncate, m)?)?;
-    m.add_function(wrap_pyfunction!(parse_duration_secs, m)?)?;
-    m.add_function(wrap_pyfunction!(text_contains_phrase, m)?)?;
-    m.add_class::<ActivityTrackerRust>()?;
-    m.add_class::<EconomyEngine>()?;
-    m.add_class::<DatabaseWriter>()?;
-    Ok(())
+/// Seed for `content_fingerprint`: the ASCII bytes of "guildest". Changing it
+/// changes every fingerprint, so it is fixed for good.
+const FINGERPRINT_SEED: u64 = 0x6775_696C_6465_7374;
+
+/// Normalized form of message content that fingerprints hash: its
+/// accent-stripped, case-folded words joined by single spaces, or the
+/// normalized text when it has no words (emoji only). None when blank.
+fn fingerprint_key(content: &str) -> Option<String> {
+    let terms = index_terms(content);
+    let key = if terms.is_empty() {
+        normalize_str(content, true, true, true, true)
+    } else {
+        terms.join(" ")
+    };
+    (!key.is_empty()).then_some(key)
+}
+
+/// Stable fingerprint of message content, for correlating repeats without
+/// storing the text: XXH64 of the normalized words (see `fingerprint_key`)
+/// with seed `FINGERPRINT_SEED`, so case, accents, punctuation, and spacing
+/// tricks collide. The tracker's copy detection uses the same value.
+/// Returns None for blank content.
+#[pyfunction]
+fn content_fingerprint(content: &str) -> Option<u64> {
+    fingerprint_key(content).map(|key| xxh64(key.as_bytes(), FINGERPRINT_SEED))
+}
+
+/// Keyed fingerprint of message content: hex HMAC-SHA256 of the same
+/// normalized text `content_fingerprint` hashes, so it can't be reversed by
+/// hashing guesses without `key`. Returns None for blank content.
+#[pyfunction]
+fn hmac_fingerprint(content: &str, key: &[u8]) -> PyResult<Option<String>> {
+    if key.is_empty() {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>("key must not be empty"));
+    }
+    Ok(fingerprint_key(content).map(|text| hmac_sha256_hex(key, text.as_bytes())))
+}
+
+/// Hex HMAC-SHA256 of `message` under `key`.
+fn hmac_sha256_hex(key: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashed character n-gram shingles of `text` for similarity features.
+/// The text is first normalized exactly as `normalize_text` does by default
+/// (NFKC, case folding, whitespace collapsed and trimmed), then every run of
+/// `n` characters is hashed like `content_fingerprint` (XXH64 of its UTF-8
+/// with the same seed). Text shorter than `n` is one shingle. Returns the
+/// distinct hashes in ascending order; when there are more than
+/// `max_shingles`, only the `max_shingles` smallest are kept (a bottom-k
+/// sketch), so giant pastes stay bounded and texts are cut consistently.
+#[pyfunction]
+#[pyo3(signature = (text, n = 3, max_shingles = 512))]
+fn char_shingles(text: &str, n: usize, max_shingles: usize) -> PyResult<Vec<u64>> {
+    if n == 0 || max_shingles == 0 {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "n and max_shingles must be positive",
+        ));
+    }
+    let normalized = normalize_str(text, true, true, true, false);
+    let bounds: Vec<usize> = normalized
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([normalized.len()])
+        .collect();
+    let chars = bounds.len() - 1;
+    if chars == 0 {
+        return Ok(Vec::new());
+    }
+    let mut hashes: Vec<u64> = if chars < n {
+        vec![xxh64(normalized.as_bytes(), FINGERPRINT_SEED)]
+    } else {
+        bounds
+            .windows(n + 1)
+            .map(|w| xxh64(&normalized.as_bytes()[w[0]..w[n]], FINGERPRINT_SEED))
+            .collect()
+    };
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes.truncate(max_shingles);
+    Ok(hashes)
+}
+
+/// Jaccard similarity of two shingle sets from `char_shingles`:
+/// |a ∩ b| / |a ∪ b|, ignoring duplicates and order. Two empty sets give 1.0.
+#[pyfunction]
+fn shingle_jaccard(mut a: Vec<u64>, mut b: Vec<u64>) -> f64 {
+    for set in [&mut a, &mut b] {
+        set.sort_unstable();
+        set.dedup();
+    }
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+/// Drop posts at or before `cutoff`, and fingerprints left with none.
+fn prune_content(content: &mut GuildContent, cutoff: f64) {
+    let GuildContent { hashes, by_use, .. } = content;
+    hashes.retain(|_, entry| {
+        while entry.posts.front().is_some_and(|&(ts, _)| ts <= cutoff) {
+            entry.posts.pop_front();
+        }
+        if entry.posts.is_empty() {
+            by_use.remove(&entry.tick);
+        }
+        !entry.posts.is_empty()
+    });
+}
+
+/// Distinct senders in first-post order.
+fn distinct_senders(posts: &VecDeque<(f64, u64)>) -> Vec<u64> {
+    let mut seen = std::collections::HashSet::new();
+    posts
+        .iter()
+        .map(|&(_, user_id)| user_id)
+        .filter(|user_id| seen.insert(*user_id))
+        .collect()
+}
+
+/// How a detection rule's result reaches the caller (see `set_rule_mode`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RuleMode {
+    /// Not evaluated: no state is kept and nothing is flagged.
+    Off,
+    /// Evaluated and logged, but never reported as flagged.
+    Shadow,
+    Enforce,
+}
+
+impl RuleMode {
+    const ALL: [RuleMode; 3] = [RuleMode::Off, RuleMode::Shadow, RuleMode::Enforce];
+
+    fn parse(mode: &str) -> PyResult<Self> {
+        match mode {
+            "off" => Ok(RuleMode::Off),
+            "shadow" => Ok(RuleMode::Shadow),
+            "enforce" => Ok(RuleMode::Enforce),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown rule mode '{}' (expected 'off', 'shadow', or 'enforce')",
+                mode
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RuleMode::Off => "off",
+            RuleMode::Shadow => "shadow",
+            RuleMode::Enforce => "enforce",
+        }
+    }
+}
+
+/// Per-rule modes and shadow-hit counters, indexed by rule. Every rule
+/// starts enforced. Atomics keep the check paths lock-free.
+struct RuleModes<const N: usize> {
+    modes: [AtomicU8; N],
+    shadow_hits: [AtomicUsize; N],
+}
+
+impl<const N: usize> RuleModes<N> {
+    fn new() -> Self {
+        RuleModes {
+            modes: std::array::from_fn(|_| AtomicU8::new(RuleMode::Enforce as u8)),
+            shadow_hits: std::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+
+    fn get(&self, rule: usize) -> RuleMode {
+        RuleMode::ALL[self.modes[rule].load(Ordering::Relaxed) as usize]
+    }
+
+    fn set(&self, rule: usize, mode: RuleMode) {
+        self.modes[rule].store(mode as u8, Ordering::Relaxed);
+    }
+
+    fn is_off(&self, rule: usize) -> bool {
+        self.get(rule) == RuleMode::Off
+    }
+
+    /// Whether a rule that fired is reported to the caller; a shadowed rule
+    /// counts a shadow hit instead.
+    fn report(&self, rule: usize) -> bool {
+        if self.get(rule) == RuleMode::Shadow {
+            self.shadow_hits[rule].fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    fn shadow_hits(&self, rule: usize) -> usize {
+        self.shadow_hits[rule].load(Ordering::Relaxed)
+    }
+}
+
+/// Detection rules of `ActivityTrackerRust` that `set_rule_mode` controls;
+/// names match their audit event rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TrackerRule {
+    Spam,
+    SpamDecay,
+    AttachmentSpam,
+    Raid,
+}
+
+impl TrackerRule {
+    const COUNT: usize = 4;
+
+    fn parse(rule: &str) -> PyResult<Self> {
+        match rule {
+            "spam" => Ok(TrackerRule::Spam),
+            "spam_decay" => Ok(TrackerRule::SpamDecay),
+            "attachment_spam" => Ok(TrackerRule::AttachmentSpam),
+            "raid" => Ok(TrackerRule::Raid),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown rule '{}' (expected 'spam', 'spam_decay', 'attachment_spam', or 'raid')",
+                rule
+            ))),
+        }
+    }
+
+    /// `core_metrics` counter of the flags this rule raised.
+    fn flag_counter(self) -> CoreCounter {
+        match self {
+            TrackerRule::Spam => CoreCounter::SpamFlags,
+            TrackerRule::SpamDecay => CoreCounter::SpamDecayFlags,
+            TrackerRule::AttachmentSpam => CoreCounter::AttachmentFlags,
+            TrackerRule::Raid => CoreCounter::RaidFlags,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TrackerRule::Spam => "spam",
+            TrackerRule::SpamDecay => "spam_decay",
+            TrackerRule::AttachmentSpam => "attachment_spam",
+            TrackerRule::Raid => "raid",
+        }
+    }
+}
+
+/// High-performance activity tracker for anti-spam and chat engagement.
+/// Methods taking `now_ts` fall back to the internal clock when it is
+/// omitted. Explicit and implicit timestamps can be mixed, but explicit ones
+/// should not run far behind the clock or windows prune unpredictably.
+#[pyclass]
+struct ActivityTrackerRust {
+    spam_window_secs: f64,
+    spam_threshold: usize,
+    chat_active_window_secs: f64,
+    chat_min_messages: usize,
+    chat_min_users: usize,
+    chat_cooldown_secs: f64,
+    guild_cooldown_secs: Option<f64>,
+    chat_trigger_chance: f64,
+    mention_trigger_chance: f64,
+    reply_trigger_chance: f64,
+    mention_cooldown_secs: Option<f64>,
+    join_window_secs: f64,
+    join_threshold: usize,
+    young_account_secs: f64,
+    history_retention_secs: f64,
+    violation_half_life_secs: f64,
+    spam_decay_secs: f64,
+    spam_decay_threshold: f64,
+    slowmode_hysteresis: f64,
+    slowmode_table: Mutex<Vec<(f64, u32)>>,
+    rng: Mutex<ChatRng>,
+    content_window_secs: f64,
+    content_max_hashes: usize,
+    attachment_window_secs: f64,
+    attachment_threshold: usize,
+    attachment_duplicate_threshold: usize,
+    event_buffer_size: usize,
+    events: Mutex<EventLog>,
+    rule_modes: RuleModes<{ TrackerRule::COUNT }>,
+    /// Seconds added to the internal clock (see `set_clock_offset`).
+    clock_offset: Mutex<f64>,
+}
+
+/// Evidence value attached to a tracker event.
+#[derive(Clone, Copy)]
+enum EventValue {
+    Bool(bool),
+    Int(u64),
+    Float(f64),
+    Str(&'static str),
+}
+
+/// Add evidence entries to a Python dict.
+fn put_details(dict: &PyDict, details: &[(&'static str, EventValue)]) -> PyResult<()> {
+    for &(key, value) in details {
+        match value {
+            EventValue::Bool(v) => dict.set_item(key, v)?,
+            EventValue::Int(v) => dict.set_item(key, v)?,
+            EventValue::Float(v) => dict.set_item(key, v)?,
+            EventValue::Str(v) => dict.set_item(key, v)?,
+        }
+    }
+    Ok(())
+}
+
+/// An automatic decision recorded for `drain_events`.
+struct TrackerEvent {
+    seq: u64,
+    rule: &'static str,
+    ts: f64,
+    guild_id: u64,
+    user_id: u64,
+    details: Vec<(&'static str, EventValue)>,
+}
+
+impl TrackerEvent {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("seq", self.seq)?;
+        dict.set_item("rule", self.rule)?;
+        dict.set_item("timestamp", self.ts)?;
+        dict.set_item("guild_id", self.guild_id)?;
+        dict.set_item("user_id", self.user_id)?;
+        put_details(dict, &self.details)?;
+        Ok(dict)
+    }
+}
+
+/// Outcome of a spam check, shared by every `*_verdict` method. Truthy
+/// when flagged. `details` is built on access, so construction stays cheap.
+#[pyclass]
+struct SpamVerdict {
+    flagged: bool,
+    rule: &'static str,
+    score: f64,
+    count: usize,
+    window_secs: f64,
+    details: Vec<(&'static str, EventValue)>,
+}
+
+#[pymethods]
+impl SpamVerdict {
+    #[getter]
+    fn flagged(&self) -> bool {
+        self.flagged
+    }
+
+    /// Check that produced the verdict, e.g. "spam" or "spam_decay".
+    #[getter]
+    fn rule(&self) -> &'static str {
+        self.rule
+    }
+
+    #[getter]
+    fn score(&self) -> f64 {
+        self.score
+    }
+
+    #[getter]
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    #[getter]
+    fn window_secs(&self) -> f64 {
+        self.window_secs
+    }
+
+    /// Thresholds and other evidence, as a new dict.
+    #[getter]
+    fn details<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        put_details(dict, &self.details)?;
+        Ok(dict)
+    }
+
+    fn __bool__(&self) -> bool {
+        self.flagged
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "SpamVerdict(flagged={}, rule='{}', score={:?}, count={}, window_secs={:?})",
+            if self.flagged { "True" } else { "False" },
+            self.rule,
+            self.score,
+            self.count,
+            self.window_secs
+        )
+    }
+}
+
+/// Bounded buffer of decisions waiting for `drain_events`.
+#[derive(Default)]
+struct EventLog {
+    events: VecDeque<TrackerEvent>,
+    next_seq: u64,
+    dropped: usize,
+}
+
+/// Source of the random rolls used by the chat trigger.
+/// Seeded from OS entropy; `set_rng_seed` reseeds it so trigger behavior is reproducible.
+struct ChatRng {
+    rng: SmallRng,
+    forced_rolls: VecDeque<f64>,
+}
+
+impl Default for ChatRng {
+    fn default() -> Self {
+        ChatRng {
+            rng: SmallRng::from_entropy(),
+            forced_rolls: VecDeque::new(),
+        }
+    }
+}
+
+impl ChatRng {
+    /// Next roll, uniform in [0, 1), preferring forced values.
+    fn roll(&mut self) -> f64 {
+        match self.forced_rolls.pop_front() {
+            Some(value) => value,
+            None => self.rng.gen::<f64>(),
+        }
+    }
+}
+
+/// Outcome of a chat-activity check, including why the bot did or did not reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChatDecision {
+    /// Triggered because the bot was mentioned by name.
+    Mention,
+    /// Triggered because the message replied to the bot.
+    Reply,
+    /// Triggered by the activity heuristic and random roll.
+    Activity,
+    /// Not enough messages or participants in the active window.
+    Inactive,
+    /// The channel is still on cooldown from a previous trigger.
+    ChannelCooldown,
+    /// The guild-wide cooldown (if configured) is still running.
+    GuildCooldown,
+    /// Thresholds were met but the guild's quiet hours are in effect.
+    QuietHours,
+    /// Thresholds were met but the random roll failed.
+    Chance,
+}
+
+impl ChatDecision {
+    fn triggered(self) -> bool {
+        matches!(self, ChatDecision::Mention | ChatDecision::Reply | ChatDecision::Activity)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ChatDecision::Mention => "mention",
+            ChatDecision::Reply => "reply",
+            ChatDecision::Activity => "activity",
+            ChatDecision::Inactive => "inactive",
+            ChatDecision::ChannelCooldown => "channel_cooldown",
+            ChatDecision::GuildCooldown => "guild_cooldown",
+            ChatDecision::QuietHours => "quiet_hours",
+            ChatDecision::Chance => "chance",
+        }
+    }
+}
+
+#[pymethods]
+impl ActivityTrackerRust {
+    /// `mention_trigger_chance` and `reply_trigger_chance` replace the base chance
+    /// when the bot is addressed directly; a chance of 1.0 or more skips the roll.
+    /// `mention_cooldown_secs` applies a shorter cooldown to direct address
+    /// (defaults to the regular chat cooldown).
+    /// A raid is `join_threshold` or more joins within `join_window_secs`;
+    /// accounts younger than `young_account_secs` are counted separately.
+    /// `history_retention_secs` keeps chat activity longer than the trigger window
+    /// for activity queries; it never drops below the trigger window.
+    /// Violation scores halve every `violation_half_life_secs`.
+    /// `check_spam_decay` scores decay with time constant `spam_decay_secs`
+    /// and flag above `spam_decay_threshold`.
+    /// `chat_cooldown_secs` spaces triggers within one channel; the optional
+    /// `guild_cooldown_secs` caps unsolicited triggers across the whole guild
+    /// (direct address ignores the guild cap).
+    /// Slowmode recommendations only step down once the message rate falls
+    /// below `slowmode_hysteresis` times the current level's threshold.
+    /// `record_content` counts copies within `content_window_secs` and keeps
+    /// at most `content_max_hashes` distinct messages per guild.
+    /// `record_attachment` flags `attachment_threshold` attachments, or
+    /// `attachment_duplicate_threshold` copies of one, within `attachment_window_secs`.
+    /// Up to `event_buffer_size` undrained decision events are kept (0 disables them).
+    #[new]
+    #[pyo3(signature = (
+        mention_trigger_chance = 1.0,
+        reply_trigger_chance = 1.0,
+        mention_cooldown_secs = None,
+        join_window_secs = 60.0,
+        join_threshold = 10,
+        young_account_secs = 604_800.0,
+        history_retention_secs = None,
+        violation_half_life_secs = 3600.0,
+        spam_decay_secs = 10.0,
+        spam_decay_threshold = 20.0,
+        slowmode_hysteresis = 0.75,
+        chat_cooldown_secs = 45.0,
+        guild_cooldown_secs = None,
+        content_window_secs = 60.0,
+        content_max_hashes = 1000,
+        attachment_window_secs = 15.0,
+        attachment_threshold = 10,
+        attachment_duplicate_threshold = 4,
+        event_buffer_size = 1000
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        mention_trigger_chance: f64,
+        reply_trigger_chance: f64,
+        mention_cooldown_secs: Option<f64>,
+        join_window_secs: f64,
+        join_threshold: usize,
+        young_account_secs: f64,
+        history_retention_secs: Option<f64>,
+        violation_half_life_secs: f64,
+        spam_decay_secs: f64,
+        spam_decay_threshold: f64,
+        slowmode_hysteresis: f64,
+        chat_cooldown_secs: f64,
+        guild_cooldown_secs: Option<f64>,
+        content_window_secs: f64,
+        content_max_hashes: usize,
+        attachment_window_secs: f64,
+        attachment_threshold: usize,
+        attachment_duplicate_threshold: usize,
+        event_buffer_size: usize,
+    ) -> Self {
+        let chat_window_secs = 30.0;
+        ActivityTrackerRust {
+            spam_window_secs: 10.0,
+            spam_threshold: 20,
+            chat_active_window_secs: 20.0,
+            chat_min_messages: 6,
+            chat_min_users: 3,
+            chat_cooldown_secs,
+            guild_cooldown_secs,
+            chat_trigger_chance: 0.35,
+            mention_trigger_chance,
+            reply_trigger_chance,
+            mention_cooldown_secs,
+            join_window_secs,
+            join_threshold,
+            young_account_secs,
+            history_retention_secs: history_retention_secs
+                .unwrap_or(chat_window_secs)
+                .max(chat_window_secs),
+            violation_half_life_secs,
+            spam_decay_secs,
+            spam_decay_threshold,
+            slowmode_hysteresis,
+            slowmode_table: Mutex::new(vec![(2.0, 5), (5.0, 15)]),
+            rng: Mutex::new(ChatRng::default()),
+            content_window_secs,
+            content_max_hashes: content_max_hashes.max(1),
+            attachment_window_secs,
+            attachment_threshold,
+            attachment_duplicate_threshold,
+            event_buffer_size,
+            events: Mutex::new(EventLog::default()),
+            rule_modes: RuleModes::new(),
+            clock_offset: Mutex::new(0.0),
+        }
+    }
+
+    /// Reseed the chat-trigger generator so rolls are reproducible.
+    fn set_rng_seed(&self, seed: u64) {
+        if let Ok(mut rng) = self.rng.lock() {
+            rng.rng = SmallRng::seed_from_u64(seed);
+        }
+    }
+
+    /// Shift the internal clock used when `now_ts` is omitted, so tests can
+    /// fast-forward time. Explicit timestamps are not affected.
+    fn set_clock_offset(&self, secs: f64) {
+        if let Ok(mut offset) = self.clock_offset.lock() {
+            *offset = secs;
+        }
+    }
+
+    /// The internal clock: Unix time plus the clock offset.
+    fn clock(&self) -> f64 {
+        self.now(None)
+    }
+
+    /// Test-only hook: make the next chat-trigger roll return `value`.
+    /// Queued values are consumed in order before the regular generator.
+    fn force_next_roll(&self, value: f64) {
+        if let Ok(mut rng) = self.rng.lock() {
+            rng.forced_rolls.push_back(value);
+        }
+    }
+
+    /// Check if a user is spamming in a guild.
+    /// Counts are kept per (guild_id, user_id); pass guild_id=0 only for
+    /// callers without guild context, which share one global record.
+    /// Returns (is_spam, message_count_in_window).
+    /// Exempt users always return (false, 0) and accumulate nothing.
+    #[pyo3(signature = (user_id, now_ts = None, guild_id = 0))]
+    fn check_spam(&self, user_id: u64, now_ts: Option<f64>, guild_id: u64) -> (bool, usize) {
+        let now_ts = self.now(now_ts);
+        if SPAM_EXEMPT.contains(&user_id) || self.rule_modes.is_off(TrackerRule::Spam as usize) {
+            return (false, 0);
+        }
+        let mut entry = SPAM_TIMESTAMPS.entry((guild_id, user_id)).or_default();
+        self.spam_step((guild_id, user_id), &mut entry, now_ts)
+    }
+
+    /// Check a burst of (user_id, timestamp) messages in order, as if calling
+    /// `check_spam` for each. Each user's record is locked once per contiguous
+    /// run of that user's messages.
+    #[pyo3(signature = (entries, guild_id = 0))]
+    fn check_spam_batch(&self, entries: Vec<(u64, f64)>, guild_id: u64) -> Vec<(bool, usize)> {
+        let mut results = Vec::with_capacity(entries.len());
+        let mut i = 0;
+        while i < entries.len() {
+            let user_id = entries[i].0;
+            let run_end = entries[i..]
+                .iter()
+                .position(|&(uid, _)| uid != user_id)
+                .map_or(entries.len(), |offset| i + offset);
+
+            if SPAM_EXEMPT.contains(&user_id) || self.rule_modes.is_off(TrackerRule::Spam as usize) {
+                results.extend(std::iter::repeat_n((false, 0), run_end - i));
+            } else {
+                let mut entry = SPAM_TIMESTAMPS.entry((guild_id, user_id)).or_default();
+                for &(_, now_ts) in &entries[i..run_end] {
+                    results.push(self.spam_step((guild_id, user_id), &mut entry, now_ts));
+                }
+            }
+            i = run_end;
+        }
+        results
+    }
+
+    /// Decay-based spam check: each message adds 1 to a per-user score that
+    /// decays exponentially with `spam_decay_secs`, catching sustained
+    /// medium-rate spam that slips between hard windows.
+    /// Scores are kept per (guild_id, user_id) like `check_spam`.
+    /// Returns (is_spam, score).
+    #[pyo3(signature = (user_id, now_ts = None, guild_id = 0))]
+    fn check_spam_decay(&self, user_id: u64, now_ts: Option<f64>, guild_id: u64) -> (bool, f64) {
+        let now_ts = self.now(now_ts);
+        if SPAM_EXEMPT.contains(&user_id) || self.rule_modes.is_off(TrackerRule::SpamDecay as usize) {
+            return (false, 0.0);
+        }
+        let mut entry = SPAM_DECAY.entry((guild_id, user_id)).or_insert((0.0, now_ts));
+        let score = self.decayed_spam_score(*entry, now_ts) + 1.0;
+        *entry = (score, now_ts.max(entry.1));
+        drop(entry);
+        bump(CoreCounter::SpamDecayChecks, 1);
+        let is_spam = score > self.spam_decay_threshold
+            && self.fire(TrackerRule::SpamDecay, now_ts, guild_id, user_id, vec![
+                ("score", EventValue::Float(score)),
+                ("threshold", EventValue::Float(self.spam_decay_threshold)),
+                ("decay_secs", EventValue::Float(self.spam_decay_secs)),
+            ]);
+        (is_spam, score)
+    }
+
+    /// Record an attachment or sticker for image-spam detection. Pass a hash
+    /// of the attachment URL or content, or the sticker ID. Returns
+    /// (is_spam, attachment_count, duplicate_count) for the window, counting
+    /// this one; duplicate_count is the posts of this hash. Kept per
+    /// (guild_id, user_id) like `check_spam`, and exempt users get (false, 0, 0).
+    #[pyo3(signature = (user_id, guild_id, attachment_hash, now_ts = None))]
+    fn record_attachment(
+        &self,
+        user_id: u64,
+        guild_id: u64,
+        attachment_hash: u64,
+        now_ts: Option<f64>,
+    ) -> (bool, usize, usize) {
+        let now_ts = self.now(now_ts);
+        if SPAM_EXEMPT.contains(&user_id) || self.rule_modes.is_off(TrackerRule::AttachmentSpam as usize) {
+            return (false, 0, 0);
+        }
+        let cutoff = now_ts - self.attachment_window_secs;
+        let mut entry = ATTACHMENTS.entry((guild_id, user_id)).or_default();
+        entry.retain(|&(ts, _)| ts > cutoff);
+        entry.push_back((now_ts, attachment_hash));
+        let count = entry.len();
+        let duplicates = entry.iter().filter(|&&(_, hash)| hash == attachment_hash).count();
+        drop(entry);
+        bump(CoreCounter::AttachmentChecks, 1);
+
+        let is_spam = (count >= self.attachment_threshold || duplicates >= self.attachment_duplicate_threshold)
+            && self.fire(TrackerRule::AttachmentSpam, now_ts, guild_id, user_id, vec![
+                ("count", EventValue::Int(count as u64)),
+                ("duplicate_count", EventValue::Int(duplicates as u64)),
+                ("threshold", EventValue::Int(self.attachment_threshold as u64)),
+                ("duplicate_threshold", EventValue::Int(self.attachment_duplicate_threshold as u64)),
+                ("window_secs", EventValue::Float(self.attachment_window_secs)),
+            ]);
+        (is_spam, count, duplicates)
+    }
+
+    /// `check_spam` as a `SpamVerdict` (rule "spam"; score and count are
+    /// both the messages in the window).
+    #[pyo3(signature = (user_id, now_ts = None, guild_id = 0))]
+    fn check_spam_verdict(&self, user_id: u64, now_ts: Option<f64>, guild_id: u64) -> SpamVerdict {
+        let exempt = SPAM_EXEMPT.contains(&user_id);
+        let (flagged, count) = self.check_spam(user_id, now_ts, guild_id);
+        self.window_verdict(flagged, count, exempt)
+    }
+
+    /// `check_spam_batch` as a list of `SpamVerdict`.
+    #[pyo3(signature = (entries, guild_id = 0))]
+    fn check_spam_batch_verdict(&self, entries: Vec<(u64, f64)>, guild_id: u64) -> Vec<SpamVerdict> {
+        let exempt: Vec<bool> = entries.iter().map(|(user_id, _)| SPAM_EXEMPT.contains(user_id)).collect();
+        self.check_spam_batch(entries, guild_id)
+            .into_iter()
+            .zip(exempt)
+            .map(|((flagged, count), exempt)| self.window_verdict(flagged, count, exempt))
+            .collect()
+    }
+
+    /// `check_spam_decay` as a `SpamVerdict` (rule "spam_decay"; count is 0).
+    #[pyo3(signature = (user_id, now_ts = None, guild_id = 0))]
+    fn check_spam_decay_verdict(&self, user_id: u64, now_ts: Option<f64>, guild_id: u64) -> SpamVerdict {
+        let exempt = SPAM_EXEMPT.contains(&user_id);
+        let (flagged, score) = self.check_spam_decay(user_id, now_ts, guild_id);
+        SpamVerdict {
+            flagged,
+            rule: "spam_decay",
+            score,
+            count: 0,
+            window_secs: self.spam_decay_secs,
+            details: vec![
+                ("threshold", EventValue::Float(self.spam_decay_threshold)),
+                ("exempt", EventValue::Bool(exempt)),
+                ("mode", EventValue::Str(self.rule_modes.get(TrackerRule::SpamDecay as usize).name())),
+            ],
+        }
+    }
+
+    /// Set how a detection rule ("spam", "spam_decay", "attachment_spam", or
+    /// "raid") reports: "enforce" (the default) flags as usual, "shadow"
+    /// keeps state and emits events marked `shadow` but never returns
+    /// flagged, and "off" skips the rule entirely (no state, zero counts).
+    fn set_rule_mode(&self, rule: &str, mode: &str) -> PyResult<()> {
+        let rule = TrackerRule::parse(rule)?;
+        self.rule_modes.set(rule as usize, RuleMode::parse(mode)?);
+        Ok(())
+    }
+
+    /// Current mode of a detection rule.
+    fn rule_mode(&self, rule: &str) -> PyResult<&'static str> {
+        Ok(self.rule_modes.get(TrackerRule::parse(rule)? as usize).name())
+    }
+
+    /// Times a shadowed rule fired without being reported.
+    fn shadow_hits(&self, rule: &str) -> PyResult<usize> {
+        Ok(self.rule_modes.shadow_hits(TrackerRule::parse(rule)? as usize))
+    }
+
+    /// Exempt a user from spam checks (moderators, trusted bots).
+    fn add_spam_exempt(&self, user_id: u64) {
+        SPAM_EXEMPT.insert(user_id);
+        forget_spam_user(user_id, None);
+    }
+
+    /// Remove a user's spam exemption.
+    fn remove_spam_exempt(&self, user_id: u64) {
+        SPAM_EXEMPT.remove(&user_id);
+    }
+
+    fn is_spam_exempt(&self, user_id: u64) -> bool {
+        SPAM_EXEMPT.contains(&user_id)
+    }
+
+    /// Replace the whole exemption list (e.g. synced from mod-role members).
+    fn set_spam_exempt(&self, user_ids: Vec<u64>) {
+        SPAM_EXEMPT.clear();
+        for user_id in user_ids {
+            SPAM_EXEMPT.insert(user_id);
+        }
+        SPAM_TIMESTAMPS.retain(|&(_, uid), _| !SPAM_EXEMPT.contains(&uid));
+        SPAM_DECAY.retain(|&(_, uid), _| !SPAM_EXEMPT.contains(&uid));
+    }
+
+    /// Add a violation of the given weight and return the user's decayed score.
+    #[pyo3(signature = (user_id, now_ts = None, weight = 1.0))]
+    fn record_violation(&self, user_id: u64, now_ts: Option<f64>, weight: f64) -> f64 {
+        let now_ts = self.now(now_ts);
+        let mut entry = VIOLATIONS.entry(user_id).or_insert((0.0, now_ts));
+        let score = self.decayed_violation(*entry, now_ts) + weight;
+        *entry = (score, now_ts.max(entry.1));
+        score
+    }
+
+    /// Current violation score, decayed exponentially since the last violation.
+    #[pyo3(signature = (user_id, now_ts = None))]
+    fn violation_score(&self, user_id: u64, now_ts: Option<f64>) -> f64 {
+        let now_ts = self.now(now_ts);
+        VIOLATIONS
+            .get(&user_id)
+            .map(|entry| self.decayed_violation(*entry, now_ts))
+            .unwrap_or(0.0)
+    }
+
+    /// Record chat activity and determine if bot should jump into conversation.
+    /// Returns true if the bot should send a reply.
+    /// `channel_id` (keyword-only) keys the channel cooldown and feeds
+    /// per-channel activity (slowmode).
+    #[pyo3(signature = (guild_id, user_id, now_ts = None, *, channel_id))]
+    fn record_chat_activity(&self, guild_id: u64, user_id: u64, now_ts: Option<f64>, channel_id: u64) -> bool {
+        let now_ts = self.now(now_ts);
+        self.chat_decision(guild_id, channel_id, user_id, now_ts, false, false).triggered()
+    }
+
+    /// Record a burst of (guild_id, channel_id, user_id, timestamp) messages in
+    /// order, as if calling `record_chat_activity` for each. Returns one result
+    /// per entry.
+    fn record_chat_activity_batch(&self, entries: Vec<(u64, u64, u64, f64)>) -> Vec<bool> {
+        entries
+            .into_iter()
+            .map(|(guild_id, channel_id, user_id, now_ts)| {
+                self.chat_decision(guild_id, channel_id, user_id, now_ts, false, false).triggered()
+            })
+            .collect()
+    }
+
+    /// Record chat activity, taking direct address of the bot into account.
+    /// Returns (triggered, reason) where reason is one of "mention", "reply",
+    /// "activity" when triggered, or "inactive", "quiet_hours",
+    /// "channel_cooldown", "guild_cooldown", "chance" otherwise.
+    #[pyo3(signature = (guild_id, user_id, now_ts = None, mentions_bot = false, is_reply_to_bot = false, *, channel_id))]
+    fn record_chat_activity_ex(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        now_ts: Option<f64>,
+        mentions_bot: bool,
+        is_reply_to_bot: bool,
+        channel_id: u64,
+    ) -> (bool, &'static str) {
+        let now_ts = self.now(now_ts);
+        let decision = self.chat_decision(guild_id, channel_id, user_id, now_ts, mentions_bot, is_reply_to_bot);
+        (decision.triggered(), decision.as_str())
+    }
+
+    /// Seconds until an activity trigger is possible in the channel again,
+    /// counting both the channel and guild cooldowns (0.0 if not on cooldown).
+    /// Without `channel_id`, only the guild cooldown counts.
+    #[pyo3(signature = (guild_id, now_ts = None, channel_id = 0))]
+    fn chat_cooldown_remaining(&self, guild_id: u64, now_ts: Option<f64>, channel_id: u64) -> f64 {
+        let now_ts = self.now(now_ts);
+        let channel = CHANNEL_COOLDOWNS
+            .get(&(guild_id, channel_id))
+            .map_or(0.0, |last| *last + self.chat_cooldown_secs - now_ts);
+        let guild = match (self.guild_cooldown_secs, CHAT_COOLDOWNS.get(&guild_id)) {
+            (Some(cooldown), Some(last)) => *last + cooldown - now_ts,
+            _ => 0.0,
+        };
+        channel.max(guild).max(0.0)
+    }
+
+    /// Stop unsolicited chat triggers daily from `start_hour` to `end_hour`
+    /// (0-23, end exclusive) in the guild's local time, given as minutes
+    /// from UTC (e.g. 330 for UTC+5:30). Windows may wrap midnight (22 to 6).
+    /// Activity is still tracked, and mentions and replies still trigger.
+    #[pyo3(signature = (guild_id, start_hour, end_hour, utc_offset_minutes = 0))]
+    fn set_quiet_hours(&self, guild_id: u64, start_hour: u8, end_hour: u8, utc_offset_minutes: i32) -> PyResult<()> {
+        QUIET_HOURS.insert(guild_id, quiet_hours(start_hour, end_hour, utc_offset_minutes)?);
+        Ok(())
+    }
+
+    /// Remove a guild's quiet hours. Returns whether it had any.
+    fn clear_quiet_hours(&self, guild_id: u64) -> bool {
+        QUIET_HOURS.remove(&guild_id).is_some()
+    }
+
+    /// (start_hour, end_hour, utc_offset_minutes) for the guild, if set.
+    fn quiet_hours(&self, guild_id: u64) -> Option<(u8, u8, i32)> {
+        QUIET_HOURS
+            .get(&guild_id)
+            .map(|quiet| (quiet.start_hour, quiet.end_hour, quiet.utc_offset_minutes))
+    }
+
+    /// Clear chat cooldowns for one channel, or the guild and all its channels.
+    #[pyo3(signature = (guild_id, channel_id = None))]
+    fn reset_chat_cooldown(&self, guild_id: u64, channel_id: Option<u64>) {
+        match channel_id {
+            Some(channel_id) => {
+                CHANNEL_COOLDOWNS.remove(&(guild_id, channel_id));
+            }
+            None => {
+                CHAT_COOLDOWNS.remove(&guild_id);
+                CHANNEL_COOLDOWNS.retain(|&(gid, _), _| gid != guild_id);
+            }
+        }
+    }
+
+    /// Timestamp of the last chat trigger in a guild, if any.
+    fn last_chat_trigger(&self, guild_id: u64) -> Option<f64> {
+        CHAT_COOLDOWNS.get(&guild_id).map(|last| *last)
+    }
+
+    /// Record a member join and check for a raid.
+    /// Returns (is_raid, joins_in_window, young_account_count).
+    #[pyo3(signature = (guild_id, user_id, account_created_ts, now_ts = None))]
+    fn record_join(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        account_created_ts: f64,
+        now_ts: Option<f64>,
+    ) -> (bool, usize, usize) {
+        let now_ts = self.now(now_ts);
+        if self.rule_modes.is_off(TrackerRule::Raid as usize) {
+            return (false, 0, 0);
+        }
+        let mut entry = GUILD_JOINS.entry(guild_id).or_default();
+        entry.push_back((now_ts, user_id, account_created_ts));
+        self.prune_joins(&mut entry, now_ts);
+
+        let joins = entry.len();
+        let young = entry
+            .iter()
+            .filter(|&&(ts, _, created)| ts - created < self.young_account_secs)
+            .count();
+        drop(entry);
+
+        let is_raid = joins >= self.join_threshold
+            && self.fire(TrackerRule::Raid, now_ts, guild_id, user_id, vec![
+                ("joins", EventValue::Int(joins as u64)),
+                ("young_accounts", EventValue::Int(young as u64)),
+                ("threshold", EventValue::Int(self.join_threshold as u64)),
+                ("window_secs", EventValue::Float(self.join_window_secs)),
+                ("young_account_secs", EventValue::Float(self.young_account_secs)),
+            ]);
+        (is_raid, joins, young)
+    }
+
+    /// User IDs that joined the guild within the join window, oldest first.
+    #[pyo3(signature = (guild_id, now_ts = None))]
+    fn recent_joiners(&self, guild_id: u64, now_ts: Option<f64>) -> Vec<u64> {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - self.join_window_secs;
+        GUILD_JOINS
+            .get(&guild_id)
+            .map(|joins| {
+                joins
+                    .iter()
+                    .filter(|&&(ts, _, _)| ts > cutoff)
+                    .map(|&(_, uid, _)| uid)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record a message for cross-user copy detection. Returns
+    /// (copies_in_window, distinct_senders) for its fingerprint in the guild,
+    /// including this message; blank content is not recorded and gives (0, 0).
+    #[pyo3(signature = (guild_id, user_id, content, now_ts = None))]
+    fn record_content(&self, guild_id: u64, user_id: u64, content: &str, now_ts: Option<f64>) -> (usize, usize) {
+        let now_ts = self.now(now_ts);
+        match content_fingerprint(content) {
+            Some(hash) => self.record_fingerprint(guild_id, user_id, hash, now_ts),
+            None => (0, 0),
+        }
+    }
+
+    /// Fingerprint `record_content` uses for `content`, for `content_senders`;
+    /// the same value as the module-level `content_fingerprint`. None for
+    /// blank content.
+    fn content_hash(&self, content: &str) -> Option<u64> {
+        content_fingerprint(content)
+    }
+
+    /// Users who posted the fingerprinted content within the window, in
+    /// first-post order.
+    #[pyo3(signature = (guild_id, content_hash, now_ts = None))]
+    fn content_senders(&self, guild_id: u64, content_hash: u64, now_ts: Option<f64>) -> Vec<u64> {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - self.content_window_secs;
+        GUILD_CONTENT
+            .get(&guild_id)
+            .and_then(|guild| {
+                guild.hashes.get(&content_hash).map(|entry| {
+                    let recent = entry.posts.iter().filter(|&&(ts, _)| ts > cutoff && ts <= now_ts).copied().collect();
+                    distinct_senders(&recent)
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    /// Take queued decision events (spam flagged, chat triggered, raid
+    /// detected) as dicts with the rule, its evidence, and the config values
+    /// it used, oldest first. `seq` increases by one per event recorded, so a
+    /// gap means events were dropped from a full buffer.
+    #[pyo3(signature = (max_events = None))]
+    fn drain_events<'py>(&self, py: Python<'py>, max_events: Option<usize>) -> PyResult<&'py PyList> {
+        let drained: Vec<TrackerEvent> = {
+            let mut log = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+            let take = max_events.unwrap_or(log.events.len()).min(log.events.len());
+            log.events.drain(..take).collect()
+        };
+        let list = PyList::empty(py);
+        for event in &drained {
+            list.append(event.to_dict(py)?)?;
+        }
+        Ok(list)
+    }
+
+    /// Most active users in a guild over the last `window_secs`, as (user_id, count)
+    /// sorted by count descending, then user_id ascending.
+    /// The window is capped by the tracker's chat history retention.
+    #[pyo3(signature = (guild_id, window_secs, now_ts = None, limit = 10))]
+    fn top_active_users(&self, guild_id: u64, window_secs: f64, now_ts: Option<f64>, limit: usize) -> Vec<(u64, usize)> {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - window_secs.min(self.history_retention_secs);
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        if let Some(activity) = CHAT_ACTIVITY.get(&guild_id) {
+            for &(ts, uid) in activity.iter() {
+                if ts >= cutoff && ts <= now_ts {
+                    *counts.entry(uid).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut ranked: Vec<(u64, usize)> = counts.into_iter().collect();
+        ranked.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.truncate(limit);
+        ranked
+    }
+
+    /// Messages per minute in a guild over the last `window_secs`. The window
+    /// is capped by the tracker's chat history retention (`history_retention_secs`),
+    /// so raise that to measure longer windows.
+    #[pyo3(signature = (guild_id, now_ts = None, window_secs = 60.0))]
+    fn message_rate(&self, guild_id: u64, now_ts: Option<f64>, window_secs: f64) -> PyResult<f64> {
+        let now_ts = self.now(now_ts);
+        let window = self.rate_window(window_secs)?;
+        let count = CHAT_ACTIVITY
+            .get(&guild_id)
+            .map_or(0, |activity| count_since(&activity, now_ts - window, now_ts));
+        Ok(count as f64 * 60.0 / window)
+    }
+
+    /// `message_rate` for every guild with messages in the window.
+    #[pyo3(signature = (now_ts = None, window_secs = 60.0))]
+    fn message_rates_all(&self, now_ts: Option<f64>, window_secs: f64) -> PyResult<HashMap<u64, f64>> {
+        let now_ts = self.now(now_ts);
+        let window = self.rate_window(window_secs)?;
+        // Count under each shard's read lock only; the dict is built after
+        let counts: Vec<(u64, usize)> = CHAT_ACTIVITY
+            .iter()
+            .map(|entry| (*entry.key(), count_since(entry.value(), now_ts - window, now_ts)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        Ok(counts
+            .into_iter()
+            .map(|(guild_id, count)| (guild_id, count as f64 * 60.0 / window))
+            .collect())
+    }
+
+    /// Messages per time bucket in a guild, oldest bucket first; the last bucket
+    /// ends at `now_ts` and may be partial. Only data within the tracker's
+    /// history retention is counted, so older buckets read as 0.
+    #[pyo3(signature = (guild_id, now_ts = None, bucket_secs = 60.0, num_buckets = 60))]
+    fn activity_histogram(&self, guild_id: u64, now_ts: Option<f64>, bucket_secs: f64, num_buckets: usize) -> PyResult<Vec<u64>> {
+        let now_ts = self.now(now_ts);
+        if bucket_secs.is_nan() || bucket_secs <= 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "bucket_secs must be positive",
+            ));
+        }
+        let mut buckets = vec![0u64; num_buckets];
+        if let Some(activity) = CHAT_ACTIVITY.get(&guild_id) {
+            for &(ts, _) in activity.iter() {
+                let age = now_ts - ts;
+                if age < 0.0 {
+                    continue;
+                }
+                let from_newest = (age / bucket_secs) as usize;
+                if from_newest < num_buckets {
+                    buckets[num_buckets - 1 - from_newest] += 1;
+                }
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// Replace the slowmode table: (msgs_per_sec, slowmode_secs) breakpoints,
+    /// strictly ascending in both columns. A channel gets the slowmode of the
+    /// highest breakpoint its message rate exceeds.
+    fn set_slowmode_table(&self, breakpoints: Vec<(f64, u32)>) -> PyResult<()> {
+        let ascending = breakpoints
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0 && pair[0].1 < pair[1].1);
+        let valid_rates = breakpoints.iter().all(|&(rate, _)| rate.is_finite() && rate >= 0.0);
+        if !ascending || !valid_rates {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "slowmode breakpoints must be finite and strictly ascending",
+            ));
+        }
+        if let Ok(mut table) = self.slowmode_table.lock() {
+            *table = breakpoints;
+        }
+        Ok(())
+    }
+
+    /// Recommended slowmode for a channel in seconds (0 = off), from its
+    /// message rate over the active window. Channels with fewer than the
+    /// chat minimum of distinct users get 0 (single-user floods are spam, not
+    /// slowmode material). Lowering is damped by the hysteresis factor so the
+    /// value doesn't flap between polls. Needs `channel_id` passed to
+    /// `record_chat_activity`.
+    #[pyo3(signature = (guild_id, channel_id, now_ts = None))]
+    fn recommend_slowmode(&self, guild_id: u64, channel_id: u64, now_ts: Option<f64>) -> u32 {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - self.chat_active_window_secs;
+        let (count, users) = CHANNEL_ACTIVITY
+            .get(&(guild_id, channel_id))
+            .map(|activity| {
+                let mut users = std::collections::HashSet::new();
+                let mut count = 0;
+                for &(_, uid) in activity.iter().filter(|&&(ts, _)| ts >= cutoff) {
+                    count += 1;
+                    users.insert(uid);
+                }
+                (count, users.len())
+            })
+            .unwrap_or((0, 0));
+        let rate = if users < self.chat_min_users {
+            0.0
+        } else {
+            count as f64 / self.chat_active_window_secs
+        };
+
+        let table = match self.slowmode_table.lock() {
+            Ok(table) => table.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let level_for = |rate: f64| {
+            table
+                .iter()
+                .rev()
+                .find(|&&(threshold, _)| rate > threshold)
+                .map_or(0, |&(_, secs)| secs)
+        };
+
+        let key = (guild_id, channel_id);
+        let previous = SLOWMODE_LEVELS.get(&key).map_or(0, |level| *level);
+        let raised = level_for(rate);
+        let recommended = if raised >= previous {
+            raised
+        } else {
+            // Only step down as far as the damped rate allows
+            level_for(rate / self.slowmode_hysteresis.clamp(f64::EPSILON, 1.0)).min(previous)
+        };
+
+        if recommended == 0 {
+            SLOWMODE_LEVELS.remove(&key);
+        } else {
+            SLOWMODE_LEVELS.insert(key, recommended);
+        }
+        recommended
+    }
+
+    /// Drop expired timestamps and remove users/guilds with nothing left in any window.
+    /// Safe to call from a periodic task while other methods run.
+    /// Returns the number of map entries removed.
+    #[pyo3(signature = (now_ts = None))]
+    fn cleanup(&self, now_ts: Option<f64>) -> usize {
+        let now_ts = self.now(now_ts);
+        TRACKED_MAPS.iter().map(|map| (map.cleanup)(self, now_ts)).sum()
+    }
+
+    /// Clear all tracking state, including spam exemptions and quiet hours.
+    fn clear_all(&self) {
+        SPAM_EXEMPT.clear();
+        for map in TRACKED_MAPS {
+            (map.clear_all)();
+        }
+    }
+
+    /// Size of the tracking state.
+    /// `approx_bytes` is a rough estimate: container capacity times element size
+    /// plus key and container headers per entry; allocator and map overhead are ignored.
+    fn stats(&self) -> HashMap<&'static str, usize> {
+        let mut spam_timestamps = 0;
+        let mut bytes = 0;
+        for entry in SPAM_TIMESTAMPS.iter() {
+            spam_timestamps += entry.len();
+            bytes += size_of::<SpamKey>() + size_of::<Vec<f64>>() + entry.capacity() * size_of::<f64>();
+        }
+
+        let mut chat_events = 0;
+        for entry in CHAT_ACTIVITY.iter() {
+            chat_events += entry.len();
+            bytes += size_of::<u64>()
+                + size_of::<VecDeque<(f64, u64)>>()
+                + entry.capacity() * size_of::<(f64, u64)>();
+        }
+
+        let mut joins = 0;
+        for entry in GUILD_JOINS.iter() {
+            joins += entry.len();
+            bytes += size_of::<u64>()
+                + size_of::<VecDeque<JoinRecord>>()
+                + entry.capacity() * size_of::<JoinRecord>();
+        }
+
+        let mut attachment_events = 0;
+        for entry in ATTACHMENTS.iter() {
+            attachment_events += entry.len();
+            bytes += size_of::<SpamKey>()
+                + size_of::<VecDeque<(f64, u64)>>()
+                + entry.capacity() * size_of::<(f64, u64)>();
+        }
+
+        for entry in CHANNEL_ACTIVITY.iter() {
+            bytes += size_of::<ChannelKey>()
+                + size_of::<VecDeque<(f64, u64)>>()
+                + entry.capacity() * size_of::<(f64, u64)>();
+        }
+
+        let cooldowns = CHAT_COOLDOWNS.len() + CHANNEL_COOLDOWNS.len();
+        bytes += CHAT_COOLDOWNS.len() * (size_of::<u64>() + size_of::<f64>());
+        bytes += CHANNEL_COOLDOWNS.len() * (size_of::<ChannelKey>() + size_of::<f64>());
+        bytes += SPAM_EXEMPT.len() * size_of::<u64>();
+        bytes += VIOLATIONS.len() * (size_of::<u64>() + size_of::<(f64, f64)>());
+        bytes += SPAM_DECAY.len() * (size_of::<SpamKey>() + size_of::<(f64, f64)>());
+
+        let (pending_events, dropped_events) = {
+            let log = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+            (log.events.len(), log.dropped)
+        };
+        bytes += pending_events * size_of::<TrackerEvent>();
+
+        let mut content_hashes = 0;
+        for entry in GUILD_CONTENT.iter() {
+            content_hashes += entry.hashes.len();
+            bytes += size_of::<u64>() + size_of::<GuildContent>();
+            for posts in entry.hashes.values() {
+                bytes += 2 * size_of::<u64>()
+                    + size_of::<ContentPosts>()
+                    + posts.posts.capacity() * size_of::<(f64, u64)>();
+            }
+        }
+
+        HashMap::from([
+            ("tracked_users", SPAM_TIMESTAMPS.len()),
+            ("tracked_guilds", CHAT_ACTIVITY.len()),
+            ("spam_timestamps", spam_timestamps),
+            ("chat_events", chat_events),
+            ("total_timestamps", spam_timestamps + chat_events + joins),
+            ("cooldown_entries", cooldowns),
+            ("join_guilds", GUILD_JOINS.len()),
+            ("join_events", joins),
+            ("spam_exempt", SPAM_EXEMPT.len()),
+            ("violation_users", VIOLATIONS.len()),
+            ("spam_decay_users", SPAM_DECAY.len()),
+            ("attachment_users", ATTACHMENTS.len()),
+            ("attachment_events", attachment_events),
+            ("tracked_channels", CHANNEL_ACTIVITY.len()),
+            ("content_hashes", content_hashes),
+            ("pending_events", pending_events),
+            ("dropped_events", dropped_events),
+            ("approx_bytes", bytes),
+        ])
+    }
+
+    /// Snapshot spam, chat activity, cooldown, join, and attachment state as a JSON string.
+    /// The object carries a `version` field; `load_state` rejects other versions.
+    fn export_state(&self) -> PyResult<String> {
+        let snapshot = TrackerSnapshot {
+            version: TRACKER_STATE_VERSION,
+            spam: SPAM_TIMESTAMPS
+                .iter()
+                .map(|e| (*e.key(), e.value().clone()))
+                .collect(),
+            chat: CHAT_ACTIVITY
+                .iter()
+                .map(|e| (*e.key(), e.value().iter().copied().collect()))
+                .collect(),
+            cooldowns: CHAT_COOLDOWNS.iter().map(|e| (*e.key(), *e.value())).collect(),
+            channel_cooldowns: CHANNEL_COOLDOWNS.iter().map(|e| (*e.key(), *e.value())).collect(),
+            joins: GUILD_JOINS
+                .iter()
+                .map(|e| (*e.key(), e.value().iter().copied().collect()))
+                .collect(),
+            attachments: ATTACHMENTS
+                .iter()
+                .map(|e| (*e.key(), e.value().iter().copied().collect()))
+                .collect(),
+        };
+        serde_json::to_string(&snapshot).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to export state: {}", e))
+        })
+    }
+
+    /// Restore state produced by `export_state`, dropping anything already
+    /// outside its window at `now_ts`. Loaded keys replace existing ones.
+    /// Returns the number of map entries restored.
+    #[pyo3(signature = (data, now_ts = None))]
+    fn load_state(&self, data: &str, now_ts: Option<f64>) -> PyResult<usize> {
+        let now_ts = self.now(now_ts);
+        let probe: SnapshotVersion = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid tracker state: {}", e))
+        })?;
+        if probe.version != TRACKER_STATE_VERSION {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unsupported tracker state version {} (expected {})",
+                probe.version, TRACKER_STATE_VERSION
+            )));
+        }
+        let snapshot: TrackerSnapshot = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid tracker state: {}", e))
+        })?;
+
+        let spam_cutoff = now_ts - self.spam_window_secs;
+        let chat_cutoff = now_ts - self.history_retention_secs;
+        let cooldown = self.longest_cooldown_secs();
+        let mut restored = 0;
+
+        for (key, mut timestamps) in snapshot.spam {
+            timestamps.retain(|&ts| ts > spam_cutoff);
+            if !timestamps.is_empty() {
+                SPAM_TIMESTAMPS.insert(key, timestamps);
+                restored += 1;
+            }
+        }
+        for (guild_id, activity) in snapshot.chat {
+            let activity: VecDeque<_> = activity.into_iter().filter(|&(ts, _)| ts >= chat_cutoff).collect();
+            if !activity.is_empty() {
+                CHAT_ACTIVITY.insert(guild_id, activity);
+                restored += 1;
+            }
+        }
+        for (guild_id, last) in snapshot.cooldowns {
+            if now_ts - last < cooldown {
+                CHAT_COOLDOWNS.insert(guild_id, last);
+                restored += 1;
+            }
+        }
+        for (key, last) in snapshot.channel_cooldowns {
+            if now_ts - last < cooldown {
+                CHANNEL_COOLDOWNS.insert(key, last);
+                restored += 1;
+            }
+        }
+        for (guild_id, joins) in snapshot.joins {
+            let mut joins: VecDeque<_> = joins.into_iter().collect();
+            self.prune_joins(&mut joins, now_ts);
+            if !joins.is_empty() {
+                GUILD_JOINS.insert(guild_id, joins);
+                restored += 1;
+            }
+        }
+        let attachment_cutoff = now_ts - self.attachment_window_secs;
+        for (key, attachments) in snapshot.attachments {
+            let attachments: VecDeque<_> = attachments.into_iter().filter(|&(ts, _)| ts > attachment_cutoff).collect();
+            if !attachments.is_empty() {
+                ATTACHMENTS.insert(key, attachments);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Warm the spam windows of `guild_id` with historical (user_id,
+    /// timestamp) messages, e.g. replayed from the database at startup.
+    /// Nothing is flagged and no events are emitted. Records after `now_ts`
+    /// are skipped, as are exempt users; `check_spam_decay` scores count every
+    /// record with its decay, the windowed check only those inside the spam
+    /// window. Each user's record is locked once. Returns the number of
+    /// records added to spam windows.
+    #[pyo3(signature = (entries, now_ts = None, guild_id = 0))]
+    fn prime_spam(&self, mut entries: Vec<(u64, f64)>, now_ts: Option<f64>, guild_id: u64) -> usize {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - self.spam_window_secs;
+        let windowed = !self.rule_modes.is_off(TrackerRule::Spam as usize);
+        let decayed = !self.rule_modes.is_off(TrackerRule::SpamDecay as usize);
+        entries.retain(|&(_, ts)| ts <= now_ts);
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        let mut primed = 0;
+        for run in entries.chunk_by(|a, b| a.0 == b.0) {
+            let key = (guild_id, run[0].0);
+            if SPAM_EXEMPT.contains(&key.1) {
+                continue;
+            }
+            if windowed {
+                let start = run.partition_point(|&(_, ts)| ts <= cutoff);
+                if start < run.len() {
+                    let mut timestamps = SPAM_TIMESTAMPS.entry(key).or_default();
+                    timestamps.retain(|&ts| ts > cutoff);
+                    timestamps.extend(run[start..].iter().map(|&(_, ts)| ts));
+                    timestamps.sort_unstable_by(f64::total_cmp);
+                    primed += run.len() - start;
+                }
+            }
+            if decayed && self.spam_decay_secs > 0.0 {
+                let added: f64 = run
+                    .iter()
+                    .map(|&(_, ts)| (-(now_ts - ts) / self.spam_decay_secs).exp())
+                    .sum();
+                let mut score = SPAM_DECAY.entry(key).or_insert((0.0, now_ts));
+                let updated_ts = now_ts.max(score.1);
+                *score = (self.decayed_spam_score(*score, updated_ts) + added, updated_ts);
+            }
+        }
+        primed
+    }
+
+    /// Warm chat activity with historical (guild_id, user_id, timestamp)
+    /// messages, e.g. replayed from the database at startup. No triggers,
+    /// cooldowns, or random rolls happen. Records older than the activity
+    /// history at `now_ts`, or after it, are skipped. Per-channel activity
+    /// (slowmode) is not primed. Each guild is locked once. Returns the number
+    /// of records added.
+    #[pyo3(signature = (entries, now_ts = None))]
+    fn prime_chat_activity(&self, mut entries: Vec<(u64, u64, f64)>, now_ts: Option<f64>) -> usize {
+        let now_ts = self.now(now_ts);
+        let cutoff = now_ts - self.history_retention_secs;
+        entries.retain(|&(_, _, ts)| ts >= cutoff && ts <= now_ts);
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.2.total_cmp(&b.2)));
+        for run in entries.chunk_by(|a, b| a.0 == b.0) {
+            let mut activity = CHAT_ACTIVITY.entry(run[0].0).or_default();
+            prune_activity(&mut activity, cutoff);
+            let merge_from = activity.partition_point(|&(ts, _)| ts <= run[0].2);
+            let newer: Vec<_> = activity.drain(merge_from..).collect();
+            let mut merged: Vec<_> = run.iter().map(|&(_, user_id, ts)| (ts, user_id)).collect();
+            merged.extend(newer);
+            merged.sort_by(|a, b| a.0.total_cmp(&b.0));
+            activity.extend(merged);
+        }
+        entries.len()
+    }
+
+    /// Snapshot the state worth keeping across restarts (guild and channel
+    /// chat cooldowns, violation scores, spam exemptions, and quiet hours) as
+    /// pretty-printed JSON, sorted by id so equal state gives equal output.
+    /// Pass `ladder` to include its offense history, and `gate` for its
+    /// thresholds and raid mode. The object carries a `version` field;
+    /// `load_durable_state` rejects other versions.
+    #[pyo3(signature = (ladder = None, gate = None))]
+    fn export_durable_state(
+        &self,
+        ladder: Option<PyRef<'_, PunishmentLadder>>,
+        gate: Option<PyRef<'_, JoinGate>>,
+    ) -> PyResult<String> {
+        let mut snapshot = DurableSnapshot {
+            version: DURABLE_STATE_VERSION,
+            cooldowns: CHAT_COOLDOWNS
+                .iter()
+                .map(|e| DurableCooldown { guild_id: *e.key(), last_trigger_ts: *e.value() })
+                .collect(),
+            channel_cooldowns: CHANNEL_COOLDOWNS
+                .iter()
+                .map(|e| DurableChannelCooldown {
+                    guild_id: e.key().0,
+                    channel_id: e.key().1,
+                    last_trigger_ts: *e.value(),
+                })
+                .collect(),
+            violations: VIOLATIONS
+                .iter()
+                .map(|e| DurableViolation { user_id: *e.key(), score: e.value().0, updated_ts: e.value().1 })
+                .collect(),
+            spam_exempt: SPAM_EXEMPT.iter().map(|id| *id).collect(),
+            quiet_hours: QUIET_HOURS
+                .iter()
+                .map(|e| DurableQuietHours {
+                    guild_id: *e.key(),
+                    start_hour: e.start_hour,
+                    end_hour: e.end_hour,
+                    utc_offset_minutes: e.utc_offset_minutes,
+                })
+                .collect(),
+            ladder_offenses: ladder.map(|ladder| ladder.export()).unwrap_or_default(),
+            join_gate: gate.map(|gate| gate.lock().clone()),
+        };
+        snapshot.cooldowns.sort_by_key(|c| c.guild_id);
+        snapshot.channel_cooldowns.sort_by_key(|c| (c.guild_id, c.channel_id));
+        snapshot.violations.sort_by_key(|v| v.user_id);
+        snapshot.spam_exempt.sort_unstable();
+        snapshot.quiet_hours.sort_by_key(|q| q.guild_id);
+        serde_json::to_string_pretty(&snapshot).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to export durable state: {}", e))
+        })
+    }
+
+    /// Restore state produced by `export_durable_state`, dropping cooldowns
+    /// that have run out and violation scores that have decayed away by
+    /// `now_ts`. Loaded keys replace existing ones and exemptions are added.
+    /// Offense history goes to `ladder` and join gate settings to `gate`
+    /// when given, and are otherwise ignored. The snapshot is checked in full
+    /// first, so one that raises `ValueError` changes nothing.
+    /// Returns the number of entries restored.
+    #[pyo3(signature = (data, now_ts = None, ladder = None, gate = None))]
+    fn load_durable_state(
+        &self,
+        data: &str,
+        now_ts: Option<f64>,
+        ladder: Option<PyRef<'_, PunishmentLadder>>,
+        gate: Option<PyRef<'_, JoinGate>>,
+    ) -> PyResult<usize> {
+        let now_ts = self.now(now_ts);
+        let probe: SnapshotVersion = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid durable state: {}", e))
+        })?;
+        if probe.version != DURABLE_STATE_VERSION {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unsupported durable state version {} (expected {})",
+                probe.version, DURABLE_STATE_VERSION
+            )));
+        }
+        let snapshot: DurableSnapshot = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid durable state: {}", e))
+        })?;
+
+        // Check everything before applying anything
+        if snapshot.violations.iter().any(|v| v.score < 0.0) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Invalid durable state: violation scores must not be negative",
+            ));
+        }
+        let quiet_windows = snapshot
+            .quiet_hours
+            .iter()
+            .map(|q| Ok((q.guild_id, quiet_hours(q.start_hour, q.end_hour, q.utc_offset_minutes)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        let gate_settings = match (&gate, snapshot.join_gate) {
+            (Some(_), Some(settings)) => {
+                JoinGate::check_flags(&settings.flags)?;
+                Some(settings)
+            }
+            _ => None,
+        };
+
+        let cooldown = self.longest_cooldown_secs();
+        let mut restored = 0;
+
+        for entry in snapshot.cooldowns {
+            if now_ts - entry.last_trigger_ts < cooldown {
+                CHAT_COOLDOWNS.insert(entry.guild_id, entry.last_trigger_ts);
+                restored += 1;
+            }
+        }
+        for entry in snapshot.channel_cooldowns {
+            if now_ts - entry.last_trigger_ts < cooldown {
+                CHANNEL_COOLDOWNS.insert((entry.guild_id, entry.channel_id), entry.last_trigger_ts);
+                restored += 1;
+            }
+        }
+        for entry in snapshot.violations {
+            let stored = (entry.score, entry.updated_ts);
+            if self.decayed_violation(stored, now_ts) >= SCORE_EPSILON {
+                VIOLATIONS.insert(entry.user_id, stored);
+                restored += 1;
+            }
+        }
+        for user_id in snapshot.spam_exempt {
+            SPAM_EXEMPT.insert(user_id);
+            restored += 1;
+        }
+        for (guild_id, window) in quiet_windows {
+            QUIET_HOURS.insert(guild_id, window);
+            restored += 1;
+        }
+        if let Some(ladder) = ladder {
+            restored += ladder.restore(snapshot.ladder_offenses, now_ts);
+        }
+        if let (Some(gate), Some(settings)) = (gate, gate_settings) {
+            *gate.lock() = settings;
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Clear tracking data for a user, in one guild or (guild_id=None) everywhere.
+    /// Violation scores are global and only cleared when guild_id is None.
+    #[pyo3(signature = (user_id, guild_id = None))]
+    fn clear_user(&self, user_id: u64, guild_id: Option<u64>) {
+        forget_spam_user(user_id, guild_id);
+        if guild_id.is_none() {
+            VIOLATIONS.remove(&user_id);
+        }
+    }
+
+    /// Forget everything tracked about these users in every guild (e.g. after
+    /// a ban wave). Returns the number of records removed.
+    fn clear_users(&self, user_ids: Vec<u64>) -> usize {
+        let users: HashSet<u64> = user_ids.into_iter().collect();
+        TRACKED_MAPS.iter().map(|map| (map.forget_users)(&users)).sum()
+    }
+
+    /// Drop a user's timestamps and scores last updated before `cutoff_ts`,
+    /// keeping anything newer. Returns the number of records removed.
+    fn prune_user_before(&self, user_id: u64, cutoff_ts: f64) -> usize {
+        TRACKED_MAPS.iter().map(|map| (map.prune_user_before)(user_id, cutoff_ts)).sum()
+    }
+
+    /// Drop state for every guild not listed (e.g. guilds the bot has left).
+    /// The shared guild_id=0 record is kept. Returns the number of records removed.
+    fn retain_guilds(&self, guild_ids: Vec<u64>) -> usize {
+        let guilds: HashSet<u64> = guild_ids.into_iter().collect();
+        TRACKED_MAPS.iter().map(|map| (map.retain_guilds)(&guilds)).sum()
+    }
+
+    /// Clear tracking data for a guild, including its users' spam records.
+    /// Returns the number of map entries removed.
+    fn clear_guild(&self, guild_id: u64) -> usize {
+        TRACKED_MAPS.iter().map(|map| (map.clear_guild)(guild_id)).sum()
+    }
+}
+
+impl ActivityTrackerRust {
+    /// Verdict for a windowed `check_spam` result.
+    fn window_verdict(&self, flagged: bool, count: usize, exempt: bool) -> SpamVerdict {
+        SpamVerdict {
+            flagged,
+            rule: "spam",
+            score: count as f64,
+            count,
+            window_secs: self.spam_window_secs,
+            details: vec![
+                ("threshold", EventValue::Int(self.spam_threshold as u64)),
+                ("exempt", EventValue::Bool(exempt)),
+                ("mode", EventValue::Str(self.rule_modes.get(TrackerRule::Spam as usize).name())),
+            ],
+        }
+    }
+
+    /// Handle a detection rule that fired: emit its event (marked `shadow`
+    /// when shadowed) and return whether to report it as flagged.
+    fn fire(
+        &self,
+        rule: TrackerRule,
+        ts: f64,
+        guild_id: u64,
+        user_id: u64,
+        mut details: Vec<(&'static str, EventValue)>,
+    ) -> bool {
+        let reported = self.rule_modes.report(rule as usize);
+        if reported {
+            bump(rule.flag_counter(), 1);
+        } else {
+            details.push(("shadow", EventValue::Bool(true)));
+        }
+        self.emit(rule.name(), ts, guild_id, user_id, details);
+        reported
+    }
+
+    /// Record a post of fingerprint `hash` for `record_content`.
+    fn record_fingerprint(&self, guild_id: u64, user_id: u64, hash: u64, now_ts: f64) -> (usize, usize) {
+        let mut guild = GUILD_CONTENT.entry(guild_id).or_default();
+        let cutoff = now_ts - self.content_window_secs;
+        guild.tick += 1;
+        let tick = guild.tick;
+        let GuildContent { hashes, by_use, .. } = &mut *guild;
+        let entry = hashes.entry(hash).or_insert_with(|| ContentPosts {
+            posts: VecDeque::new(),
+            tick,
+        });
+        by_use.remove(&entry.tick);
+        by_use.insert(tick, hash);
+        entry.tick = tick;
+        while entry.posts.front().is_some_and(|&(ts, _)| ts <= cutoff) {
+            entry.posts.pop_front();
+        }
+        if entry.posts.len() == CONTENT_MAX_POSTS {
+            entry.posts.pop_front();
+        }
+        entry.posts.push_back((now_ts, user_id));
+        let result = (entry.posts.len(), distinct_senders(&entry.posts).len());
+
+        while hashes.len() > self.content_max_hashes {
+            let Some((_, oldest)) = by_use.pop_first() else {
+                break;
+            };
+            hashes.remove(&oldest);
+        }
+        result
+    }
+
+    /// Queue a decision event for `drain_events`, dropping the oldest when full.
+    fn emit(&self, rule: &'static str, ts: f64, guild_id: u64, user_id: u64, details: Vec<(&'static str, EventValue)>) {
+        if self.event_buffer_size == 0 {
+            return;
+        }
+        let mut log = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        if log.events.len() == self.event_buffer_size {
+            log.events.pop_front();
+            log.dropped += 1;
+        }
+        log.next_seq += 1;
+        let seq = log.next_seq;
+        log.events.push_back(TrackerEvent { seq, rule, ts, guild_id, user_id, details });
+    }
+
+    /// `now_ts` when given, otherwise the internal clock.
+    fn now(&self, now_ts: Option<f64>) -> f64 {
+        now_ts.unwrap_or_else(|| unix_now() + self.clock_offset.lock().map_or(0.0, |offset| *offset))
+    }
+
+    /// Validate a rate window and cap it at the chat history retention.
+    fn rate_window(&self, window_secs: f64) -> PyResult<f64> {
+        if window_secs.is_nan() || window_secs <= 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "window_secs must be positive",
+            ));
+        }
+        Ok(window_secs.min(self.history_retention_secs))
+    }
+
+    /// Prune a user's spam timestamps, record `now_ts`, and judge the window.
+    fn spam_step(&self, (guild_id, user_id): SpamKey, timestamps: &mut Vec<f64>, now_ts: f64) -> (bool, usize) {
+        let cutoff = now_ts - self.spam_window_secs;
+
+        // Remove old timestamps
+        timestamps.retain(|&ts| ts > cutoff);
+
+        // Add current timestamp
+        timestamps.push(now_ts);
+
+        let count = timestamps.len();
+        bump(CoreCounter::SpamChecks, 1);
+        let is_spam = count > self.spam_threshold
+            && self.fire(TrackerRule::Spam, now_ts, guild_id, user_id, vec![
+                ("count", EventValue::Int(count as u64)),
+                ("threshold", EventValue::Int(self.spam_threshold as u64)),
+                ("window_secs", EventValue::Float(self.spam_window_secs)),
+            ]);
+        (is_spam, count)
+    }
+
+    /// Shared chat-activity logic behind `record_chat_activity` and its variants.
+    fn chat_decision(
+        &self,
+        guild_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        now_ts: f64,
+        mentions_bot: bool,
+        is_reply_to_bot: bool,
+    ) -> ChatDecision {
+        let cleanup_cutoff = now_ts - self.history_retention_secs;
+        let active_cutoff = now_ts - self.chat_active_window_secs;
+        bump(CoreCounter::ChatMessages, 1);
+
+        if channel_id != 0 {
+            let mut channel = CHANNEL_ACTIVITY.entry((guild_id, channel_id)).or_default();
+            channel.push_back((now_ts, user_id));
+            prune_activity(&mut channel, active_cutoff);
+        }
+
+        // Get or create the activity deque for this guild
+        let mut entry = CHAT_ACTIVITY.entry(guild_id).or_default();
+
+        // Add current activity
+        entry.push_back((now_ts, user_id));
+
+        // Clean old entries
+        prune_activity(&mut entry, cleanup_cutoff);
+
+        // Count active messages and unique users in the active window
+        let mut active_count = 0;
+        let mut unique_users = std::collections::HashSet::new();
+
+        for &(ts, uid) in entry.iter() {
+            if ts >= active_cutoff {
+                active_count += 1;
+                unique_users.insert(uid);
+            }
+        }
+        drop(entry);
+
+        // Check thresholds
+        if active_count < self.chat_min_messages || unique_users.len() < self.chat_min_users {
+            return ChatDecision::Inactive;
+        }
+
+        // Direct address picks its own chance and (optionally) a shorter cooldown
+        let (chance, cooldown, hit) = if mentions_bot {
+            (self.mention_trigger_chance, self.addressed_cooldown_secs(), ChatDecision::Mention)
+        } else if is_reply_to_bot {
+            (self.reply_trigger_chance, self.addressed_cooldown_secs(), ChatDecision::Reply)
+        } else {
+            (self.chat_trigger_chance, self.chat_cooldown_secs, ChatDecision::Activity)
+        };
+
+        // Quiet hours silence unsolicited replies; direct address still works
+        if hit == ChatDecision::Activity && QUIET_HOURS.get(&guild_id).is_some_and(|quiet| quiet.contains(now_ts)) {
+            return ChatDecision::QuietHours;
+        }
+
+        // Check the channel cooldown, then the guild-wide cap for unsolicited replies
+        let channel_key = (guild_id, channel_id);
+        if let Some(last_trigger) = CHANNEL_COOLDOWNS.get(&channel_key) {
+            if (now_ts - *last_trigger) < cooldown {
+                bump(CoreCounter::ChatCooldownSuppressed, 1);
+                return ChatDecision::ChannelCooldown;
+            }
+        }
+        if let (ChatDecision::Activity, Some(guild_cooldown)) = (hit, self.guild_cooldown_secs) {
+            if let Some(last_trigger) = CHAT_COOLDOWNS.get(&guild_id) {
+                if (now_ts - *last_trigger) < guild_cooldown {
+                    bump(CoreCounter::ChatCooldownSuppressed, 1);
+                    return ChatDecision::GuildCooldown;
+                }
+            }
+        }
+
+        // Random chance to trigger: rolls are uniform in [0, 1), so a chance of
+        // 0.0 (or less) never fires and 1.0 (or more) always fires.
+        if chance >= 1.0 || self.roll() < chance {
+            CHANNEL_COOLDOWNS.insert(channel_key, now_ts);
+            CHAT_COOLDOWNS.insert(guild_id, now_ts);
+            bump(CoreCounter::ChatTriggers, 1);
+            self.emit("chat_trigger", now_ts, guild_id, user_id, vec![
+                ("reason", EventValue::Str(hit.as_str())),
+                ("channel_id", EventValue::Int(channel_id)),
+                ("active_messages", EventValue::Int(active_count as u64)),
+                ("active_users", EventValue::Int(unique_users.len() as u64)),
+                ("min_messages", EventValue::Int(self.chat_min_messages as u64)),
+                ("min_users", EventValue::Int(self.chat_min_users as u64)),
+                ("active_window_secs", EventValue::Float(self.chat_active_window_secs)),
+                ("chance", EventValue::Float(chance)),
+                ("cooldown_secs", EventValue::Float(cooldown)),
+            ]);
+            return hit;
+        }
+
+        ChatDecision::Chance
+    }
+
+    /// Violation score decayed from its last update to `now_ts`.
+    fn decayed_violation(&self, (score, updated_ts): (f64, f64), now_ts: f64) -> f64 {
+        let elapsed = (now_ts - updated_ts).max(0.0);
+        if self.violation_half_life_secs <= 0.0 {
+            return score;
+        }
+        score * 0.5f64.powf(elapsed / self.violation_half_life_secs)
+    }
+
+    /// Decay-mode spam score decayed from its last update to `now_ts`.
+    fn decayed_spam_score(&self, (score, updated_ts): (f64, f64), now_ts: f64) -> f64 {
+        let elapsed = (now_ts - updated_ts).max(0.0);
+        if self.spam_decay_secs <= 0.0 {
+            return 0.0;
+        }
+        score * (-elapsed / self.spam_decay_secs).exp()
+    }
+
+    /// Drop joins that fell out of the join window.
+    fn prune_joins(&self, joins: &mut VecDeque<JoinRecord>, now_ts: f64) {
+        let cutoff = now_ts - self.join_window_secs;
+        while let Some(&(ts, _, _)) = joins.front() {
+            if ts <= cutoff {
+                joins.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn addressed_cooldown_secs(&self) -> f64 {
+        self.mention_cooldown_secs.unwrap_or(self.chat_cooldown_secs)
+    }
+
+    /// Longest cooldown any trigger can be subject to.
+    fn longest_cooldown_secs(&self) -> f64 {
+        self.chat_cooldown_secs
+            .max(self.addressed_cooldown_secs())
+            .max(self.guild_cooldown_secs.unwrap_or(0.0))
+    }
+
+    fn roll(&self) -> f64 {
+        match self.rng.lock() {
+            Ok(mut rng) => rng.roll(),
+            Err(poisoned) => poisoned.into_inner().roll(),
+        }
+    }
+}
+
+/// Drop a user's spam records in one guild, or in every guild when `guild_id` is None.
+fn forget_spam_user(user_id: u64, guild_id: Option<u64>) {
+    match guild_id {
+        Some(gid) => {
+            SPAM_TIMESTAMPS.remove(&(gid, user_id));
+            SPAM_DECAY.remove(&(gid, user_id));
+            ATTACHMENTS.remove(&(gid, user_id));
+        }
+        None => {
+            SPAM_TIMESTAMPS.retain(|&(_, uid), _| uid != user_id);
+            SPAM_DECAY.retain(|&(_, uid), _| uid != user_id);
+            ATTACHMENTS.retain(|&(_, uid), _| uid != user_id);
+        }
+    }
+}
+
+/// Hooks one tracker map into the maintenance methods (`clear_users`,
+/// `prune_user_before`, `retain_guilds`, `clear_guild`, `clear_all`,
+/// `cleanup`). Each returns the records it removed: whole entries for keyed
+/// maps, single events for shared logs. Every map holding user or guild data
+/// belongs in `TRACKED_MAPS`.
+struct TrackedMap {
+    forget_users: fn(&HashSet<u64>) -> usize,
+    prune_user_before: fn(u64, f64) -> usize,
+    retain_guilds: fn(&HashSet<u64>) -> usize,
+    clear_guild: fn(u64) -> usize,
+    clear_all: fn(),
+    cleanup: fn(&ActivityTrackerRust, f64) -> usize,
+}
+
+/// A timestamped per-user record in a shared log.
+trait LogEvent {
+    fn ts(&self) -> f64;
+    fn user_id(&self) -> u64;
+}
+
+impl LogEvent for (f64, u64) {
+    fn ts(&self) -> f64 {
+        self.0
+    }
+
+    fn user_id(&self) -> u64 {
+        self.1
+    }
+}
+
+impl LogEvent for JoinRecord {
+    fn ts(&self) -> f64 {
+        self.0
+    }
+
+    fn user_id(&self) -> u64 {
+        self.1
+    }
+}
+
+/// Remove matching events from every log in `map`, dropping emptied logs.
+fn remove_events<K, T>(map: &DashMap<K, VecDeque<T>>, remove: impl Fn(&T) -> bool) -> usize
+where
+    K: Eq + Hash,
+{
+    let mut removed = 0;
+    map.retain(|_, events| {
+        let before = events.len();
+        events.retain(|event| !remove(event));
+        removed += before - events.len();
+        !events.is_empty()
+    });
+    removed
+}
+
+/// Remove matching posts from every guild's fingerprints, dropping emptied
+/// fingerprints and guilds.
+fn remove_content_posts(remove: impl Fn(&(f64, u64)) -> bool) -> usize {
+    let mut removed = 0;
+    GUILD_CONTENT.retain(|_, content| {
+        let GuildContent { hashes, by_use, .. } = content;
+        hashes.retain(|_, entry| {
+            let before = entry.posts.len();
+            entry.posts.retain(|post| !remove(post));
+            removed += before - entry.posts.len();
+            if entry.posts.is_empty() {
+                by_use.remove(&entry.tick);
+            }
+            !entry.posts.is_empty()
+        });
+        !hashes.is_empty()
+    });
+    removed
+}
+
+/// Guild 0 is the shared record for callers without a guild and is always kept.
+fn keeps_guild(guild_ids: &HashSet<u64>, guild_id: u64) -> bool {
+    guild_id == 0 || guild_ids.contains(&guild_id)
+}
+
+/// Every tracker map with user or guild data. Spam exemptions are
+/// configuration and deliberately excluded.
+static TRACKED_MAPS: &[TrackedMap] = &[
+    // SPAM_TIMESTAMPS
+    TrackedMap {
+        forget_users: |users| retain_counting(&SPAM_TIMESTAMPS, |(_, uid), _| !users.contains(uid)),
+        prune_user_before: |user_id, cutoff| {
+            let mut removed = 0;
+            SPAM_TIMESTAMPS.retain(|&(_, uid), timestamps| {
+                if uid == user_id {
+                    let before = timestamps.len();
+                    timestamps.retain(|&ts| ts >= cutoff);
+                    removed += before - timestamps.len();
+                }
+                !timestamps.is_empty()
+            });
+            removed
+        },
+        retain_guilds: |guilds| retain_counting(&SPAM_TIMESTAMPS, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&SPAM_TIMESTAMPS, |&(gid, _), _| gid != guild_id),
+        clear_all: || SPAM_TIMESTAMPS.clear(),
+        cleanup: |tracker, now_ts| {
+            let cutoff = now_ts - tracker.spam_window_secs;
+            retain_counting(&SPAM_TIMESTAMPS, |_, timestamps| {
+                timestamps.retain(|&ts| ts > cutoff);
+                !timestamps.is_empty()
+            })
+        },
+    },
+    // SPAM_DECAY
+    TrackedMap {
+        forget_users: |users| retain_counting(&SPAM_DECAY, |(_, uid), _| !users.contains(uid)),
+        prune_user_before: |user_id, cutoff| {
+            retain_counting(&SPAM_DECAY, |&(_, uid), &mut (_, updated)| uid != user_id || updated >= cutoff)
+        },
+        retain_guilds: |guilds| retain_counting(&SPAM_DECAY, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&SPAM_DECAY, |&(gid, _), _| gid != guild_id),
+        clear_all: || SPAM_DECAY.clear(),
+        cleanup: |tracker, now_ts| {
+            retain_counting(&SPAM_DECAY, |_, entry| tracker.decayed_spam_score(*entry, now_ts) >= SCORE_EPSILON)
+        },
+    },
+    // ATTACHMENTS
+    TrackedMap {
+        forget_users: |users| retain_counting(&ATTACHMENTS, |(_, uid), _| !users.contains(uid)),
+        prune_user_before: |user_id, cutoff| {
+            let mut removed = 0;
+            ATTACHMENTS.retain(|&(_, uid), attachments| {
+                if uid == user_id {
+                    let before = attachments.len();
+                    attachments.retain(|&(ts, _)| ts >= cutoff);
+                    removed += before - attachments.len();
+                }
+                !attachments.is_empty()
+            });
+            removed
+        },
+        retain_guilds: |guilds| retain_counting(&ATTACHMENTS, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&ATTACHMENTS, |&(gid, _), _| gid != guild_id),
+        clear_all: || ATTACHMENTS.clear(),
+        cleanup: |tracker, now_ts| {
+            let cutoff = now_ts - tracker.attachment_window_secs;
+            retain_counting(&ATTACHMENTS, |_, attachments| {
+                attachments.retain(|&(ts, _)| ts > cutoff);
+                !attachments.is_empty()
+            })
+        },
+    },
+    // VIOLATIONS
+    TrackedMap {
+        forget_users: |users| retain_counting(&VIOLATIONS, |uid, _| !users.contains(uid)),
+        prune_user_before: |user_id, cutoff| {
+            retain_counting(&VIOLATIONS, |&uid, &mut (_, updated)| uid != user_id || updated >= cutoff)
+        },
+        retain_guilds: |_| 0,
+        clear_guild: |_| 0,
+        clear_all: || VIOLATIONS.clear(),
+        cleanup: |tracker, now_ts| {
+            retain_counting(&VIOLATIONS, |_, entry| tracker.decayed_violation(*entry, now_ts) >= SCORE_EPSILON)
+        },
+    },
+    // CHAT_ACTIVITY
+    TrackedMap {
+        forget_users: |users| remove_events(&CHAT_ACTIVITY, |event| users.contains(&event.user_id())),
+        prune_user_before: |user_id, cutoff| {
+            remove_events(&CHAT_ACTIVITY, |event| event.user_id() == user_id && event.ts() < cutoff)
+        },
+        retain_guilds: |guilds| retain_counting(&CHAT_ACTIVITY, |&gid, _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| CHAT_ACTIVITY.remove(&guild_id).is_some() as usize,
+        clear_all: || CHAT_ACTIVITY.clear(),
+        cleanup: |tracker, now_ts| {
+            let cutoff = now_ts - tracker.history_retention_secs;
+            retain_counting(&CHAT_ACTIVITY, |_, activity| {
+                activity.retain(|&(ts, _)| ts >= cutoff);
+                !activity.is_empty()
+            })
+        },
+    },
+    // CHANNEL_ACTIVITY
+    TrackedMap {
+        forget_users: |users| remove_events(&CHANNEL_ACTIVITY, |event| users.contains(&event.user_id())),
+        prune_user_before: |user_id, cutoff| {
+            remove_events(&CHANNEL_ACTIVITY, |event| event.user_id() == user_id && event.ts() < cutoff)
+        },
+        retain_guilds: |guilds| retain_counting(&CHANNEL_ACTIVITY, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&CHANNEL_ACTIVITY, |&(gid, _), _| gid != guild_id),
+        clear_all: || CHANNEL_ACTIVITY.clear(),
+        cleanup: |tracker, now_ts| {
+            let cutoff = now_ts - tracker.chat_active_window_secs;
+            retain_counting(&CHANNEL_ACTIVITY, |_, activity| {
+                prune_activity(activity, cutoff);
+                !activity.is_empty()
+            })
+        },
+    },
+    // SLOWMODE_LEVELS
+    TrackedMap {
+        forget_users: |_| 0,
+        prune_user_before: |_, _| 0,
+        retain_guilds: |guilds| retain_counting(&SLOWMODE_LEVELS, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&SLOWMODE_LEVELS, |&(gid, _), _| gid != guild_id),
+        clear_all: || SLOWMODE_LEVELS.clear(),
+        // After CHANNEL_ACTIVITY, so channels that just went quiet lose their level
+        cleanup: |_, _| retain_counting(&SLOWMODE_LEVELS, |key, _| CHANNEL_ACTIVITY.contains_key(key)),
+    },
+    // CHAT_COOLDOWNS
+    TrackedMap {
+        forget_users: |_| 0,
+        prune_user_before: |_, _| 0,
+        retain_guilds: |guilds| retain_counting(&CHAT_COOLDOWNS, |&gid, _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| CHAT_COOLDOWNS.remove(&guild_id).is_some() as usize,
+        clear_all: || CHAT_COOLDOWNS.clear(),
+        cleanup: |tracker, now_ts| {
+            let cooldown = tracker.longest_cooldown_secs();
+            retain_counting(&CHAT_COOLDOWNS, |_, last| now_ts - *last < cooldown)
+        },
+    },
+    // CHANNEL_COOLDOWNS
+    TrackedMap {
+        forget_users: |_| 0,
+        prune_user_before: |_, _| 0,
+        retain_guilds: |guilds| retain_counting(&CHANNEL_COOLDOWNS, |&(gid, _), _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| retain_counting(&CHANNEL_COOLDOWNS, |&(gid, _), _| gid != guild_id),
+        clear_all: || CHANNEL_COOLDOWNS.clear(),
+        cleanup: |tracker, now_ts| {
+            let cooldown = tracker.longest_cooldown_secs();
+            retain_counting(&CHANNEL_COOLDOWNS, |_, last| now_ts - *last < cooldown)
+        },
+    },
+    // GUILD_JOINS
+    TrackedMap {
+        forget_users: |users| remove_events(&GUILD_JOINS, |event| users.contains(&event.user_id())),
+        prune_user_before: |user_id, cutoff| {
+            remove_events(&GUILD_JOINS, |event| event.user_id() == user_id && event.ts() < cutoff)
+        },
+        retain_guilds: |guilds| retain_counting(&GUILD_JOINS, |&gid, _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| GUILD_JOINS.remove(&guild_id).is_some() as usize,
+        clear_all: || GUILD_JOINS.clear(),
+        cleanup: |tracker, now_ts| {
+            retain_counting(&GUILD_JOINS, |_, joins| {
+                tracker.prune_joins(joins, now_ts);
+                !joins.is_empty()
+            })
+        },
+    },
+    // QUIET_HOURS
+    TrackedMap {
+        forget_users: |_| 0,
+        prune_user_before: |_, _| 0,
+        retain_guilds: |guilds| retain_counting(&QUIET_HOURS, |&gid, _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| QUIET_HOURS.remove(&guild_id).is_some() as usize,
+        clear_all: || QUIET_HOURS.clear(),
+        cleanup: |_, _| 0,
+    },
+    // GUILD_CONTENT
+    TrackedMap {
+        forget_users: |users| remove_content_posts(|post| users.contains(&post.user_id())),
+        prune_user_before: |user_id, cutoff| {
+            remove_content_posts(|post| post.user_id() == user_id && post.ts() < cutoff)
+        },
+        retain_guilds: |guilds| retain_counting(&GUILD_CONTENT, |&gid, _| keeps_guild(guilds, gid)),
+        clear_guild: |guild_id| GUILD_CONTENT.remove(&guild_id).is_some() as usize,
+        clear_all: || GUILD_CONTENT.clear(),
+        cleanup: |tracker, now_ts| {
+            let cutoff = now_ts - tracker.content_window_secs;
+            retain_counting(&GUILD_CONTENT, |_, content| {
+                prune_content(content, cutoff);
+                !content.hashes.is_empty()
+            })
+        },
+    },
+];
+
+/// Pop activity entries older than `cutoff` from the front of a deque.
+fn prune_activity(activity: &mut VecDeque<(f64, u64)>, cutoff: f64) {
+    while let Some(&(ts, _)) = activity.front() {
+        if ts < cutoff {
+            activity.pop_front();
+        } else {
+            break;
+        }
+    }
+}
+
+/// `DashMap::retain` that reports how many entries were removed.
+fn retain_counting<K, V, F>(map: &DashMap<K, V>, mut keep: F) -> usize
+where
+    K: Eq + std::hash::Hash,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    let mut removed = 0;
+    map.retain(|key, value| {
+        let kept = keep(key, value);
+        if !kept {
+            removed += 1;
+        }
+        kept
+    });
+    removed
+}
+
+/// Pseudo-random generator using two integer seeds.
+fn rand_from_pair(a: u64, b: u64) -> f64 {
+    let seed = a ^ b.rotate_left(13) ^ 0x9e37_79b9_7f4a_7c15;
+    let mixed = seed
+        .wrapping_mul(0xff51_afd7_ed55_8ccd)
+        .wrapping_add(seed.rotate_right(17));
+    (mixed as f64) / (u64::MAX as f64)
+}
+
+// ============================================
+// Response selection
+// ============================================
+
+/// Reject empty weight lists and weights that are not finite and positive.
+fn validate_weights(weights: &[f64]) -> PyResult<()> {
+    if weights.is_empty() {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "weights must not be empty",
+        ));
+    }
+    if let Some(bad) = weights.iter().find(|w| !(w.is_finite() && **w > 0.0)) {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "weights must be finite and positive, got {}",
+            bad
+        )));
+    }
+    Ok(())
+}
+
+/// Weighted index draw; `weights` must already be validated.
+fn weighted_index<R: Rng>(rng: &mut R, weights: &[f64]) -> usize {
+    let total: f64 = weights.iter().sum();
+    let mut target = rng.gen::<f64>() * total;
+    for (i, &w) in weights.iter().enumerate() {
+        if target < w {
+            return i;
+        }
+        target -= w;
+    }
+    // Float rounding can leave target a hair above the last weight
+    weights.len() - 1
+}
+
+/// Pick an index with probability proportional to its weight.
+/// Pass `seed` for a reproducible draw.
+#[pyfunction]
+#[pyo3(signature = (weights, seed = None))]
+fn weighted_choice(weights: Vec<f64>, seed: Option<u64>) -> PyResult<usize> {
+    validate_weights(&weights)?;
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    };
+    Ok(weighted_index(&mut rng, &weights))
+}
+
+/// Weighted picker over tagged responses (canned replies, reaction GIFs).
+#[pyclass]
+struct ResponsePicker {
+    weights: Vec<f64>,
+    tags: Vec<String>,
+    rng: Mutex<SmallRng>,
+}
+
+#[pymethods]
+impl ResponsePicker {
+    /// `entries` are (weight, tag) pairs; weights must be finite and positive.
+    #[new]
+    #[pyo3(signature = (entries, seed = None))]
+    fn new(entries: Vec<(f64, String)>, seed: Option<u64>) -> PyResult<Self> {
+        let (weights, tags): (Vec<f64>, Vec<String>) = entries.into_iter().unzip();
+        validate_weights(&weights)?;
+        let rng = match seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        Ok(ResponsePicker {
+            weights,
+            tags,
+            rng: Mutex::new(rng),
+        })
+    }
+
+    /// Pick a tag by weight.
+    fn pick(&self) -> String {
+        let index = weighted_index(&mut *self.rng(), &self.weights);
+        self.tags[index].clone()
+    }
+
+    /// Pick a tag by weight, skipping `recent_tags` (e.g. the last response).
+    /// Falls back to `pick()` when every entry is excluded.
+    fn pick_excluding(&self, recent_tags: Vec<String>) -> String {
+        let allowed: Vec<usize> = (0..self.tags.len())
+            .filter(|&i| !recent_tags.contains(&self.tags[i]))
+            .collect();
+        if allowed.is_empty() {
+            return self.pick();
+        }
+        let weights: Vec<f64> = allowed.iter().map(|&i| self.weights[i]).collect();
+        let index = allowed[weighted_index(&mut *self.rng(), &weights)];
+        self.tags[index].clone()
+    }
+
+    fn __len__(&self) -> usize {
+        self.tags.len()
+    }
+}
+
+impl ResponsePicker {
+    fn rng(&self) -> std::sync::MutexGuard<'_, SmallRng> {
+        self.rng.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ============================================
+// Economy helpers (hot paths exposed to Python)
+// ============================================
+
+#[pyclass]
+struct EconomyEngine {
+    elite_threshold: f64,
+    middle_threshold: f64,
+    min_wage: f64,
+    income_tax_rate: f64,
+    wealth_tax_rate: f64,
+}
+
+#[pymethods]
+impl EconomyEngine {
+    #[new]
+    #[pyo3(signature = (
+        elite_threshold = 100_000.0,
+        middle_threshold = 10_000.0,
+        min_wage = 400.0,
+        income_tax_rate = 0.15,
+        wealth_tax_rate = 0.0
+    ))]
+    fn new(
+        elite_threshold: f64,
+        middle_threshold: f64,
+        min_wage: f64,
+        income_tax_rate: f64,
+        wealth_tax_rate: f64,
+    ) -> Self {
+        EconomyEngine {
+            elite_threshold,
+            middle_threshold,
+            min_wage,
+            income_tax_rate,
+            wealth_tax_rate,
+        }
+    }
+
+    /// Classify a citizen's class tier based on balance thresholds.
+    fn classify(&self, balance: f64) -> String {
+        if balance >= self.elite_threshold {
+            "elite".to_string()
+        } else if balance >= self.middle_threshold {
+            "middle".to_string()
+        } else {
+            "working".to_string()
+        }
+    }
+
+    /// Calculate a single work action payout with XP bonus and variance.
+    fn work_payout(&self, annual_salary: f64, work_xp: i64, seed_a: u64, seed_b: u64) -> f64 {
+        let base_pay = annual_salary.max(self.min_wage) / 365.0 * 5.0;
+        let xp_bonus = 1.0 + (work_xp as f64 / 1000.0);
+        let variance = 0.8 + rand_from_pair(seed_a, seed_b) * 0.4;
+        base_pay * xp_bonus * variance
+    }
+
+    /// Compute income tax on a given income value.
+    fn income_tax(&self, income: f64) -> f64 {
+        if income <= 0.0 || self.income_tax_rate <= 0.0 {
+            0.0
+        } else {
+            income * self.income_tax_rate
+        }
+    }
+
+    /// Compute wealth tax over a threshold (default 50k in Python caller).
+    fn wealth_tax(&self, balance: f64, threshold: f64) -> f64 {
+        if balance <= threshold || self.wealth_tax_rate <= 0.0 {
+            0.0
+        } else {
+            (balance - threshold) * self.wealth_tax_rate
+        }
+    }
+}
+
+// ============================================
+// Database Writer with async queue
+// ============================================
+
+/// One voice transcription, as queued, journaled, and written natively.
+#[pyclass]
+#[derive(Clone, Serialize, Deserialize)]
+struct TranscriptionRecord {
+    #[pyo3(get, set)]
+    guild_id: u64,
+    #[pyo3(get, set)]
+    channel_id: u64,
+    #[pyo3(get, set)]
+    user_id: u64,
+    #[pyo3(get, set)]
+    content: String,
+    #[pyo3(get, set)]
+    username: String,
+    #[pyo3(get, set)]
+    duration_secs: f64,
+    /// Unix timestamp of the utterance.
+    #[pyo3(get, set)]
+    message_ts: Option<f64>,
+    #[pyo3(get, set)]
+    language: Option<String>,
+    /// Transcriber confidence in [0, 1].
+    #[pyo3(get, set)]
+    confidence: Option<f64>,
+}
+
+#[pymethods]
+impl TranscriptionRecord {
+    #[new]
+    #[pyo3(signature = (
+        guild_id,
+        channel_id,
+        user_id,
+        content,
+        username = String::new(),
+        duration_secs = 0.0,
+        message_ts = None,
+        language = None,
+        confidence = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        guild_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        content: String,
+        username: String,
+        duration_secs: f64,
+        message_ts: Option<f64>,
+        language: Option<String>,
+        confidence: Option<f64>,
+    ) -> PyResult<Self> {
+        let record = TranscriptionRecord {
+            guild_id,
+            channel_id,
+            user_id,
+            content,
+            username,
+            duration_secs,
+            message_ts,
+            language,
+            confidence,
+        };
+        record.validate()?;
+        Ok(record)
+    }
+
+    /// Parse a record from its `to_json()` form.
+    #[staticmethod]
+    fn from_json(json: &str) -> PyResult<Self> {
+        let record: TranscriptionRecord = serde_json::from_str(json)
+            .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("invalid transcription record: {}", e)))?;
+        record.validate()?;
+        Ok(record)
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(self).map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))
+    }
+
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("guild_id", self.guild_id)?;
+        dict.set_item("channel_id", self.channel_id)?;
+        dict.set_item("user_id", self.user_id)?;
+        dict.set_item("content", &self.content)?;
+        dict.set_item("username", &self.username)?;
+        dict.set_item("duration_secs", self.duration_secs)?;
+        dict.set_item("message_ts", self.message_ts)?;
+        dict.set_item("language", &self.language)?;
+        dict.set_item("confidence", self.confidence)?;
+        Ok(dict)
+    }
+}
+
+impl TranscriptionRecord {
+    fn validate(&self) -> PyResult<()> {
+        if let Some(confidence) = self.confidence {
+            if !(0.0..=1.0).contains(&confidence) {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "confidence must be between 0 and 1",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keyword arguments for `transcription_handler`: only the optional
+    /// fields that are set, so handlers written for the six positional
+    /// arguments keep working.
+    fn extra_kwargs<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let kwargs = PyDict::new(py);
+        if let Some(message_ts) = self.message_ts {
+            kwargs.set_item("message_ts", message_ts)?;
+        }
+        if let Some(language) = &self.language {
+            kwargs.set_item("language", language)?;
+        }
+        if let Some(confidence) = self.confidence {
+            kwargs.set_item("confidence", confidence)?;
+        }
+        Ok(kwargs)
+    }
+}
+
+/// A database write operation to be queued
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum DbWriteOp {
+    Transcription(TranscriptionRecord),
+    Generic {
+        table: String,
+        data: String, // JSON serialized
+    },
+    Delete {
+        table: String,
+        filter: JsonMap,
+    },
+    Update {
+        table: String,
+        set: JsonMap,
+        filter: JsonMap,
+    },
+    Upsert {
+        table: String,
+        data: JsonMap,
+        conflict_keys: Vec<String>,
+    },
+    Shutdown,
+}
+
+type JsonMap = serde_json::Map<String, serde_json::Value>;
+
+impl DbWriteOp {
+    fn kind(&self) -> &'static str {
+        match self {
+            DbWriteOp::Transcription(_) => "transcription",
+            DbWriteOp::Generic { .. } => "generic",
+            DbWriteOp::Delete { .. } => "delete",
+            DbWriteOp::Update { .. } => "update",
+            DbWriteOp::Upsert { .. } => "upsert",
+            DbWriteOp::Shutdown => "shutdown",
+        }
+    }
+
+    fn table(&self) -> &str {
+        match self {
+            DbWriteOp::Transcription(_) => "transcriptions",
+            DbWriteOp::Generic { table, .. }
+            | DbWriteOp::Delete { table, .. }
+            | DbWriteOp::Update { table, .. }
+            | DbWriteOp::Upsert { table, .. } => table,
+            DbWriteOp::Shutdown => "",
+        }
+    }
+
+    /// Guild the op concerns, if it says (a `guild_id` field in its payload).
+    fn guild_id(&self) -> Option<u64> {
+        match self {
+            DbWriteOp::Transcription(record) => Some(record.guild_id),
+            DbWriteOp::Generic { data, .. } => serde_json::from_str::<serde_json::Value>(data)
+                .ok()
+                .and_then(|value| value.get("guild_id")?.as_u64()),
+            DbWriteOp::Delete { filter, .. } | DbWriteOp::Update { filter, .. } => {
+                filter.get("guild_id").and_then(|v| v.as_u64())
+            }
+            DbWriteOp::Upsert { data, .. } => data.get("guild_id").and_then(|v| v.as_u64()),
+            DbWriteOp::Shutdown => None,
+        }
+    }
+
+    /// Category for `pending_writes_detailed()`: `"transcription"`, or the
+    /// kind and table such as `"generic:guild_settings"`.
+    fn pending_key(&self) -> String {
+        match self {
+            DbWriteOp::Transcription(_) | DbWriteOp::Shutdown => self.kind().to_string(),
+            _ => format!("{}:{}", self.kind(), self.table()),
+        }
+    }
+
+    /// Rough payload size in bytes.
+    fn size(&self) -> usize {
+        let json_len = |map: &JsonMap| serde_json::to_string(map).map(|s| s.len()).unwrap_or(0);
+        match self {
+            DbWriteOp::Transcription(record) => {
+                record.content.len() + record.username.len() + record.language.as_ref().map_or(0, |l| l.len())
+            }
+            DbWriteOp::Generic { data, .. } => data.len(),
+            DbWriteOp::Delete { filter, .. } => json_len(filter),
+            DbWriteOp::Update { set, filter, .. } => json_len(set) + json_len(filter),
+            DbWriteOp::Upsert { data, .. } => json_len(data),
+            DbWriteOp::Shutdown => 0,
+        }
+    }
+}
+
+/// An op waiting in the write queue, stamped for time-in-queue metrics.
+struct QueuedOp {
+    op: DbWriteOp,
+    priority: i32,
+    queued_at: Instant,
+    /// Journal sequence number, when a journal is in use.
+    seq: Option<u64>,
+}
+
+/// Queued ops grouped by priority; FIFO within a level.
+#[derive(Default)]
+struct PriorityOps {
+    levels: BTreeMap<i32, VecDeque<QueuedOp>>,
+    len: usize,
+}
+
+impl PriorityOps {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, queued: QueuedOp) {
+        self.levels.entry(queued.priority).or_default().push_back(queued);
+        self.len += 1;
+    }
+
+    /// Oldest op of the highest priority.
+    fn pop_next(&mut self) -> Option<QueuedOp> {
+        let mut level = self.levels.last_entry()?;
+        let queued = level.get_mut().pop_front();
+        if level.get().is_empty() {
+            level.remove();
+        }
+        self.len -= 1;
+        queued
+    }
+
+    /// Lowest priority with anything queued.
+    fn least_priority(&self) -> Option<i32> {
+        self.levels.keys().next().copied()
+    }
+
+    /// Oldest op of the lowest priority, i.e. the one to sacrifice first.
+    fn pop_least(&mut self) -> Option<QueuedOp> {
+        let mut level = self.levels.first_entry()?;
+        let queued = level.get_mut().pop_front();
+        if level.get().is_empty() {
+            level.remove();
+        }
+        self.len -= 1;
+        queued
+    }
+
+    /// Remove and return everything queued.
+    fn clear(&mut self) -> Vec<QueuedOp> {
+        self.len = 0;
+        std::mem::take(&mut self.levels).into_values().flatten().collect()
+    }
+}
+
+/// What `queue_*` does when a bounded write queue is full.
+#[derive(Clone, Copy)]
+enum QueuePolicy {
+    /// Wait for room, raising `TimeoutError` after `block_timeout_secs`.
+    Block,
+    /// Discard the oldest queued write to make room.
+    DropOldest,
+    /// Raise `RuntimeError` immediately.
+    Error,
+}
+
+impl QueuePolicy {
+    fn parse(policy: &str) -> PyResult<Self> {
+        match policy {
+            "block" => Ok(QueuePolicy::Block),
+            "drop_oldest" => Ok(QueuePolicy::DropOldest),
+            "error" => Ok(QueuePolicy::Error),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown queue policy '{}' (expected 'block', 'drop_oldest', or 'error')",
+                policy
+            ))),
+        }
+    }
+}
+
+/// Optionally bounded FIFO between the Python side and the worker thread.
+struct WriteQueue {
+    ops: Mutex<PriorityOps>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    block_timeout: Duration,
+    /// Set by `close()`; only changed while holding `ops`.
+    closed: AtomicBool,
+}
+
+impl WriteQueue {
+    /// Enqueue a write, applying the full-queue policy. Rejected and evicted
+    /// writes are counted in `shared.dropped_count`; `drop_oldest` evicts
+    /// the oldest op of the lowest priority level, or drops the new write
+    /// when everything queued has a higher priority.
+    fn push(&self, op: DbWriteOp, priority: i32, shared: &WriterShared) -> PyResult<()> {
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(writer_closed_error());
+        }
+        let mut evict = false;
+        if let Some(capacity) = self.capacity {
+            if ops.len() >= capacity {
+                match self.policy {
+                    QueuePolicy::Block => {
+                        // A timeout too long for an `Instant` waits for room indefinitely
+                        let deadline = Instant::now().checked_add(self.block_timeout);
+                        while ops.len() >= capacity {
+                            ops = match deadline {
+                                None => self.not_full.wait(ops).unwrap_or_else(PoisonError::into_inner),
+                                Some(deadline) => {
+                                    let remaining = deadline.saturating_duration_since(Instant::now());
+                                    if remaining.is_zero() {
+                                        shared.dropped_count.fetch_add(1, Ordering::Relaxed);
+                                        bump(CoreCounter::DbOpsDropped, 1);
+                                        return Err(PyErr::new::<pyo3::exceptions::PyTimeoutError, _>(format!(
+                                            "Write queue still full (capacity {}) after {:.1}s",
+                                            capacity,
+                                            self.block_timeout.as_secs_f64()
+                                        )));
+                                    }
+                                    self.not_full
+                                        .wait_timeout(ops, remaining)
+                                        .unwrap_or_else(PoisonError::into_inner)
+                                        .0
+                                }
+                            };
+                            if self.closed.load(Ordering::SeqCst) {
+                                return Err(writer_closed_error());
+                            }
+                        }
+                    }
+                    QueuePolicy::DropOldest => {
+                        // Never evict a write that outranks this one; drop this one instead
+                        if ops.least_priority().is_some_and(|least| least > priority) {
+                            shared.dropped_count.fetch_add(1, Ordering::Relaxed);
+                            bump(CoreCounter::DbOpsDropped, 1);
+                            return Ok(());
+                        }
+                        evict = true;
+                    }
+                    QueuePolicy::Error => {
+                        shared.dropped_count.fetch_add(1, Ordering::Relaxed);
+                        bump(CoreCounter::DbOpsDropped, 1);
+                        return Err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
+                            "Write queue full (capacity {})",
+                            capacity
+                        )));
+                    }
+                }
+            }
+        }
+
+        // Journal before accepting, so an acked write survives a crash
+        let seq = match &shared.journal {
+            Some(journal) => Some(journal.append(&op, priority).map_err(|e| {
+                PyErr::new::<pyo3::exceptions::PyIOError, _>(format!("Failed to journal write: {}", e))
+            })?),
+            None => None,
+        };
+        shared.start(&op);
+        let evicted = if evict { ops.pop_least() } else { None };
+
+        ops.push(QueuedOp { op, priority, queued_at: Instant::now(), seq });
+        shared.total_queued.fetch_add(1, Ordering::Relaxed);
+        bump(CoreCounter::DbOpsQueued, 1);
+        if !evict {
+            let depth = shared.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+            shared.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+        }
+        self.not_empty.notify_one();
+        // A journal error is reported through `on_error`, which takes the GIL;
+        // never do that while holding the queue lock `close()` takes under the GIL
+        drop(ops);
+        if let Some(evicted) = evicted {
+            shared.dropped_count.fetch_add(1, Ordering::Relaxed);
+            bump(CoreCounter::DbOpsDropped, 1);
+            shared.consume_journal(evicted.seq.into_iter().collect());
+            shared.finish([evicted.op.pending_key()]);
+        }
+        Ok(())
+    }
+
+    /// Stop accepting writes and queue a `Shutdown` behind whatever is left
+    /// (or in place of it when `drain` is false, counting those as dropped;
+    /// they stay in the journal, if any, to be replayed next run).
+    /// Returns false if the queue was already closed.
+    fn close(&self, drain: bool, shared: &WriterShared) -> bool {
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        if !drain && !ops.is_empty() {
+            let discarded = ops.clear();
+            shared.queue_depth.fetch_sub(discarded.len(), Ordering::Relaxed);
+            shared.dropped_count.fetch_add(discarded.len(), Ordering::Relaxed);
+            bump(CoreCounter::DbOpsDropped, discarded.len() as u64);
+            shared.finish(discarded.iter().map(|queued| queued.op.pending_key()));
+        }
+        // Lowest priority so it lands behind everything still queued
+        ops.push(QueuedOp {
+            op: DbWriteOp::Shutdown,
+            priority: i32::MIN,
+            queued_at: Instant::now(),
+            seq: None,
+        });
+        self.not_empty.notify_one();
+        // Wake blocked producers so they see the queue is closed
+        self.not_full.notify_all();
+        true
+    }
+
+    /// Queue an op recovered from the journal; bypasses capacity and policy.
+    fn push_replayed(&self, queued: QueuedOp, shared: &WriterShared) {
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        shared.start(&queued.op);
+        shared.total_queued.fetch_add(1, Ordering::Relaxed);
+        bump(CoreCounter::DbOpsQueued, 1);
+        let depth = shared.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+        shared.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+        ops.push(queued);
+        self.not_empty.notify_one();
+    }
+
+    /// Sleep for up to `timeout`, waking early if the queue gets closed.
+    /// Returns whether it is closed.
+    fn wait_closed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            ops = self
+                .not_empty
+                .wait_timeout(ops, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Take the oldest write, waiting until `deadline` (forever if `None`).
+    /// Returns `None` only when the deadline passes with the queue empty.
+    fn pop(&self, deadline: Option<Instant>, shared: &WriterShared) -> Option<QueuedOp> {
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(queued) = ops.pop_next() {
+                if !matches!(queued.op, DbWriteOp::Shutdown) {
+                    shared.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                }
+                self.not_full.notify_one();
+                return Some(queued);
+            }
+            ops = match deadline {
+                None => self.not_empty.wait(ops).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return None;
+                    }
+                    self.not_empty
+                        .wait_timeout(ops, remaining)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+}
+
+/// Schema for the native SQLite path; matches `db/transcriptions.py` plus the
+/// `TRANSCRIPTION_EXTRA_COLUMNS`, which `open_sqlite` adds to older tables.
+#[cfg(feature = "sqlite")]
+const TRANSCRIPTIONS_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS transcriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    username TEXT,
+    content TEXT NOT NULL,
+    duration_secs REAL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    message_ts REAL,
+    language TEXT,
+    confidence REAL
+);
+CREATE INDEX IF NOT EXISTS idx_transcriptions_guild ON transcriptions(guild_id);
+CREATE INDEX IF NOT EXISTS idx_transcriptions_user ON transcriptions(user_id);
+CREATE INDEX IF NOT EXISTS idx_transcriptions_created ON transcriptions(created_at);
+";
+
+#[cfg(feature = "sqlite")]
+const TRANSCRIPTION_EXTRA_COLUMNS: &[(&str, &str)] =
+    &[("message_ts", "REAL"), ("language", "TEXT"), ("confidence", "REAL")];
+
+/// Async database writer that queues writes to a background thread.
+/// This prevents database writes from blocking the Python async loop.
+///
+/// Writes are handed to Python callables on the worker thread:
+/// `transcription_handler(guild_id, channel_id, user_id, content, username, duration_secs)`
+/// and `generic_handler(table, data)` with `data` already parsed from JSON.
+/// With `db_path`, transcriptions are instead inserted natively into that
+/// SQLite database (WAL mode) without taking the GIL; generic writes still
+/// go to the Python handler.
+/// Failures are reported to stderr, counted in `failed_writes()`, and the
+/// latest is kept in `last_error()`; they never stop the worker.
+///
+/// The worker drains the queue in batches of up to `max_batch` ops, waiting
+/// `linger_secs` after the first op for more to arrive. Each batch is one
+/// SQLite transaction and one GIL acquisition.
+///
+/// The queue is unbounded unless `capacity` is given; production use should
+/// set one so a slow disk cannot grow memory without limit. When full,
+/// `policy` picks between `"block"` (wait up to `block_timeout_secs`, then
+/// `TimeoutError`), `"drop_oldest"`, and `"error"` (`RuntimeError`). Writes
+/// refused or evicted this way are counted in `dropped_count()`.
+///
+/// A failed op is retried up to `max_attempts` times in total, backing off
+/// exponentially from `retry_backoff_secs` with jitter. Ops that still fail
+/// land in a dead-letter list readable through `failed_ops()`.
+///
+/// `workers` threads share the load, each with its own queue (`capacity`
+/// applies per worker) and SQLite connection. Ops are routed by hashing
+/// (table, guild_id), so writes for the same key keep their order.
+///
+/// With `journal_path`, every accepted op is appended to a JSON-lines journal
+/// before `queue_*` returns and marked done once it commits (or is dropped or
+/// dead-lettered). Ops left unfinished by a crash are replayed on the next
+/// construction; see `replayed_count()`.
+///
+/// Generic writes go to the handler registered for their table with
+/// `register_table_handler()`, falling back to `generic_handler` unless
+/// `strict_tables` is set, in which case unregistered tables fail.
+///
+/// With `dedup_window_secs`, a transcription with the same (guild, user,
+/// content) as one queued within the window is dropped and counted in
+/// `deduped_count()`; generic writes dedupe only when given a `dedup_key`.
+///
+/// Each final failure is reported once: to `on_error(error, summary)` when
+/// given (summary is a dict with `op`, `table`, `guild_id`, and `size`, or
+/// None for errors not tied to an op), otherwise to stderr. The last few
+/// error strings are always kept for `recent_errors()`.
+#[pyclass]
+struct DatabaseWriter {
+    /// One queue per worker thread.
+    queues: Vec<Arc<WriteQueue>>,
+    shared: Arc<WriterShared>,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Ops recovered from the journal at startup.
+    replayed: usize,
+    /// Unreadable journal records skipped at startup.
+    journal_skipped: usize,
+    table_handlers: TableHandlers,
+}
+
+/// State shared between the writer handle and its worker thread.
+struct WriterShared {
+    pending_count: AtomicUsize,
+    /// `pending_count` split by `DbWriteOp::pending_key()`.
+    pending_by_key: DashMap<String, AtomicUsize>,
+    /// Paired with `drained`; held only around waits and notifications.
+    drain_lock: Mutex<()>,
+    /// Signalled whenever pending reaches zero or the worker exits.
+    drained: Condvar,
+    workers: usize,
+    workers_alive: AtomicUsize,
+    failed_count: AtomicUsize,
+    last_error: Mutex<Option<String>>,
+    batches_committed: AtomicUsize,
+    batched_ops: AtomicUsize,
+    dropped_count: AtomicUsize,
+    dead_letters: Mutex<Vec<DeadLetter>>,
+    recent_errors: Mutex<VecDeque<String>>,
+    on_error: Option<Py<PyAny>>,
+    total_queued: AtomicUsize,
+    total_committed: AtomicUsize,
+    /// Ops sitting in any worker's queue.
+    queue_depth: AtomicUsize,
+    /// Windowed stats, cleared by `reset_metrics()`.
+    max_queue_depth: AtomicUsize,
+    queue_latencies: Mutex<VecDeque<f64>>,
+    journal: Option<Journal>,
+    dedup: Option<DedupWindow>,
+    deduped_count: AtomicUsize,
+}
+
+/// How many time-in-queue samples feed the average and p95.
+const LATENCY_SAMPLE_LIMIT: usize = 1024;
+
+/// How many error strings `recent_errors()` keeps.
+const RECENT_ERRORS_LIMIT: usize = 50;
+
+/// Most keys the dedup window remembers; the oldest go first past this.
+const DEDUP_MAX_KEYS: usize = 10_000;
+
+/// Hashes of recently queued ops, for dropping exact repeats.
+struct DedupWindow {
+    window: Duration,
+    state: Mutex<DedupState>,
+}
+
+#[derive(Default)]
+struct DedupState {
+    /// Keys in the order they were seen, for pruning.
+    order: VecDeque<(Instant, u64)>,
+    seen: HashMap<u64, Instant>,
+}
+
+impl DedupWindow {
+    fn new(window_secs: f64) -> Self {
+        DedupWindow {
+            window: saturating_duration(window_secs),
+            state: Mutex::new(DedupState::default()),
+        }
+    }
+
+    /// True if `key` was seen within the window; otherwise remember it.
+    /// Expired keys are pruned on every call.
+    fn check(&self, key: u64) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while let Some(&(at, old)) = state.order.front() {
+            if now.duration_since(at) < self.window && state.order.len() < DEDUP_MAX_KEYS {
+                break;
+            }
+            state.order.pop_front();
+            if state.seen.get(&old) == Some(&at) {
+                state.seen.remove(&old);
+            }
+        }
+        if state.seen.contains_key(&key) {
+            return true;
+        }
+        state.order.push_back((now, key));
+        state.seen.insert(key, now);
+        false
+    }
+
+    /// Forget `key`, for an op that was checked but never made it into the queue.
+    fn forget(&self, key: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.seen.remove(&key);
+    }
+}
+
+/// Dedup key for an op: transcriptions by (guild, user, content), generic
+/// writes only when the caller names a key. Other ops are never deduplicated.
+fn dedup_key(op: &DbWriteOp, explicit: Option<&str>) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    match (op, explicit) {
+        (DbWriteOp::Transcription(record), _) => {
+            (record.guild_id, record.user_id, &record.content).hash(&mut hasher);
+        }
+        (DbWriteOp::Generic { table, .. }, Some(key)) => {
+            (table, key).hash(&mut hasher);
+        }
+        _ => return None,
+    }
+    Some(hasher.finish())
+}
+
+/// An op that exhausted its retries.
+struct DeadLetter {
+    op: DbWriteOp,
+    error: String,
+    attempts: u32,
+}
+
+/// Counts a worker as gone when its thread exits, including by panic.
+struct WorkerGuard(Arc<WriterShared>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.workers_alive.fetch_sub(1, Ordering::SeqCst);
+        let _lock = self.0.drain_lock.lock();
+        self.0.drained.notify_all();
+    }
+}
+
+impl WriterShared {
+    fn record_failure(&self, error: String, op: Option<&DbWriteOp>) {
+        self.failed_count.fetch_add(1, Ordering::Relaxed);
+        bump(CoreCounter::DbOpsFailed, 1);
+        self.report_error(error, op);
+    }
+
+    /// Surface an error (callback or stderr, recent list, last error)
+    /// without counting it as a failed write.
+    fn report_error(&self, error: String, op: Option<&DbWriteOp>) {
+        // Falls back to stderr once the interpreter is exiting
+        let reported = self.on_error.as_ref().and_then(|callback| {
+            with_gil_unless_exiting(|py| {
+                let summary = match op {
+                    Some(op) => op_summary(py, op).map(|d| d.to_object(py)),
+                    None => Ok(py.None()),
+                };
+                // A raising callback must not take the worker down with it
+                if let Err(e) = summary.and_then(|summary| callback.call1(py, (&error, summary))) {
+                    eprintln!("DB write failed: {} (on_error raised: {})", error, e);
+                }
+            })
+        });
+        if reported.is_none() {
+            eprintln!("DB write failed: {}", error);
+        }
+        if let Ok(mut recent) = self.recent_errors.lock() {
+            if recent.len() == RECENT_ERRORS_LIMIT {
+                recent.pop_front();
+            }
+            recent.push_back(error.clone());
+        }
+        if let Ok(mut last) = self.last_error.lock() {
+            *last = Some(error);
+        }
+    }
+
+    /// Mark journaled ops as done so they are not replayed.
+    fn consume_journal(&self, seqs: Vec<u64>) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.consume(&seqs) {
+                self.report_error(format!("Failed to update write journal: {}", e), None);
+            }
+        }
+    }
+
+    /// Give up on an op: count it and keep it for `failed_ops()`.
+    fn dead_letter(&self, op: DbWriteOp, error: String, attempts: u32) {
+        self.record_failure(format!("{} (attempts: {})", error, attempts), Some(&op));
+        if let Ok(mut dead) = self.dead_letters.lock() {
+            dead.push(DeadLetter { op, error, attempts });
+        }
+    }
+
+    /// Set aside ops whose Python handler was skipped because the
+    /// interpreter is exiting. They are dead-lettered with one summary
+    /// report, and left in the journal (if any) to be replayed next run.
+    fn spill_at_exit(&self, ops: Vec<QueuedOp>) {
+        if ops.is_empty() {
+            return;
+        }
+        let count = ops.len();
+        let keys: Vec<String> = ops.iter().map(|queued| queued.op.pending_key()).collect();
+        self.failed_count.fetch_add(count, Ordering::Relaxed);
+        bump(CoreCounter::DbOpsFailed, count as u64);
+        let error = "interpreter is shutting down; Python handler not called".to_string();
+        if let Ok(mut dead) = self.dead_letters.lock() {
+            dead.extend(ops.into_iter().map(|queued| DeadLetter { op: queued.op, error: error.clone(), attempts: 0 }));
+        }
+        let replay = if self.journal.is_some() { "; kept in the journal for replay" } else { "" };
+        self.report_error(format!("{} writes skipped at interpreter shutdown{}", count, replay), None);
+        self.finish(keys);
+    }
+
+    /// Count committed ops and sample how long each sat in the queue.
+    fn record_committed(&self, committed: &[QueuedOp]) {
+        self.total_committed.fetch_add(committed.len(), Ordering::Relaxed);
+        bump(CoreCounter::DbOpsCommitted, committed.len() as u64);
+        let now = Instant::now();
+        if let Ok(mut samples) = self.queue_latencies.lock() {
+            for queued in committed {
+                if samples.len() == LATENCY_SAMPLE_LIMIT {
+                    samples.pop_front();
+                }
+                samples.push_back(now.duration_since(queued.queued_at).as_secs_f64());
+            }
+        }
+        self.consume_journal(committed.iter().filter_map(|queued| queued.seq).collect());
+    }
+
+    /// Count an accepted op as pending.
+    fn start(&self, op: &DbWriteOp) {
+        self.pending_by_key
+            .entry(op.pending_key())
+            .or_default()
+            .fetch_add(1, Ordering::Release);
+        self.pending_count.fetch_add(1, Ordering::Release);
+    }
+
+    /// Mark ops, given by their pending keys, as fully handled.
+    fn finish(&self, keys: impl IntoIterator<Item = String>) {
+        let mut n = 0;
+        let mut key_drained = false;
+        for key in keys {
+            n += 1;
+            if let Some(count) = self.pending_by_key.get(&key) {
+                let previous = count
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| Some(c.saturating_sub(1)))
+                    .unwrap_or(0);
+                key_drained |= previous <= 1;
+            }
+        }
+        if n == 0 {
+            return;
+        }
+        let previous = self
+            .pending_count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| Some(count.saturating_sub(n)))
+            .unwrap_or(0);
+        if previous <= n || key_drained {
+            // Take the lock so a waiter between its check and its wait cannot miss this
+            let _lock = self.drain_lock.lock();
+            self.drained.notify_all();
+        }
+    }
+
+    /// Pending ops overall, or for one `pending_key()`.
+    fn pending(&self, key: Option<&str>) -> usize {
+        match key {
+            None => self.pending_count.load(Ordering::Acquire),
+            Some(key) => self
+                .pending_by_key
+                .get(key)
+                .map_or(0, |count| count.load(Ordering::Acquire)),
+        }
+    }
+}
+
+/// Cap on a single retry delay, before jitter.
+const RETRY_MAX_DELAY_SECS: f64 = 5.0;
+
+/// Write-ahead journal records: one line per queued op, plus lines marking
+/// ops done. Only `append`/`consume` write to it while the writer runs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JournalRecord {
+    Op { seq: u64, priority: i32, op: DbWriteOp },
+    Done { done: Vec<u64> },
+}
+
+/// A journaled op that has not been marked done: (seq, priority, op).
+type JournalEntry = (u64, i32, DbWriteOp);
+
+/// Compact the journal after this many ops have been marked done.
+const JOURNAL_COMPACT_EVERY: usize = 1000;
+
+/// Append-only JSON-lines journal that lets queued writes survive a crash.
+struct Journal {
+    path: PathBuf,
+    inner: Mutex<JournalInner>,
+}
+
+struct JournalInner {
+    file: File,
+    next_seq: u64,
+    done_since_compact: usize,
+}
+
+impl Journal {
+    /// Open (or create) the journal at `path` and return it with the ops a
+    /// previous run left unfinished, oldest first, and the number of
+    /// unreadable records skipped. The file is rewritten to hold just those
+    /// ops, which also drops a torn final record.
+    fn open(path: &Path) -> io::Result<(Journal, Vec<JournalEntry>, usize)> {
+        let (live, skipped) = match fs::read(path) {
+            Ok(bytes) => Self::live_ops(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+            Err(e) => return Err(e),
+        };
+        let file = Self::rewrite(path, &live)?;
+        let next_seq = live.last().map(|(seq, _, _)| seq + 1).unwrap_or(1);
+        let journal = Journal {
+            path: path.to_path_buf(),
+            inner: Mutex::new(JournalInner { file, next_seq, done_since_compact: 0 }),
+        };
+        Ok((journal, live, skipped))
+    }
+
+    /// Ops without a matching done record, and how many complete lines were
+    /// unreadable and skipped. An unterminated final line is where a crash
+    /// hit mid-write and is ignored without counting.
+    fn live_ops(bytes: &[u8]) -> (Vec<JournalEntry>, usize) {
+        let mut ops = BTreeMap::new();
+        let mut skipped = 0;
+        let mut rest = bytes;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let line = &rest[..end];
+            rest = &rest[end + 1..];
+            match serde_json::from_slice::<JournalRecord>(line) {
+                Ok(JournalRecord::Op { seq, priority, op }) => {
+                    ops.insert(seq, (priority, op));
+                }
+                Ok(JournalRecord::Done { done }) => {
+                    for seq in done {
+                        ops.remove(&seq);
+                    }
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+        let live = ops.into_iter().map(|(seq, (priority, op))| (seq, priority, op)).collect();
+        (live, skipped)
+    }
+
+    /// Replace the journal with just `live` and return an append handle.
+    fn rewrite(path: &Path, live: &[JournalEntry]) -> io::Result<File> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for (seq, priority, op) in live {
+                tmp.write_all(Self::op_line(*seq, *priority, op)?.as_bytes())?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn op_line(seq: u64, priority: i32, op: &DbWriteOp) -> io::Result<String> {
+        let mut line = serde_json::to_string(&serde_json::json!({ "seq": seq, "priority": priority, "op": op }))?;
+        line.push('\n');
+        Ok(line)
+    }
+
+    /// Record a queued op, synced to disk before returning its sequence number.
+    fn append(&self, op: &DbWriteOp, priority: i32) -> io::Result<u64> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let seq = inner.next_seq;
+        // One write per record, so a crash can only tear the last line
+        inner.file.write_all(Self::op_line(seq, priority, op)?.as_bytes())?;
+        inner.file.sync_data()?;
+        inner.next_seq += 1;
+        Ok(seq)
+    }
+
+    /// Mark ops done, compacting once enough have piled up.
+    fn consume(&self, seqs: &[u64]) -> io::Result<()> {
+        if seqs.is_empty() {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut line = serde_json::to_string(&serde_json::json!({ "done": seqs }))?;
+        line.push('\n');
+        inner.file.write_all(line.as_bytes())?;
+        inner.done_since_compact += seqs.len();
+        if inner.done_since_compact >= JOURNAL_COMPACT_EVERY {
+            let (live, _) = Self::live_ops(&fs::read(&self.path)?);
+            inner.file = Self::rewrite(&self.path, &live)?;
+            inner.done_since_compact = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Python callables that perform the actual writes.
+struct WriteHandlers {
+    transcription: Option<Py<PyAny>>,
+    generic: Option<Py<PyAny>>,
+    mutation: Option<Py<PyAny>>,
+    /// Per-table handlers for generic writes, shared with the Python side.
+    tables: TableHandlers,
+    /// Fail generic writes to unregistered tables instead of using `generic`.
+    strict_tables: bool,
+}
+
+type TableHandlers = Arc<RwLock<HashMap<String, Py<PyAny>>>>;
+
+impl WriteHandlers {
+    fn clone_ref(&self, py: Python<'_>) -> Self {
+        WriteHandlers {
+            transcription: self.transcription.as_ref().map(|h| h.clone_ref(py)),
+            generic: self.generic.as_ref().map(|h| h.clone_ref(py)),
+            mutation: self.mutation.as_ref().map(|h| h.clone_ref(py)),
+            tables: self.tables.clone(),
+            strict_tables: self.strict_tables,
+        }
+    }
+
+    /// Handler for a generic write to `table`: its registered handler, else
+    /// the default unless `strict_tables`. Only holds the registry lock.
+    fn generic_for(&self, table: &str) -> Option<Py<PyAny>> {
+        let registered = self.tables.read().ok().and_then(|tables| tables.get(table).cloned());
+        match registered {
+            Some(handler) => Some(handler),
+            None if self.strict_tables => None,
+            None => self.generic.clone(),
+        }
+    }
+}
+
+/// Connection for the native write path.
+#[cfg(feature = "sqlite")]
+type NativeDb = rusqlite::Connection;
+
+/// Stand-in when built without the `sqlite` feature; never constructed,
+/// since `db_path` is rejected up front.
+#[cfg(not(feature = "sqlite"))]
+enum NativeDb {}
+
+/// Everything the worker thread needs to perform writes.
+struct WriteBackend {
+    handlers: WriteHandlers,
+    sqlite: Option<NativeDb>,
+    max_batch: usize,
+    linger: Duration,
+    max_attempts: u32,
+    retry_backoff_secs: f64,
+    rng: SmallRng,
+}
+
+impl WriteBackend {
+    /// Exponential backoff before retry number `attempt` (1-based), with
+    /// jitter in [50%, 100%] so retries from a burst spread out.
+    fn retry_delay(&mut self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(30) as i32;
+        let base = (self.retry_backoff_secs * 2f64.powi(exp)).min(RETRY_MAX_DELAY_SECS);
+        Duration::from_secs_f64(base * self.rng.gen_range(0.5..=1.0))
+    }
+}
+
+#[pymethods]
+impl DatabaseWriter {
+    #[new]
+    #[pyo3(signature = (
+        transcription_handler = None,
+        generic_handler = None,
+        db_path = None,
+        max_batch = 256,
+        linger_secs = 0.05,
+        capacity = None,
+        policy = "block",
+        block_timeout_secs = 5.0,
+        max_attempts = 3,
+        retry_backoff_secs = 0.1,
+        on_error = None,
+        mutation_handler = None,
+        workers = 1,
+        journal_path = None,
+        strict_tables = false,
+        dedup_window_secs = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        transcription_handler: Option<Py<PyAny>>,
+        generic_handler: Option<Py<PyAny>>,
+        db_path: Option<String>,
+        max_batch: usize,
+        linger_secs: f64,
+        capacity: Option<usize>,
+        policy: &str,
+        block_timeout_secs: f64,
+        max_attempts: u32,
+        retry_backoff_secs: f64,
+        on_error: Option<Py<PyAny>>,
+        mutation_handler: Option<Py<PyAny>>,
+        workers: usize,
+        journal_path: Option<String>,
+        strict_tables: bool,
+        dedup_window_secs: Option<f64>,
+    ) -> PyResult<Self> {
+        if db_path.is_some() && !cfg!(feature = "sqlite") {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "db_path needs guildest_core built with the sqlite feature",
+            ));
+        }
+        if max_batch == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "max_batch must be at least 1",
+            ));
+        }
+        if !linger_secs.is_finite() || linger_secs < 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "linger_secs must be a non-negative number",
+            ));
+        }
+        if capacity == Some(0) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "capacity must be at least 1",
+            ));
+        }
+        if !block_timeout_secs.is_finite() || block_timeout_secs < 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "block_timeout_secs must be a non-negative number",
+            ));
+        }
+        if max_attempts == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "max_attempts must be at least 1",
+            ));
+        }
+        if !retry_backoff_secs.is_finite() || retry_backoff_secs < 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "retry_backoff_secs must be a non-negative number",
+            ));
+        }
+        if workers == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "workers must be at least 1",
+            ));
+        }
+        if let Some(secs) = dedup_window_secs {
+            if !secs.is_finite() || secs <= 0.0 {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "dedup_window_secs must be a positive number",
+                ));
+            }
+        }
+        let policy = QueuePolicy::parse(policy)?;
+        let (journal, recovered, journal_skipped) = match journal_path {
+            Some(path) => {
+                let (journal, recovered, skipped) = Journal::open(Path::new(&path)).map_err(|e| {
+                    PyErr::new::<pyo3::exceptions::PyIOError, _>(format!(
+                        "Failed to open write journal '{}': {}",
+                        path, e
+                    ))
+                })?;
+                (Some(journal), recovered, skipped)
+            }
+            None => (None, Vec::new(), 0),
+        };
+        let shared = Arc::new(WriterShared {
+            pending_count: AtomicUsize::new(0),
+            pending_by_key: DashMap::new(),
+            drain_lock: Mutex::new(()),
+            drained: Condvar::new(),
+            workers,
+            workers_alive: AtomicUsize::new(workers),
+            failed_count: AtomicUsize::new(0),
+            last_error: Mutex::new(None),
+            batches_committed: AtomicUsize::new(0),
+            batched_ops: AtomicUsize::new(0),
+            dropped_count: AtomicUsize::new(0),
+            dead_letters: Mutex::new(Vec::new()),
+            recent_errors: Mutex::new(VecDeque::new()),
+            on_error,
+            total_queued: AtomicUsize::new(0),
+            total_committed: AtomicUsize::new(0),
+            queue_depth: AtomicUsize::new(0),
+            max_queue_depth: AtomicUsize::new(0),
+            queue_latencies: Mutex::new(VecDeque::new()),
+            journal,
+            dedup: dedup_window_secs.map(DedupWindow::new),
+            deduped_count: AtomicUsize::new(0),
+        });
+        let handlers = WriteHandlers {
+            transcription: transcription_handler,
+            generic: generic_handler,
+            mutation: mutation_handler,
+            tables: Arc::new(RwLock::new(HashMap::new())),
+            strict_tables,
+        };
+        let table_handlers = handlers.tables.clone();
+
+        let mut queues: Vec<Arc<WriteQueue>> = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let queue = Arc::new(WriteQueue {
+                ops: Mutex::new(PriorityOps::default()),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+                capacity,
+                policy,
+                block_timeout: saturating_duration(block_timeout_secs),
+                closed: AtomicBool::new(false),
+            });
+            let worker_queue = queue.clone();
+            let worker_shared = shared.clone();
+            let worker_handlers = handlers.clone_ref(py);
+            let db_path = db_path.clone();
+
+            // Spawn background thread to process writes
+            let spawned = thread::Builder::new()
+                .name(format!("db-writer-{}", index))
+                .spawn(move || {
+                    let _guard = WorkerGuard(worker_shared.clone());
+                    // The connection is opened on the worker so it never crosses threads
+                    let sqlite = db_path.and_then(|path| match open_sqlite(&path) {
+                        Ok(conn) => Some(conn),
+                        Err(e) => {
+                            worker_shared
+                                .record_failure(format!("Failed to open SQLite database '{}': {}", path, e), None);
+                            None
+                        }
+                    });
+                    let backend = WriteBackend {
+                        handlers: worker_handlers,
+                        sqlite,
+                        max_batch,
+                        linger: saturating_duration(linger_secs),
+                        max_attempts,
+                        retry_backoff_secs,
+                        rng: SmallRng::from_entropy(),
+                    };
+                    DatabaseWriter::process_writes(&worker_queue, backend, worker_shared);
+                });
+            let handle = match spawned {
+                Ok(handle) => handle,
+                Err(e) => {
+                    // Stop the workers already running before reporting the failure
+                    shared.workers_alive.fetch_sub(workers - index, Ordering::SeqCst);
+                    for queue in &queues {
+                        queue.close(true, &shared);
+                    }
+                    return Err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
+                        "Failed to start DB writer worker: {}",
+                        e
+                    )));
+                }
+            };
+            queues.push(queue);
+            handles.push(handle);
+        }
+
+        if journal_skipped > 0 {
+            shared.report_error(format!("Skipped {} unreadable write journal records", journal_skipped), None);
+        }
+        let replayed = recovered.len();
+        for (seq, priority, op) in recovered {
+            let queued = QueuedOp { op, priority, queued_at: Instant::now(), seq: Some(seq) };
+            queues[route(&queued.op, queues.len())].push_replayed(queued, &shared);
+        }
+
+        let workers = Arc::new(Mutex::new(handles));
+        register_live_writer(&queues, &shared, &workers);
+        Ok(DatabaseWriter {
+            queues,
+            shared,
+            workers,
+            replayed,
+            journal_skipped,
+            table_handlers,
+        })
+    }
+
+    /// Queue a transcription to be saved, given as a `TranscriptionRecord` or
+    /// as the six positional fields `(guild_id, channel_id, user_id, content,
+    /// username, duration_secs)`. Higher `priority` is written sooner.
+    #[pyo3(signature = (record, channel_id = None, user_id = None, content = None, username = None, duration_secs = None, priority = 0))]
+    #[allow(clippy::too_many_arguments)]
+    fn queue_transcription(
+        &self,
+        py: Python<'_>,
+        record: &PyAny,
+        channel_id: Option<u64>,
+        user_id: Option<u64>,
+        content: Option<String>,
+        username: Option<String>,
+        duration_secs: Option<f64>,
+        priority: i32,
+    ) -> PyResult<()> {
+        let record = if let Ok(record) = record.extract::<TranscriptionRecord>() {
+            if channel_id.is_some() || user_id.is_some() || content.is_some() || username.is_some() || duration_secs.is_some() {
+                return Err(PyErr::new::<pyo3::exceptions::PyTypeError, _>(
+                    "queue_transcription() takes either a TranscriptionRecord or the positional fields, not both",
+                ));
+            }
+            record.validate()?;
+            record
+        } else {
+            match (channel_id, user_id, content, username, duration_secs) {
+                (Some(channel_id), Some(user_id), Some(content), Some(username), Some(duration_secs)) => {
+                    TranscriptionRecord {
+                        guild_id: record.extract()?,
+                        channel_id,
+                        user_id,
+                        content,
+                        username,
+                        duration_secs,
+                        message_ts: None,
+                        language: None,
+                        confidence: None,
+                    }
+                }
+                _ => {
+                    return Err(PyErr::new::<pyo3::exceptions::PyTypeError, _>(
+                        "queue_transcription() needs a TranscriptionRecord or guild_id, channel_id, user_id, content, username, duration_secs",
+                    ))
+                }
+            }
+        };
+        let op = DbWriteOp::Transcription(record);
+        let key = dedup_key(&op, None);
+        self.enqueue_deduped(py, op, key, priority)
+    }
+
+    /// Queue a generic database write (JSON data). Higher `priority` is written sooner.
+    /// With a dedup window, writes to the same table with the same `dedup_key`
+    /// inside the window are dropped.
+    #[pyo3(signature = (table, json_data, priority = 0, dedup_key = None))]
+    fn queue_write(
+        &self,
+        py: Python<'_>,
+        table: String,
+        json_data: String,
+        priority: i32,
+        dedup_key: Option<&str>,
+    ) -> PyResult<()> {
+        let op = DbWriteOp::Generic {
+            table,
+            data: json_data,
+        };
+        let key = self::dedup_key(&op, dedup_key);
+        self.enqueue_deduped(py, op, key, priority)
+    }
+
+    /// Queue `DELETE FROM table WHERE ...`; `where_json` must not be empty.
+    #[pyo3(signature = (table, where_json, priority = 0))]
+    fn queue_delete(&self, py: Python<'_>, table: String, where_json: &str, priority: i32) -> PyResult<()> {
+        let op = DbWriteOp::Delete {
+            table,
+            filter: parse_json_object("where_json", where_json)?,
+        };
+        self.enqueue_mutation(py, op, priority)
+    }
+
+    /// Queue `UPDATE table SET ... WHERE ...`; `where_json` must not be empty.
+    #[pyo3(signature = (table, set_json, where_json, priority = 0))]
+    fn queue_update(
+        &self,
+        py: Python<'_>,
+        table: String,
+        set_json: &str,
+        where_json: &str,
+        priority: i32,
+    ) -> PyResult<()> {
+        let op = DbWriteOp::Update {
+            table,
+            set: parse_json_object("set_json", set_json)?,
+            filter: parse_json_object("where_json", where_json)?,
+        };
+        self.enqueue_mutation(py, op, priority)
+    }
+
+    /// Queue an insert that updates the existing row when `conflict_keys` collide.
+    #[pyo3(signature = (table, data_json, conflict_keys, priority = 0))]
+    fn queue_upsert(
+        &self,
+        py: Python<'_>,
+        table: String,
+        data_json: &str,
+        conflict_keys: Vec<String>,
+        priority: i32,
+    ) -> PyResult<()> {
+        let op = DbWriteOp::Upsert {
+            table,
+            data: parse_json_object("data_json", data_json)?,
+            conflict_keys,
+        };
+        self.enqueue_mutation(py, op, priority)
+    }
+
+    /// Number of writes that failed (handler raised or missing, SQLite error).
+    fn failed_writes(&self) -> usize {
+        self.shared.failed_count.load(Ordering::Relaxed)
+    }
+
+    /// Most recent write or connection error, if any.
+    fn last_error(&self) -> Option<String> {
+        self.shared.last_error.lock().ok().and_then(|last| last.clone())
+    }
+
+    /// The most recent error strings, oldest first.
+    fn recent_errors(&self) -> Vec<String> {
+        self.shared
+            .recent_errors
+            .lock()
+            .map(|recent| recent.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Ops that exhausted their retries, oldest first, as dicts with `op`
+    /// ("transcription", "generic", "delete", "update", or "upsert"), the
+    /// op's fields, `error`, and `attempts`. With `clear=True` the list is
+    /// emptied after reading.
+    #[pyo3(signature = (clear = false))]
+    fn failed_ops(&self, py: Python<'_>, clear: bool) -> PyResult<Vec<PyObject>> {
+        let mut dead = self.shared.dead_letters.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = Vec::with_capacity(dead.len());
+        for letter in dead.iter() {
+            let dict = op_to_dict(py, &letter.op)?;
+            dict.set_item("error", &letter.error)?;
+            dict.set_item("attempts", letter.attempts)?;
+            out.push(dict.to_object(py));
+        }
+        if clear {
+            dead.clear();
+        }
+        Ok(out)
+    }
+
+    /// Pipeline health: lifetime totals (`total_queued`, `total_committed`,
+    /// `total_failed`, `total_dropped`, `total_deduped`), `queue_depth` now, and windowed
+    /// `max_queue_depth` plus `avg_queue_secs` / `p95_queue_secs` over the
+    /// last committed ops.
+    fn metrics(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let shared = &self.shared;
+        let mut latencies: Vec<f64> = shared
+            .queue_latencies
+            .lock()
+            .map(|samples| samples.iter().copied().collect())
+            .unwrap_or_default();
+        latencies.sort_by(|a, b| a.total_cmp(b));
+        let (avg, p95) = if latencies.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let avg = latencies.iter().sum::<f64>() / latencies.len() as f64;
+            let idx = ((latencies.len() as f64 * 0.95).ceil() as usize).saturating_sub(1);
+            (avg, latencies[idx])
+        };
+
+        let dict = PyDict::new(py);
+        dict.set_item("total_queued", shared.total_queued.load(Ordering::Relaxed))?;
+        dict.set_item("total_committed", shared.total_committed.load(Ordering::Relaxed))?;
+        dict.set_item("total_failed", shared.failed_count.load(Ordering::Relaxed))?;
+        dict.set_item("total_dropped", shared.dropped_count.load(Ordering::Relaxed))?;
+        dict.set_item("total_deduped", shared.deduped_count.load(Ordering::Relaxed))?;
+        dict.set_item("queue_depth", shared.queue_depth.load(Ordering::Relaxed))?;
+        dict.set_item("max_queue_depth", shared.max_queue_depth.load(Ordering::Relaxed))?;
+        dict.set_item("avg_queue_secs", avg)?;
+        dict.set_item("p95_queue_secs", p95)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Clear the windowed stats (max depth, time-in-queue samples); totals stay.
+    fn reset_metrics(&self) {
+        let depth = self.shared.queue_depth.load(Ordering::Relaxed);
+        self.shared.max_queue_depth.store(depth, Ordering::Relaxed);
+        if let Ok(mut samples) = self.shared.queue_latencies.lock() {
+            samples.clear();
+        }
+    }
+
+    /// Route generic writes for `table` to `callback(table, data)`, replacing
+    /// any handler already registered for it. Takes effect for the next batch.
+    fn register_table_handler(&self, table: String, callback: Py<PyAny>) {
+        let mut tables = self.table_handlers.write().unwrap_or_else(PoisonError::into_inner);
+        tables.insert(table, callback);
+    }
+
+    /// Remove the handler for `table`; returns whether one was registered.
+    fn unregister_table_handler(&self, table: &str) -> bool {
+        let mut tables = self.table_handlers.write().unwrap_or_else(PoisonError::into_inner);
+        tables.remove(table).is_some()
+    }
+
+    /// Tables with a registered handler, sorted.
+    fn registered_tables(&self) -> Vec<String> {
+        let tables = self.table_handlers.read().unwrap_or_else(PoisonError::into_inner);
+        let mut names: Vec<String> = tables.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Number of ops recovered from the journal when this writer started.
+    fn replayed_count(&self) -> usize {
+        self.replayed
+    }
+
+    /// Number of unreadable journal records skipped when this writer started.
+    fn journal_skipped_count(&self) -> usize {
+        self.journal_skipped
+    }
+
+    /// Number of writes refused or evicted because the queue was full.
+    fn dropped_count(&self) -> usize {
+        self.shared.dropped_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of writes dropped as duplicates within the dedup window.
+    fn deduped_count(&self) -> usize {
+        self.shared.deduped_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of batches the worker has finished.
+    fn batches_committed(&self) -> usize {
+        self.shared.batches_committed.load(Ordering::Relaxed)
+    }
+
+    /// Average number of ops per committed batch (0.0 before the first batch).
+    fn avg_batch_size(&self) -> f64 {
+        let batches = self.shared.batches_committed.load(Ordering::Relaxed);
+        if batches == 0 {
+            return 0.0;
+        }
+        self.shared.batched_ops.load(Ordering::Relaxed) as f64 / batches as f64
+    }
+
+    /// Get the number of pending writes.
+    fn pending_writes(&self) -> usize {
+        self.shared.pending(None)
+    }
+
+    /// Pending writes by category: `"transcription"` or `"<kind>:<table>"`
+    /// (e.g. `"generic:guild_settings"`). Categories with nothing pending are left out.
+    fn pending_writes_detailed(&self) -> HashMap<String, usize> {
+        self.shared
+            .pending_by_key
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Acquire)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Wait until every queued write has been handled, or only those in the
+    /// `key` category of `pending_writes_detailed()`.
+    /// Returns False if `timeout_secs` elapses first; raises `RuntimeError`
+    /// if a worker thread is no longer running.
+    #[pyo3(signature = (timeout_secs = None, key = None))]
+    fn flush(&self, py: Python<'_>, timeout_secs: Option<f64>, key: Option<&str>) -> PyResult<bool> {
+        let deadline = deadline_after("timeout_secs", timeout_secs)?;
+        let shared = &self.shared;
+
+        // Release the GIL: Python handlers on the worker need it to make progress
+        py.allow_threads(|| wait_drained(shared, deadline, key))
+            .map_err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>)
+    }
+
+    /// Awaitable `flush()`: waits on a helper thread and resolves on the
+    /// running event loop, so the loop stays responsive while the queue drains.
+    #[cfg(feature = "asyncio")]
+    #[pyo3(signature = (timeout_secs = None, key = None))]
+    fn flush_async(&self, py: Python<'_>, timeout_secs: Option<f64>, key: Option<String>) -> PyResult<PyObject> {
+        let deadline = deadline_after("timeout_secs", timeout_secs)?;
+        let shared = self.shared.clone();
+        spawn_awaitable(py, move || wait_drained(&shared, deadline, key.as_deref()))
+    }
+
+    /// Stop accepting writes, optionally drain what is queued, and join the
+    /// workers. Returns False if any worker is still busy after `timeout_secs`
+    /// (they are then left to finish in the background). Later calls are no-ops.
+    #[pyo3(signature = (drain = true, timeout_secs = 10.0))]
+    fn close(&self, py: Python<'_>, drain: bool, timeout_secs: f64) -> PyResult<bool> {
+        let deadline = deadline_after("timeout_secs", Some(timeout_secs))?;
+        self.begin_close(drain);
+        let shared = &self.shared;
+        let workers = &self.workers;
+        Ok(py.allow_threads(|| join_workers(shared, workers, deadline)))
+    }
+
+    /// Awaitable `close()`; see `flush_async()`.
+    #[cfg(feature = "asyncio")]
+    #[pyo3(signature = (drain = true, timeout_secs = 10.0))]
+    fn close_async(&self, py: Python<'_>, drain: bool, timeout_secs: f64) -> PyResult<PyObject> {
+        let deadline = deadline_after("timeout_secs", Some(timeout_secs))?;
+        self.begin_close(drain);
+        let shared = self.shared.clone();
+        let workers = self.workers.clone();
+        spawn_awaitable(py, move || Ok(join_workers(&shared, &workers, deadline)))
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Close with the default drain and timeout; exceptions propagate.
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&PyAny>,
+        _exc_value: Option<&PyAny>,
+        _traceback: Option<&PyAny>,
+    ) -> PyResult<bool> {
+        self.close(py, true, 10.0)?;
+        Ok(false)
+    }
+}
+
+impl DatabaseWriter {
+    /// Push onto the queue with the GIL released, since the block policy may
+    /// wait on a worker that needs the GIL to make room.
+    fn enqueue(&self, py: Python<'_>, op: DbWriteOp, priority: i32) -> PyResult<()> {
+        let queue = &self.queues[self.worker_for(&op)];
+        let shared = &self.shared;
+        py.allow_threads(|| queue.push(op, priority, shared))
+    }
+
+    /// Enqueue unless `key` repeats an op already queued within the dedup
+    /// window; duplicates are dropped silently and counted.
+    fn enqueue_deduped(&self, py: Python<'_>, op: DbWriteOp, key: Option<u64>, priority: i32) -> PyResult<()> {
+        let (dedup, key) = match (&self.shared.dedup, key) {
+            (Some(dedup), Some(key)) => (dedup, key),
+            _ => return self.enqueue(py, op, priority),
+        };
+        if dedup.check(key) {
+            self.shared.deduped_count.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let result = self.enqueue(py, op, priority);
+        if result.is_err() {
+            dedup.forget(key);
+        }
+        result
+    }
+
+    /// Close every worker queue; the workers exit once they reach `Shutdown`.
+    fn begin_close(&self, drain: bool) {
+        for queue in &self.queues {
+            queue.close(drain, &self.shared);
+        }
+    }
+
+    /// Ops with the same (table, guild) always go to the same worker so they
+    /// stay in order relative to each other.
+    fn worker_for(&self, op: &DbWriteOp) -> usize {
+        route(op, self.queues.len())
+    }
+
+    /// Check a delete/update/upsert builds valid SQL before queueing it, so
+    /// bad input raises here rather than failing later on the worker.
+    fn enqueue_mutation(&self, py: Python<'_>, op: DbWriteOp, priority: i32) -> PyResult<()> {
+        mutation_sql(&op).map_err(PyErr::new::<pyo3::exceptions::PyValueError, _>)?;
+        self.enqueue(py, op, priority)
+    }
+
+    /// Background thread that processes write operations.
+    fn process_writes(queue: &WriteQueue, mut backend: WriteBackend, shared: Arc<WriterShared>) {
+        loop {
+            // Block for the first op, then linger briefly so a batch can form
+            let (batch, shutdown) = match queue.pop(None, &shared) {
+                Some(QueuedOp { op: DbWriteOp::Shutdown, .. }) | None => (Vec::new(), true),
+                Some(queued) => {
+                    // Once the interpreter is exiting, take everything left in one go
+                    let max_batch =
+                        if PYTHON_EXITING.load(Ordering::SeqCst) { usize::MAX } else { backend.max_batch };
+                    Self::collect_batch(queue, queued, max_batch, backend.linger, &shared)
+                }
+            };
+            if !batch.is_empty() {
+                Self::commit_batch(queue, &mut backend, batch, &shared);
+            }
+            if shutdown {
+                break;
+            }
+        }
+    }
+
+    /// Gather ops after `first` until the batch is full or the linger runs out.
+    /// Returns the batch and whether a shutdown was seen.
+    fn collect_batch(
+        queue: &WriteQueue,
+        first: QueuedOp,
+        max_batch: usize,
+        linger: Duration,
+        shared: &WriterShared,
+    ) -> (Vec<QueuedOp>, bool) {
+        // A linger too long for an `Instant` waits for a full batch or shutdown
+        let deadline = Instant::now().checked_add(linger);
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match queue.pop(deadline, shared) {
+                Some(QueuedOp { op: DbWriteOp::Shutdown, .. }) => return (batch, true),
+                Some(queued) => batch.push(queued),
+                None => break,
+            }
+        }
+        (batch, false)
+    }
+
+    /// Write a batch, retrying failures with backoff until they succeed,
+    /// run out of attempts, or the writer is closed. Pending only drops once
+    /// an op reaches its final outcome.
+    fn commit_batch(queue: &WriteQueue, backend: &mut WriteBackend, batch: Vec<QueuedOp>, shared: &WriterShared) {
+        let size = batch.len();
+        let (committed, mut failures, skipped) = Self::write_batch(backend, batch);
+        shared.spill_at_exit(skipped);
+        shared.batches_committed.fetch_add(1, Ordering::Relaxed);
+        shared.batched_ops.fetch_add(size, Ordering::Relaxed);
+        shared.record_committed(&committed);
+        shared.finish(committed.iter().map(|queued| queued.op.pending_key()));
+
+        let mut attempts = 1;
+        while !failures.is_empty() && attempts < backend.max_attempts {
+            let delay = backend.retry_delay(attempts);
+            if queue.wait_closed(delay) {
+                break;
+            }
+            attempts += 1;
+            let (committed, still_failing, skipped) =
+                Self::write_batch(backend, failures.into_iter().map(|(queued, _)| queued).collect());
+            shared.spill_at_exit(skipped);
+            shared.record_committed(&committed);
+            shared.finish(committed.iter().map(|queued| queued.op.pending_key()));
+            failures = still_failing;
+        }
+
+        let keys: Vec<String> = failures.iter().map(|(queued, _)| queued.op.pending_key()).collect();
+        shared.consume_journal(failures.iter().filter_map(|(queued, _)| queued.seq).collect());
+        for (queued, error) in failures {
+            shared.dead_letter(queued.op, error, attempts);
+        }
+        shared.finish(keys);
+    }
+
+    /// One attempt at a batch: native inserts share one transaction, Python
+    /// handlers share one GIL acquisition. Returns the committed ops, the ops
+    /// that failed, and the ops skipped because the interpreter is exiting.
+    fn write_batch(
+        backend: &mut WriteBackend,
+        batch: Vec<QueuedOp>,
+    ) -> (Vec<QueuedOp>, Vec<(QueuedOp, String)>, Vec<QueuedOp>) {
+        let (native, python): (Vec<QueuedOp>, Vec<QueuedOp>) = batch.into_iter().partition(|queued| {
+            backend.sqlite.is_some() && !matches!(queued.op, DbWriteOp::Generic { .. })
+        });
+
+        let mut committed = Vec::new();
+        let mut failures = Vec::new();
+        let mut skipped = Vec::new();
+        if let Some(conn) = backend.sqlite.as_mut() {
+            if !native.is_empty() {
+                let (written, failed) = Self::write_native(conn, native);
+                committed.extend(written);
+                failures.extend(failed);
+            }
+        }
+
+        if !python.is_empty() {
+            let handlers = &backend.handlers;
+            // Resolve table handlers before taking the GIL
+            let mut python: Vec<(QueuedOp, Option<Py<PyAny>>)> = python
+                .into_iter()
+                .map(|queued| {
+                    let handler = match &queued.op {
+                        DbWriteOp::Generic { table, .. } => handlers.generic_for(table),
+                        _ => None,
+                    };
+                    (queued, handler)
+                })
+                .collect();
+            // Whatever is left in `python` afterwards was never handed over
+            with_gil_unless_exiting(|py| {
+                for (queued, table_handler) in python.drain(..) {
+                    match Self::call_handler(py, handlers, &queued.op, table_handler.as_ref()) {
+                        Ok(()) => committed.push(queued),
+                        Err(e) => failures.push((queued, e.to_string())),
+                    }
+                }
+            });
+            skipped.extend(python.into_iter().map(|(queued, _)| queued));
+        }
+        (committed, failures, skipped)
+    }
+
+    /// Run native ops in a single transaction. A failed statement fails on
+    /// its own; a failed commit fails every op that was written.
+    #[cfg(feature = "sqlite")]
+    fn write_native(
+        conn: &mut NativeDb,
+        ops: Vec<QueuedOp>,
+    ) -> (Vec<QueuedOp>, Vec<(QueuedOp, String)>) {
+        let tx = match conn.transaction() {
+            Ok(tx) => tx,
+            Err(e) => return (Vec::new(), ops.into_iter().map(|queued| (queued, e.to_string())).collect()),
+        };
+        let mut written = Vec::new();
+        let mut failures = Vec::new();
+        for queued in ops {
+            match execute_native(&tx, &queued.op) {
+                Ok(()) => written.push(queued),
+                Err(e) => failures.push((queued, e.to_string())),
+            }
+        }
+        if let Err(e) = tx.commit() {
+            failures.extend(written.into_iter().map(|queued| (queued, e.to_string())));
+            return (Vec::new(), failures);
+        }
+        (written, failures)
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn write_native(conn: &mut NativeDb, _ops: Vec<QueuedOp>) -> (Vec<QueuedOp>, Vec<(QueuedOp, String)>) {
+        match *conn {}
+    }
+
+    /// Hand one op to its Python handler; generic writes use `table_handler`
+    /// as resolved by `WriteHandlers::generic_for`.
+    fn call_handler(
+        py: Python<'_>,
+        handlers: &WriteHandlers,
+        op: &DbWriteOp,
+        table_handler: Option<&Py<PyAny>>,
+    ) -> PyResult<()> {
+        match op {
+            DbWriteOp::Transcription(record) => {
+                let handler = handlers.transcription.as_ref().ok_or_else(|| {
+                    PyErr::new::<pyo3::exceptions::PyRuntimeError, _>("no transcription_handler configured")
+                })?;
+                let args = (
+                    record.guild_id,
+                    record.channel_id,
+                    record.user_id,
+                    &record.content,
+                    &record.username,
+                    record.duration_secs,
+                );
+                handler.call(py, args, Some(record.extra_kwargs(py)?))?;
+            }
+            DbWriteOp::Generic { table, data } => {
+                let handler = table_handler.ok_or_else(|| {
+                    let message = if handlers.strict_tables {
+                        format!("no handler registered for table '{}'", table)
+                    } else {
+                        format!("no handler registered or generic_handler configured for table '{}'", table)
+                    };
+                    PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(message)
+                })?;
+                let parsed = py.import("json")?.call_method1("loads", (data,))?;
+                handler.call1(py, (table, parsed))?;
+            }
+            DbWriteOp::Delete { table, .. } | DbWriteOp::Update { table, .. } | DbWriteOp::Upsert { table, .. } => {
+                let handler = handlers.mutation.as_ref().ok_or_else(|| {
+                    PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
+                        "no mutation_handler configured for table '{}'",
+                        table
+                    ))
+                })?;
+                handler.call1(py, (op.kind(), table, mutation_payload(py, op)?))?;
+            }
+            DbWriteOp::Shutdown => {}
+        }
+        Ok(())
+    }
+}
+
+/// Open the SQLite database in WAL mode and make sure the schema exists.
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &str) -> rusqlite::Result<NativeDb> {
+    let conn = rusqlite::Connection::open(path)?;
+    conn.query_row("PRAGMA journal_mode=WAL", [], |_| Ok(()))?;
+    // Several workers may share the file; wait for the write lock instead of failing
+    conn.busy_timeout(Duration::from_secs(5))?;
+    conn.execute_batch(TRANSCRIPTIONS_SCHEMA)?;
+    // Tables created by `db/transcriptions.py` predate the extra columns
+    let existing: Vec<String> = conn
+        .prepare("PRAGMA table_info(transcriptions)")?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (column, ty) in TRANSCRIPTION_EXTRA_COLUMNS {
+        if !existing.iter().any(|c| c == column) {
+            conn.execute_batch(&format!("ALTER TABLE transcriptions ADD COLUMN {} {}", column, ty))?;
+        }
+    }
+    Ok(conn)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_path: &str) -> Result<NativeDb, &'static str> {
+    Err("built without the sqlite feature")
+}
+
+/// Run one native op; the caller owns the transaction.
+#[cfg(feature = "sqlite")]
+fn execute_native(conn: &rusqlite::Connection, op: &DbWriteOp) -> Result<(), String> {
+    if let DbWriteOp::Transcription(record) = op {
+        return insert_transcription(conn, record).map_err(|e| e.to_string());
+    }
+    let (sql, params) = mutation_sql(op)?;
+    let mut stmt = conn.prepare_cached(&sql).map_err(|e| e.to_string())?;
+    stmt.execute(rusqlite::params_from_iter(params.iter().map(json_to_sql)))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Comparisons allowed in a where object, e.g. `{"created_at": {"<": "2024-01-01"}}`.
+const WHERE_OPERATORS: &[&str] = &["=", "!=", "<", "<=", ">", ">="];
+
+/// Table and column names are spliced into SQL, so only plain identifiers pass.
+fn quote_identifier(name: &str) -> Result<String, String> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(format!("\"{}\"", name))
+    } else {
+        Err(format!("Invalid SQL identifier '{}'", name))
+    }
+}
+
+/// Bind value for a JSON scalar; arrays and objects are stored as JSON text.
+#[cfg(feature = "sqlite")]
+fn json_to_sql(value: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+/// `"col" = ? AND ...` for a where object. Values are equality matches
+/// (`null` means IS NULL) or `{operator: value}` with a `WHERE_OPERATORS` entry.
+fn where_clause(filter: &JsonMap, params: &mut Vec<serde_json::Value>) -> Result<String, String> {
+    if filter.is_empty() {
+        return Err("where clause must name at least one column".to_string());
+    }
+    let mut terms = Vec::with_capacity(filter.len());
+    for (column, value) in filter {
+        let column = quote_identifier(column)?;
+        match value {
+            serde_json::Value::Null => terms.push(format!("{} IS NULL", column)),
+            serde_json::Value::Object(cmp) => {
+                let (operator, operand) = match (cmp.len(), cmp.iter().next()) {
+                    (1, Some(pair)) => pair,
+                    _ => return Err(format!("comparison for {} must have exactly one operator", column)),
+                };
+                if !WHERE_OPERATORS.contains(&operator.as_str()) {
+                    return Err(format!("Unsupported operator '{}' for {}", operator, column));
+                }
+                params.push(operand.clone());
+                terms.push(format!("{} {} ?", column, operator));
+            }
+            other => {
+                params.push(other.clone());
+                terms.push(format!("{} = ?", column));
+            }
+        }
+    }
+    Ok(terms.join(" AND "))
+}
+
+/// Parameterized SQL for a delete/update/upsert op, with its JSON bind values.
+fn mutation_sql(op: &DbWriteOp) -> Result<(String, Vec<serde_json::Value>), String> {
+    let mut params = Vec::new();
+    let sql = match op {
+        DbWriteOp::Delete { table, filter } => {
+            let table = quote_identifier(table)?;
+            format!("DELETE FROM {} WHERE {}", table, where_clause(filter, &mut params)?)
+        }
+        DbWriteOp::Update { table, set, filter } => {
+            let table = quote_identifier(table)?;
+            if set.is_empty() {
+                return Err("update must set at least one column".to_string());
+            }
+            let mut assignments = Vec::with_capacity(set.len());
+            for (column, value) in set {
+                assignments.push(format!("{} = ?", quote_identifier(column)?));
+                params.push(value.clone());
+            }
+            let filter = where_clause(filter, &mut params)?;
+            format!("UPDATE {} SET {} WHERE {}", table, assignments.join(", "), filter)
+        }
+        DbWriteOp::Upsert { table, data, conflict_keys } => {
+            let table = quote_identifier(table)?;
+            if data.is_empty() {
+                return Err("upsert data must have at least one column".to_string());
+            }
+            if conflict_keys.is_empty() {
+                return Err("upsert needs at least one conflict key".to_string());
+            }
+            let mut columns = Vec::with_capacity(data.len());
+            let mut updates = Vec::new();
+            for (column, value) in data {
+                let quoted = quote_identifier(column)?;
+                if !conflict_keys.contains(column) {
+                    updates.push(format!("{0} = excluded.{0}", quoted));
+                }
+                columns.push(quoted);
+                params.push(value.clone());
+            }
+            let mut keys = Vec::with_capacity(conflict_keys.len());
+            for key in conflict_keys {
+                if !data.contains_key(key) {
+                    return Err(format!("conflict key '{}' is missing from the upsert data", key));
+                }
+                keys.push(quote_identifier(key)?);
+            }
+            let action = if updates.is_empty() {
+                "NOTHING".to_string()
+            } else {
+                format!("UPDATE SET {}", updates.join(", "))
+            };
+            format!(
+                "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO {}",
+                table,
+                columns.join(", "),
+                vec!["?"; columns.len()].join(", "),
+                keys.join(", "),
+                action
+            )
+        }
+        _ => return Err(format!("{} is not a delete/update/upsert op", op.kind())),
+    };
+    Ok((sql, params))
+}
+
+/// Parse a `*_json` argument that must be a JSON object.
+fn parse_json_object(name: &str, json: &str) -> PyResult<JsonMap> {
+    match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(serde_json::Value::Object(map)) => Ok(map),
+        Ok(_) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "{} must be a JSON object",
+            name
+        ))),
+        Err(e) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "Invalid {}: {}",
+            name, e
+        ))),
+    }
+}
+
+/// Convert a JSON object to a Python dict.
+fn json_map_to_py(py: Python<'_>, map: &JsonMap) -> PyResult<PyObject> {
+    let text = serde_json::to_string(map)
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (text,))?.to_object(py))
+}
+
+/// Payload handed to `mutation_handler(op, table, payload)`.
+fn mutation_payload<'py>(py: Python<'py>, op: &DbWriteOp) -> PyResult<&'py PyDict> {
+    let payload = PyDict::new(py);
+    match op {
+        DbWriteOp::Delete { filter, .. } => {
+            payload.set_item("where", json_map_to_py(py, filter)?)?;
+        }
+        DbWriteOp::Update { set, filter, .. } => {
+            payload.set_item("set", json_map_to_py(py, set)?)?;
+            payload.set_item("where", json_map_to_py(py, filter)?)?;
+        }
+        DbWriteOp::Upsert { data, conflict_keys, .. } => {
+            payload.set_item("data", json_map_to_py(py, data)?)?;
+            payload.set_item("conflict_keys", conflict_keys)?;
+        }
+        _ => {}
+    }
+    Ok(payload)
+}
+
+/// Insert one transcription row; the caller owns the transaction.
+#[cfg(feature = "sqlite")]
+fn insert_transcription(conn: &rusqlite::Connection, record: &TranscriptionRecord) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO transcriptions
+             (guild_id, channel_id, user_id, username, content, duration_secs, message_ts, language, confidence)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    stmt.execute(rusqlite::params![
+        record.guild_id as i64,
+        record.channel_id as i64,
+        record.user_id as i64,
+        record.username,
+        record.content,
+        record.duration_secs,
+        record.message_ts,
+        record.language,
+        record.confidence
+    ])?;
+    Ok(())
+}
+
+/// Describe a queued op as a Python dict: `op` is `DbWriteOp::kind()`, and
+/// the fields are the record's for transcriptions, `table` and `data` (the
+/// JSON string) for generic writes, or `table` plus the mutation payload
+/// (`where`, `set`, `data`, `conflict_keys`) for delete/update/upsert.
+fn op_to_dict<'py>(py: Python<'py>, op: &DbWriteOp) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    match op {
+        DbWriteOp::Transcription(record) => {
+            dict.set_item("op", op.kind())?;
+            for (key, value) in record.to_dict(py)? {
+                dict.set_item(key, value)?;
+            }
+        }
+        DbWriteOp::Generic { table, data } => {
+            dict.set_item("op", op.kind())?;
+            dict.set_item("table", table)?;
+            dict.set_item("data", data)?;
+        }
+        DbWriteOp::Delete { table, .. } | DbWriteOp::Update { table, .. } | DbWriteOp::Upsert { table, .. } => {
+            dict.set_item("op", op.kind())?;
+            dict.set_item("table", table)?;
+            for (key, value) in mutation_payload(py, op)? {
+                dict.set_item(key, value)?;
+            }
+        }
+        DbWriteOp::Shutdown => {
+            dict.set_item("op", op.kind())?;
+        }
+    }
+    Ok(dict)
+}
+
+/// Short description of a failed op for `on_error`: kind, table, guild (when
+/// the payload names one), and size in bytes.
+fn op_summary<'py>(py: Python<'py>, op: &DbWriteOp) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("op", op.kind())?;
+    dict.set_item("table", op.table())?;
+    dict.set_item("guild_id", op.guild_id())?;
+    dict.set_item("size", op.size())?;
+    Ok(dict)
+}
+
+/// Worker index for an op, hashing its (table, guild) key.
+fn route(op: &DbWriteOp, workers: usize) -> usize {
+    if workers == 1 {
+        return 0;
+    }
+    let mut hasher = DefaultHasher::new();
+    op.table().hash(&mut hasher);
+    op.guild_id().hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// A non-negative number of seconds as a `Duration`, saturating at
+/// `Duration::MAX` for values too large to represent.
+fn saturating_duration(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+/// Turn an optional timeout into a deadline, rejecting negative or NaN values.
+/// A timeout too long for an `Instant` gives no deadline (wait forever).
+fn deadline_after(name: &str, timeout_secs: Option<f64>) -> PyResult<Option<Instant>> {
+    match timeout_secs {
+        Some(t) if !t.is_finite() || t < 0.0 => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "{} must be a non-negative number",
+            name
+        ))),
+        Some(t) => Ok(Instant::now().checked_add(saturating_duration(t))),
+        None => Ok(None),
+    }
+}
+
+/// Block until nothing is pending (in the `key` category, if given).
+/// `Ok(false)` on timeout, `Err` if a worker died with writes still queued.
+/// Call without the GIL.
+fn wait_drained(shared: &WriterShared, deadline: Option<Instant>, key: Option<&str>) -> Result<bool, String> {
+    let mut guard = shared.drain_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        let pending = shared.pending(key);
+        if pending == 0 {
+            return Ok(true);
+        }
+        let alive = shared.workers_alive.load(Ordering::SeqCst);
+        if alive < shared.workers {
+            return Err(format!(
+                "{} of {} DB writer workers are not running ({} writes pending)",
+                shared.workers - alive,
+                shared.workers,
+                pending
+            ));
+        }
+        guard = match deadline {
+            None => shared.drained.wait(guard).unwrap_or_else(PoisonError::into_inner),
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(false);
+                }
+                shared
+                    .drained
+                    .wait_timeout(guard, remaining)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+        };
+    }
+}
+
+/// Wait for every worker to exit, then join them. False if the deadline
+/// passes first. Call without the GIL.
+fn join_workers(shared: &WriterShared, workers: &Mutex<Vec<JoinHandle<()>>>, deadline: Option<Instant>) -> bool {
+    let mut guard = shared.drain_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    while shared.workers_alive.load(Ordering::SeqCst) > 0 {
+        guard = match deadline {
+            None => shared.drained.wait(guard).unwrap_or_else(PoisonError::into_inner),
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return false;
+                }
+                shared
+                    .drained
+                    .wait_timeout(guard, remaining)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+        };
+    }
+    drop(guard);
+    for handle in workers.lock().map(|mut w| std::mem::take(&mut *w)).unwrap_or_default() {
+        let _ = handle.join();
+    }
+    true
+}
+
+/// Run `work` on a helper thread and return an asyncio future, created on
+/// the running loop, that resolves to its result (or raises `RuntimeError`).
+#[cfg(feature = "asyncio")]
+fn spawn_awaitable<F>(py: Python<'_>, work: F) -> PyResult<PyObject>
+where
+    F: FnOnce() -> Result<bool, String> + Send + 'static,
+{
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let future = event_loop.call_method0("create_future")?;
+    let loop_ref: PyObject = event_loop.into();
+    let future_ref: PyObject = future.into();
+
+    thread::Builder::new()
+        .name("db-writer-await".to_string())
+        .spawn(move || {
+            let outcome = work();
+            // Nobody is awaiting anymore if the interpreter is exiting
+            with_gil_unless_exiting(|py| {
+                let resolve = pyo3::types::PyCFunction::new_closure(py, None, None, move |args, _kwargs| -> PyResult<()> {
+                    let future = future_ref.as_ref(args.py());
+                    // The awaiting task may have been cancelled meanwhile
+                    if future.call_method0("done")?.is_true()? {
+                        return Ok(());
+                    }
+                    match &outcome {
+                        Ok(value) => future.call_method1("set_result", (*value,))?,
+                        Err(message) => future.call_method1(
+                            "set_exception",
+                            (PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(message.clone()).into_value(args.py()),),
+                        )?,
+                    };
+                    Ok(())
+                });
+                // A closed loop has nobody left to tell
+                if let Ok(resolve) = resolve {
+                    let _ = loop_ref.call_method1(py, "call_soon_threadsafe", (resolve,));
+                }
+            });
+        })
+        .map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to start flush thread: {}", e))
+        })?;
+    Ok(future.into())
+}
+
+fn writer_closed_error() -> PyErr {
+    PyErr::new::<pyo3::exceptions::PyRuntimeError, _>("DatabaseWriter is closed")
+}
+
+impl Drop for DatabaseWriter {
+    /// Best-effort close: queued writes still drain, but we cannot wait for
+    /// the workers here since they may need the GIL we are holding. Never
+    /// takes the GIL itself; drops during finalization rely on the exit hook.
+    fn drop(&mut self) {
+        self.begin_close(true);
+        if self.shared.workers_alive.load(Ordering::SeqCst) == 0 {
+            if let Ok(mut workers) = self.workers.lock() {
+                for handle in workers.drain(..) {
+                    let _ = handle.join();
+                }
+            }
+        }
+    }
+}
+
+/// Set by the exit hook; from then on writer threads stop taking the GIL.
+static PYTHON_EXITING: AtomicBool = AtomicBool::new(false);
+
+/// Writer threads inside, or about to enter, `with_gil_unless_exiting`.
+static PYTHON_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Writers the exit hook should close before the interpreter finalizes.
+static LIVE_WRITERS: LazyLock<Mutex<Vec<ExitHandle>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// How long the exit hook waits for writers to drain, and then again for
+/// callbacks already running.
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+
+/// Weak references to a writer's parts, enough to close and join it.
+struct ExitHandle {
+    queues: Vec<Weak<WriteQueue>>,
+    shared: Weak<WriterShared>,
+    workers: Weak<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+/// Track a new writer for `close_writers_at_exit`.
+fn register_live_writer(
+    queues: &[Arc<WriteQueue>],
+    shared: &Arc<WriterShared>,
+    workers: &Arc<Mutex<Vec<JoinHandle<()>>>>,
+) {
+    let mut live = LIVE_WRITERS.lock().unwrap_or_else(PoisonError::into_inner);
+    // Writers whose workers have all exited no longer need closing
+    live.retain(|writer| writer.shared.strong_count() > 0);
+    live.push(ExitHandle {
+        queues: queues.iter().map(Arc::downgrade).collect(),
+        shared: Arc::downgrade(shared),
+        workers: Arc::downgrade(workers),
+    });
+}
+
+/// Keeps a thread counted in `PYTHON_CALLS`, even if the callback panics.
+struct PythonCall;
+
+impl Drop for PythonCall {
+    fn drop(&mut self) {
+        PYTHON_CALLS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Run `f` with the GIL from a writer thread, or return None without
+/// touching Python once the interpreter is exiting. Taking the GIL during
+/// finalization kills the calling thread mid-batch.
+fn with_gil_unless_exiting<R>(f: impl FnOnce(Python<'_>) -> R) -> Option<R> {
+    // Count ourselves before checking the flag, so the exit hook either
+    // sees this call and waits for it, or this call sees the flag
+    PYTHON_CALLS.fetch_add(1, Ordering::SeqCst);
+    let _call = PythonCall;
+    // SAFETY: Py_IsInitialized is safe to call at any time, without the GIL
+    if PYTHON_EXITING.load(Ordering::SeqCst) || unsafe { pyo3::ffi::Py_IsInitialized() } == 0 {
+        return None;
+    }
+    Some(Python::with_gil(f))
+}
+
+/// Registered with `atexit` on import, so it runs after any exit handlers
+/// the application registers later. Closes every live writer, draining for
+/// up to `EXIT_GRACE`, then stops writer threads from calling into Python
+/// and waits (again up to `EXIT_GRACE`) for callbacks already running.
+/// Workers still busy after that finish native writes and spill the rest.
+#[pyfunction]
+fn close_writers_at_exit(py: Python<'_>) {
+    let writers = std::mem::take(&mut *LIVE_WRITERS.lock().unwrap_or_else(PoisonError::into_inner));
+    py.allow_threads(|| {
+        let deadline = Instant::now() + EXIT_GRACE;
+        let live: Vec<_> = writers
+            .iter()
+            .filter_map(|writer| Some((writer, writer.shared.upgrade()?, writer.workers.upgrade()?)))
+            .collect();
+        for (writer, shared, _) in &live {
+            for queue in writer.queues.iter().filter_map(Weak::upgrade) {
+                queue.close(true, shared);
+            }
+        }
+        for (_, shared, workers) in &live {
+            join_workers(shared, workers, Some(deadline));
+        }
+        PYTHON_EXITING.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + EXIT_GRACE;
+        while PYTHON_CALLS.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+    });
+}
+
+// ============================================
+// Rate limiting
+// ============================================
+
+/// Per-key token bucket state.
+struct TokenBucket {
+    tokens: f64,
+    last_ts: f64,
+}
+
+/// Token-bucket rate limiter keyed by an integer id (user, guild, API route...).
+/// Buckets start full and refill continuously; fractional token costs are allowed.
+#[pyclass]
+struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    idle_ttl_secs: f64,
+    buckets: DashMap<u64, TokenBucket>,
+}
+
+#[pymethods]
+impl RateLimiter {
+    #[new]
+    #[pyo3(signature = (capacity, refill_per_sec, idle_ttl_secs = 600.0))]
+    fn new(capacity: f64, refill_per_sec: f64, idle_ttl_secs: f64) -> PyResult<Self> {
+        if capacity.is_nan() || capacity <= 0.0 || refill_per_sec.is_nan() || refill_per_sec < 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "capacity must be positive and refill_per_sec non-negative",
+            ));
+        }
+        if !idle_ttl_secs.is_finite() || idle_ttl_secs <= 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "idle_ttl_secs must be a positive number",
+            ));
+        }
+        Ok(RateLimiter {
+            capacity,
+            refill_per_sec,
+            idle_ttl_secs,
+            buckets: DashMap::new(),
+        })
+    }
+
+    /// Take `tokens` from the key's bucket if available.
+    /// Returns false (taking nothing) when the bucket is short.
+    #[pyo3(signature = (key, now_ts, tokens = 1.0))]
+    fn try_acquire(&self, key: u64, now_ts: f64, tokens: f64) -> PyResult<bool> {
+        check_tokens(tokens)?;
+        let mut bucket = self.refilled(key, now_ts);
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Seconds until `tokens` can be acquired for the key (0.0 if available now).
+    /// Returns infinity when the request exceeds capacity or the bucket never refills.
+    #[pyo3(signature = (key, now_ts, tokens = 1.0))]
+    fn time_until_available(&self, key: u64, now_ts: f64, tokens: f64) -> PyResult<f64> {
+        check_tokens(tokens)?;
+        if tokens > self.capacity {
+            return Ok(f64::INFINITY);
+        }
+        let bucket = self.refilled(key, now_ts);
+        let missing = tokens - bucket.tokens;
+        Ok(if missing <= 0.0 {
+            0.0
+        } else if self.refill_per_sec > 0.0 {
+            missing / self.refill_per_sec
+        } else {
+            f64::INFINITY
+        })
+    }
+
+    /// Drop buckets idle for longer than the TTL. Returns the number removed.
+    fn cleanup(&self, now_ts: f64) -> usize {
+        let before = self.buckets.len();
+        self.buckets
+            .retain(|_, bucket| now_ts - bucket.last_ts <= self.idle_ttl_secs);
+        before.saturating_sub(self.buckets.len())
+    }
+
+    /// Number of buckets currently tracked.
+    fn __len__(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+/// Token costs must be positive; a negative one would mint tokens.
+fn check_tokens(tokens: f64) -> PyResult<()> {
+    if tokens.is_finite() && tokens > 0.0 {
+        Ok(())
+    } else {
+        Err(PyErr::new::<pyo3::exceptions::PyValueError, _>("tokens must be a positive number"))
+    }
+}
+
+impl RateLimiter {
+    /// Fetch (or create full) the key's bucket, topped up to `now_ts`.
+    fn refilled(&self, key: u64, now_ts: f64) -> dashmap::mapref::one::RefMut<'_, u64, TokenBucket> {
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| TokenBucket {
+            tokens: self.capacity,
+            last_ts: now_ts,
+        });
+        let elapsed = now_ts - bucket.last_ts;
+        if elapsed > 0.0 {
+            bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+            bucket.last_ts = now_ts;
+        }
+        bucket
+    }
+}
+
+/// Sliding-window limiter: at most `max_events` per key in any rolling `window_secs`.
+/// Uses the same timestamp-pruning rule as spam tracking (events at exactly
+/// `now - window_secs` have aged out).
+#[pyclass]
+struct SlidingWindowLimiter {
+    max_events: usize,
+    window_secs: f64,
+    events: DashMap<u64, VecDeque<f64>>,
+}
+
+#[pymethods]
+impl SlidingWindowLimiter {
+    #[new]
+    fn new(max_events: usize, window_secs: f64) -> PyResult<Self> {
+        if max_events == 0 || window_secs.is_nan() || window_secs <= 0.0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "max_events and window_secs must be positive",
+            ));
+        }
+        Ok(SlidingWindowLimiter {
+            max_events,
+            window_secs,
+            events: DashMap::new(),
+        })
+    }
+
+    /// Record an event for `key` if the window allows it.
+    /// Returns (allowed, events_in_window, retry_after_secs). Denied attempts
+    /// are not recorded; retry_after is the time until the oldest blocking
+    /// event ages out (0.0 when allowed).
+    fn record_and_check(&self, key: u64, now_ts: f64) -> (bool, usize, f64) {
+        let cutoff = now_ts - self.window_secs;
+        let mut entry = self.events.entry(key).or_default();
+
+        while let Some(&ts) = entry.front() {
+            if ts <= cutoff {
+                entry.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if entry.len() < self.max_events {
+            entry.push_back(now_ts);
+            return (true, entry.len(), 0.0);
+        }
+
+        // The event that has to age out before one more fits in the window
+        let blocking = entry[entry.len() - self.max_events];
+        let retry_after = (blocking + self.window_secs - now_ts).max(0.0);
+        (false, entry.len(), retry_after)
+    }
+
+    /// Drop keys with no events inside the window. Returns the number removed.
+    fn cleanup(&self, now_ts: f64) -> usize {
+        let cutoff = now_ts - self.window_secs;
+        let before = self.events.len();
+        self.events
+            .retain(|_, events| events.back().is_some_and(|&ts| ts > cutoff));
+        before.saturating_sub(self.events.len())
+    }
+
+    /// Number of keys currently tracked.
+    fn __len__(&self) -> usize {
+        self.events.len()
+    }
+}
+
+// ============================================
+// Punishment ladder
+// ============================================
+
+/// Escalation policy for repeat offenders. Each step is (window_secs,
+/// action_tag): step `i` applies once a user has at least `i + 1` offenses
+/// within that step's window, and the highest such step wins. Offenses older
+/// than the longest window are forgotten. Action tags are opaque to Rust.
+#[pyclass]
+struct PunishmentLadder {
+    steps: Vec<(f64, String)>,
+    max_window_secs: f64,
+    /// (guild_id, user_id) -> offense timestamps, oldest first.
+    offenses: DashMap<SpamKey, VecDeque<f64>>,
+}
+
+impl PunishmentLadder {
+    /// (step index, offenses in that step's window) for `history`, or None
+    /// if it holds no offense within the first step's window.
+    fn step_for(&self, history: &VecDeque<f64>, now_ts: f64) -> Option<(usize, usize)> {
+        self.steps.iter().enumerate().rev().find_map(|(index, (window_secs, _))| {
+            let cutoff = now_ts - window_secs;
+            let count = history.iter().filter(|&&ts| ts > cutoff && ts <= now_ts).count();
+            (count > index).then_some((index, count))
+        })
+    }
+
+    fn prune(&self, history: &mut VecDeque<f64>, now_ts: f64) {
+        let cutoff = now_ts - self.max_window_secs;
+        history.retain(|&ts| ts > cutoff);
+    }
+
+    fn export(&self) -> Vec<DurableOffenses> {
+        let mut offenses: Vec<DurableOffenses> = self
+            .offenses
+            .iter()
+            .map(|e| DurableOffenses {
+                guild_id: e.key().0,
+                user_id: e.key().1,
+                offense_ts: e.value().iter().copied().collect(),
+            })
+            .collect();
+        offenses.sort_by_key(|o| (o.guild_id, o.user_id));
+        offenses
+    }
+
+    /// Load exported history, dropping offenses outside the longest window.
+    /// Returns the number of users restored.
+    fn restore(&self, offenses: Vec<DurableOffenses>, now_ts: f64) -> usize {
+        let mut restored = 0;
+        for entry in offenses {
+            let mut history: VecDeque<f64> = entry.offense_ts.into_iter().collect();
+            self.prune(&mut history, now_ts);
+            if !history.is_empty() {
+                self.offenses.insert((entry.guild_id, entry.user_id), history);
+                restored += 1;
+            }
+        }
+        restored
+    }
+}
+
+#[pymethods]
+impl PunishmentLadder {
+    #[new]
+    fn new(steps: Vec<(f64, String)>) -> PyResult<Self> {
+        if steps.is_empty() {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "steps must not be empty",
+            ));
+        }
+        if steps.iter().any(|(window_secs, _)| !(window_secs.is_finite() && *window_secs > 0.0)) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "step windows must be positive and finite",
+            ));
+        }
+        let max_window_secs = steps.iter().map(|(window_secs, _)| *window_secs).fold(0.0, f64::max);
+        Ok(PunishmentLadder {
+            steps,
+            max_window_secs,
+            offenses: DashMap::new(),
+        })
+    }
+
+    /// Record an offense and return the step it reaches as
+    /// (step_index, action_tag, offenses_in_step_window).
+    #[pyo3(signature = (guild_id, user_id, now_ts = None))]
+    fn record_offense(&self, guild_id: u64, user_id: u64, now_ts: Option<f64>) -> (usize, String, usize) {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let mut history = self.offenses.entry((guild_id, user_id)).or_default();
+        self.prune(&mut history, now_ts);
+        history.push_back(now_ts);
+        // The offense just recorded always falls within the first step's window
+        let (index, count) = self.step_for(&history, now_ts).unwrap_or((0, 1));
+        (index, self.steps[index].1.clone(), count)
+    }
+
+    /// The step a user is on without recording anything, as in
+    /// `record_offense`, or None with no recent offenses.
+    #[pyo3(signature = (guild_id, user_id, now_ts = None))]
+    fn current_step(&self, guild_id: u64, user_id: u64, now_ts: Option<f64>) -> Option<(usize, String, usize)> {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let history = self.offenses.get(&(guild_id, user_id))?;
+        let (index, count) = self.step_for(&history, now_ts)?;
+        Some((index, self.steps[index].1.clone(), count))
+    }
+
+    /// Forget a user's offenses in one guild, or in every guild when guild_id is None.
+    #[pyo3(signature = (user_id, guild_id = None))]
+    fn clear_user(&self, user_id: u64, guild_id: Option<u64>) {
+        match guild_id {
+            Some(guild_id) => {
+                self.offenses.remove(&(guild_id, user_id));
+            }
+            None => self.offenses.retain(|&(_, uid), _| uid != user_id),
+        }
+    }
+
+    /// Drop offenses older than the longest window. Returns users removed.
+    #[pyo3(signature = (now_ts = None))]
+    fn cleanup(&self, now_ts: Option<f64>) -> usize {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        retain_counting(&self.offenses, |_, history| {
+            self.prune(history, now_ts);
+            !history.is_empty()
+        })
+    }
+
+    /// Number of users with offense history.
+    fn __len__(&self) -> usize {
+        self.offenses.len()
+    }
+}
+
+// ============================================
+// Join gate (new member screening)
+// ============================================
+
+/// Discord epoch (2015-01-01) in Unix milliseconds.
+const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+/// Unix seconds a Discord snowflake was created at.
+fn snowflake_created_ts(snowflake: u64) -> f64 {
+    ((snowflake >> 22) + DISCORD_EPOCH_MS) as f64 / 1000.0
+}
+
+/// `JoinGate` flags: escalate the matching quarantine to a kick.
+const JOIN_GATE_FLAGS: &[&str] = &["kick_young", "kick_in_raid"];
+
+const VERDICT_ALLOW: u8 = 0;
+const VERDICT_QUARANTINE: u8 = 1;
+const VERDICT_KICK: u8 = 2;
+
+/// Screens new members by account age. Accounts younger than
+/// `min_account_age` are quarantined, and in raid mode so are accounts
+/// younger than `raid_mode_min_age`. Flag "kick_young" kicks instead for the
+/// first rule and "kick_in_raid" for the second. Ages are duration strings
+/// such as "7d", parsed as by `parse_duration_checked`.
+#[pyclass]
+struct JoinGate {
+    settings: Mutex<DurableJoinGate>,
+}
+
+impl JoinGate {
+    fn lock(&self) -> std::sync::MutexGuard<'_, DurableJoinGate> {
+        self.settings.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn check_flags(flags: &[String]) -> PyResult<()> {
+        match flags.iter().find(|flag| !JOIN_GATE_FLAGS.contains(&flag.as_str())) {
+            Some(flag) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown join gate flag '{}' (expected one of {})",
+                flag,
+                JOIN_GATE_FLAGS.join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[pymethods]
+impl JoinGate {
+    #[classattr]
+    const ALLOW: u8 = VERDICT_ALLOW;
+    #[classattr]
+    const QUARANTINE: u8 = VERDICT_QUARANTINE;
+    #[classattr]
+    const KICK: u8 = VERDICT_KICK;
+
+    #[new]
+    #[pyo3(signature = (min_account_age = "7d", raid_mode_min_age = "30d", flags = Vec::new()))]
+    fn new(min_account_age: &str, raid_mode_min_age: &str, flags: Vec<String>) -> PyResult<Self> {
+        Self::check_flags(&flags)?;
+        Ok(JoinGate {
+            settings: Mutex::new(DurableJoinGate {
+                min_account_age_secs: parse_duration_checked(min_account_age)?,
+                raid_mode_min_age_secs: parse_duration_checked(raid_mode_min_age)?,
+                flags,
+                raid_mode: false,
+            }),
+        })
+    }
+
+    /// Verdict for a joining member: `ALLOW` (0), `QUARANTINE` (1), or
+    /// `KICK` (2). Account age comes from the snowflake unless `created_ts`
+    /// is given. `raid_mode` overrides the gate's stored raid mode.
+    #[pyo3(signature = (user_id, now_ts = None, raid_mode = None, created_ts = None))]
+    fn evaluate(&self, user_id: u64, now_ts: Option<f64>, raid_mode: Option<bool>, created_ts: Option<f64>) -> u8 {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let age = now_ts - created_ts.unwrap_or_else(|| snowflake_created_ts(user_id));
+        let settings = self.lock();
+        let kick_or_quarantine = |flag: &str| {
+            if settings.flags.iter().any(|f| f == flag) {
+                VERDICT_KICK
+            } else {
+                VERDICT_QUARANTINE
+            }
+        };
+        let mut verdict = VERDICT_ALLOW;
+        if age < settings.min_account_age_secs as f64 {
+            verdict = verdict.max(kick_or_quarantine("kick_young"));
+        }
+        if raid_mode.unwrap_or(settings.raid_mode) && age < settings.raid_mode_min_age_secs as f64 {
+            verdict = verdict.max(kick_or_quarantine("kick_in_raid"));
+        }
+        verdict
+    }
+
+    /// Change either age threshold; omitted ones are kept.
+    #[pyo3(signature = (min_account_age = None, raid_mode_min_age = None))]
+    fn set_thresholds(&self, min_account_age: Option<&str>, raid_mode_min_age: Option<&str>) -> PyResult<()> {
+        let min_account_age = min_account_age.map(parse_duration_checked).transpose()?;
+        let raid_mode_min_age = raid_mode_min_age.map(parse_duration_checked).transpose()?;
+        let mut settings = self.lock();
+        if let Some(secs) = min_account_age {
+            settings.min_account_age_secs = secs;
+        }
+        if let Some(secs) = raid_mode_min_age {
+            settings.raid_mode_min_age_secs = secs;
+        }
+        Ok(())
+    }
+
+    /// Replace the flag list.
+    fn set_flags(&self, flags: Vec<String>) -> PyResult<()> {
+        Self::check_flags(&flags)?;
+        self.lock().flags = flags;
+        Ok(())
+    }
+
+    fn set_raid_mode(&self, enabled: bool) {
+        self.lock().raid_mode = enabled;
+    }
+
+    /// Current settings: `min_account_age_secs`, `raid_mode_min_age_secs`,
+    /// `flags`, and `raid_mode`.
+    fn settings<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let settings = self.lock().clone();
+        let dict = PyDict::new(py);
+        dict.set_item("min_account_age_secs", settings.min_account_age_secs)?;
+        dict.set_item("raid_mode_min_age_secs", settings.raid_mode_min_age_secs)?;
+        dict.set_item("flags", settings.flags)?;
+        dict.set_item("raid_mode", settings.raid_mode)?;
+        Ok(dict)
+    }
+}
+
+/// Unix seconds a Discord snowflake (user, message, or channel ID) was created at.
+#[pyfunction]
+fn snowflake_timestamp(snowflake: u64) -> f64 {
+    snowflake_created_ts(snowflake)
+}
+
+// ============================================
+// Message pipeline (all per-message checks in one call)
+// ============================================
+
+/// `<@id>` and `<@!id>` user mentions, `<@&id>` role mentions.
+static MENTION_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<@([!&]?)([0-9]+)>").unwrap());
+
+/// Custom emoji: `<:name:id>`, or `<a:name:id>` when animated.
+static CUSTOM_EMOJI_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<a?:[A-Za-z0-9_]{2,32}:[0-9]+>").unwrap());
+
+/// http(s) links; `<url>` (embed suppressed) is matched without the brackets.
+static LINK_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://[^\s<>]+").unwrap());
+
+/// Whether `c` starts an emoji or pictographic symbol grapheme.
+fn is_emoji_char(c: char) -> bool {
+    matches!(c, '\u{1F000}'..='\u{1FAFF}' | '\u{2600}'..='\u{27BF}' | '\u{2300}'..='\u{23FF}' | '\u{2B00}'..='\u{2BFF}')
+}
+
+/// One check `MessagePipeline` can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PipelineStage {
+    Filter,
+    Spam,
+    Mentions,
+    Emoji,
+    Links,
+}
+
+impl PipelineStage {
+    const COUNT: usize = 5;
+
+    fn parse(stage: &str) -> PyResult<Self> {
+        match stage {
+            "filter" => Ok(PipelineStage::Filter),
+            "spam" => Ok(PipelineStage::Spam),
+            "mentions" => Ok(PipelineStage::Mentions),
+            "emoji" => Ok(PipelineStage::Emoji),
+            "links" => Ok(PipelineStage::Links),
+            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unknown stage '{}' (expected 'filter', 'spam', 'mentions', 'emoji', or 'links')",
+                stage
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PipelineStage::Filter => "filter",
+            PipelineStage::Spam => "spam",
+            PipelineStage::Mentions => "mentions",
+            PipelineStage::Emoji => "emoji",
+            PipelineStage::Links => "links",
+        }
+    }
+}
+
+/// Running totals reported by `MessagePipeline.stats`, indexed by stage.
+#[derive(Default)]
+struct PipelineStats {
+    messages: u64,
+    overruns: u64,
+    runs: [u64; PipelineStage::COUNT],
+    skips: [u64; PipelineStage::COUNT],
+    budget_blown: [u64; PipelineStage::COUNT],
+    total_us: [f64; PipelineStage::COUNT],
+    max_us: [f64; PipelineStage::COUNT],
+}
+
+/// Runs the configured per-message checks in one call and times each one.
+///
+/// Stages run in the order given (by default every configured one: "filter"
+/// when a `WordFilter` is passed, "spam" when an `ActivityTrackerRust` is
+/// passed, then "mentions", "emoji", and "links"). With `budget_ms` set, a
+/// message whose running time passes the budget after a stage stops there:
+/// the result names that stage in `over_budget` and lists the rest as
+/// skipped. The budget is soft; a stage is never interrupted. Stages have
+/// rule modes like the tracker's rules (see `set_rule_mode`).
+#[pyclass]
+struct MessagePipeline {
+    stages: Vec<PipelineStage>,
+    word_filter: Option<Py<WordFilter>>,
+    tracker: Option<Py<ActivityTrackerRust>>,
+    max_mentions: Option<usize>,
+    max_emoji: Option<usize>,
+    max_links: Option<usize>,
+    budget: Option<Duration>,
+    rule_modes: RuleModes<{ PipelineStage::COUNT }>,
+    stats: Mutex<PipelineStats>,
+}
+
+#[pymethods]
+impl MessagePipeline {
+    #[new]
+    #[pyo3(signature = (
+        word_filter = None,
+        tracker = None,
+        stages = None,
+        max_mentions = None,
+        max_emoji = None,
+        max_links = None,
+        budget_ms = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        word_filter: Option<Py<WordFilter>>,
+        tracker: Option<Py<ActivityTrackerRust>>,
+        stages: Option<Vec<String>>,
+        max_mentions: Option<usize>,
+        max_emoji: Option<usize>,
+        max_links: Option<usize>,
+        budget_ms: Option<f64>,
+    ) -> PyResult<Self> {
+        let stages = match stages {
+            Some(names) => names.iter().map(|name| PipelineStage::parse(name)).collect::<PyResult<Vec<_>>>()?,
+            None => [
+                word_filter.as_ref().map(|_| PipelineStage::Filter),
+                tracker.as_ref().map(|_| PipelineStage::Spam),
+                Some(PipelineStage::Mentions),
+                Some(PipelineStage::Emoji),
+                Some(PipelineStage::Links),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        };
+        for (i, stage) in stages.iter().enumerate() {
+            if stages[..i].contains(stage) {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "Stage '{}' listed twice",
+                    stage.name()
+                )));
+            }
+            let missing = match stage {
+                PipelineStage::Filter if word_filter.is_none() => Some("word_filter"),
+                PipelineStage::Spam if tracker.is_none() => Some("tracker"),
+                _ => None,
+            };
+            if let Some(missing) = missing {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "Stage '{}' needs {}",
+                    stage.name(),
+                    missing
+                )));
+            }
+        }
+        let budget = match budget_ms {
+            Some(ms) if !(ms.is_finite() && ms > 0.0) => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "budget_ms must be positive",
+                ));
+            }
+            Some(ms) => Some(Duration::from_secs_f64(ms / 1000.0)),
+            None => None,
+        };
+        // Compile the patterns now so the first message isn't billed for it
+        LazyLock::force(&MENTION_REGEX);
+        LazyLock::force(&CUSTOM_EMOJI_REGEX);
+        LazyLock::force(&LINK_REGEX);
+        Ok(MessagePipeline {
+            stages,
+            word_filter,
+            tracker,
+            max_mentions,
+            max_emoji,
+            max_links,
+            budget,
+            rule_modes: RuleModes::new(),
+            stats: Mutex::new(PipelineStats::default()),
+        })
+    }
+
+    /// Run every stage on one message. Returns a dict with `guild_id`,
+    /// `channel_id`, `user_id`, `flagged` (any stage flagged), one dict per
+    /// stage that ran (each with its own `flagged`, plus `shadow` when a
+    /// shadowed stage would have flagged), `timings_us` per stage,
+    /// `total_us`, `over_budget` (the stage that passed the budget, or None),
+    /// and `skipped` (stages not run). Stage results:
+    /// - filter: `severity`, `hits` as (entry, severity, start, end)
+    /// - spam: `count` in the window, `fingerprint` (`content_fingerprint`),
+    ///   and `copies`/`copy_senders` for the same content across the guild
+    ///   (`record_content`)
+    /// - mentions: `count` of distinct users and roles, `users`, `roles`,
+    ///   `everyone` (@everyone or @here)
+    /// - emoji: `count` (Unicode plus custom), `custom`
+    /// - links: `count`, `urls`
+    #[pyo3(signature = (guild_id, channel_id, user_id, content, now_ts = None))]
+    fn process<'py>(
+        &self,
+        py: Python<'py>,
+        guild_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        content: &str,
+        now_ts: Option<f64>,
+    ) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        result.set_item("guild_id", guild_id)?;
+        result.set_item("channel_id", channel_id)?;
+        result.set_item("user_id", user_id)?;
+        let timings = PyDict::new(py);
+        let mut flagged = false;
+        let mut over_budget = None;
+        let mut skipped = Vec::new();
+        let mut elapsed = [None; PipelineStage::COUNT];
+        let started = Instant::now();
+
+        for &stage in &self.stages {
+            if self.rule_modes.is_off(stage as usize) {
+                continue;
+            }
+            if over_budget.is_some() {
+                skipped.push(stage);
+                continue;
+            }
+            let stage_started = Instant::now();
+            let (mut stage_flagged, details) = self.run_stage(py, stage, guild_id, user_id, content, now_ts)?;
+            let took = stage_started.elapsed();
+            if stage_flagged && !self.rule_modes.report(stage as usize) {
+                stage_flagged = false;
+                details.set_item("shadow", true)?;
+            }
+            flagged |= stage_flagged;
+            details.set_item("flagged", stage_flagged)?;
+            result.set_item(stage.name(), details)?;
+            timings.set_item(stage.name(), took.as_secs_f64() * 1e6)?;
+            elapsed[stage as usize] = Some(took.as_secs_f64() * 1e6);
+            if self.budget.is_some_and(|budget| started.elapsed() > budget) {
+                over_budget = Some(stage);
+            }
+        }
+        let total_us = started.elapsed().as_secs_f64() * 1e6;
+
+        {
+            let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+            stats.messages += 1;
+            bump(CoreCounter::PipelineMessages, 1);
+            for (i, us) in elapsed.iter().enumerate() {
+                if let Some(us) = *us {
+                    stats.runs[i] += 1;
+                    stats.total_us[i] += us;
+                    stats.max_us[i] = stats.max_us[i].max(us);
+                }
+            }
+            for &stage in &skipped {
+                stats.skips[stage as usize] += 1;
+            }
+            if let Some(stage) = over_budget {
+                stats.overruns += 1;
+                bump(CoreCounter::PipelineOverruns, 1);
+                stats.budget_blown[stage as usize] += 1;
+            }
+        }
+
+        result.set_item("flagged", flagged)?;
+        result.set_item("timings_us", timings)?;
+        result.set_item("total_us", total_us)?;
+        result.set_item("over_budget", over_budget.map(PipelineStage::name))?;
+        result.set_item("skipped", skipped.into_iter().map(PipelineStage::name).collect::<Vec<_>>())?;
+        Ok(result)
+    }
+
+    /// Stage names in run order.
+    #[getter]
+    fn stages(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    /// Totals since creation (or the last `reset_stats`): `messages`,
+    /// `overruns`, and per stage `runs`, `skipped`, `over_budget` (times it
+    /// was the stage that passed the budget), `mean_us`, and `max_us`.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        let dict = PyDict::new(py);
+        dict.set_item("messages", stats.messages)?;
+        dict.set_item("overruns", stats.overruns)?;
+        for &stage in &self.stages {
+            let i = stage as usize;
+            let entry = PyDict::new(py);
+            entry.set_item("runs", stats.runs[i])?;
+            entry.set_item("skipped", stats.skips[i])?;
+            entry.set_item("over_budget", stats.budget_blown[i])?;
+            let mean = if stats.runs[i] == 0 { 0.0 } else { stats.total_us[i] / stats.runs[i] as f64 };
+            entry.set_item("mean_us", mean)?;
+            entry.set_item("max_us", stats.max_us[i])?;
+            dict.set_item(stage.name(), entry)?;
+        }
+        Ok(dict)
+    }
+
+    fn reset_stats(&self) {
+        *self.stats.lock().unwrap_or_else(PoisonError::into_inner) = PipelineStats::default();
+    }
+
+    /// Set a stage's mode: "enforce" (the default), "shadow" (runs and
+    /// reports its details, but never flags), or "off" (not run).
+    fn set_rule_mode(&self, rule: &str, mode: &str) -> PyResult<()> {
+        let stage = PipelineStage::parse(rule)?;
+        self.rule_modes.set(stage as usize, RuleMode::parse(mode)?);
+        Ok(())
+    }
+
+    /// Current mode of a stage.
+    fn rule_mode(&self, rule: &str) -> PyResult<&'static str> {
+        Ok(self.rule_modes.get(PipelineStage::parse(rule)? as usize).name())
+    }
+
+    /// Times a shadowed stage would have flagged a message.
+    fn shadow_hits(&self, rule: &str) -> PyResult<usize> {
+        Ok(self.rule_modes.shadow_hits(PipelineStage::parse(rule)? as usize))
+    }
+}
+
+impl MessagePipeline {
+    /// Run one stage; returns (flagged, details).
+    fn run_stage<'py>(
+        &self,
+        py: Python<'py>,
+        stage: PipelineStage,
+        guild_id: u64,
+        user_id: u64,
+        content: &str,
+        now_ts: Option<f64>,
+    ) -> PyResult<(bool, &'py PyDict)> {
+        let details = PyDict::new(py);
+        let over = |count: usize, max: Option<usize>| max.is_some_and(|max| count > max);
+        let flagged = match stage {
+            PipelineStage::Filter => {
+                let filter = self.word_filter.as_ref().expect("checked in new").borrow(py);
+                let hits = filter.check(content);
+                let severity = hits.iter().map(|hit| hit.1).max().unwrap_or(0);
+                details.set_item("severity", severity)?;
+                details.set_item("hits", hits)?;
+                severity > 0
+            }
+            PipelineStage::Spam => {
+                let tracker = self.tracker.as_ref().expect("checked in new").borrow(py);
+                let now_ts = tracker.now(now_ts);
+                let (is_spam, count) = tracker.check_spam(user_id, Some(now_ts), guild_id);
+                let fingerprint = content_fingerprint(content);
+                let (copies, senders) =
+                    fingerprint.map_or((0, 0), |hash| tracker.record_fingerprint(guild_id, user_id, hash, now_ts));
+                details.set_item("count", count)?;
+                details.set_item("fingerprint", fingerprint)?;
+                details.set_item("copies", copies)?;
+                details.set_item("copy_senders", senders)?;
+                is_spam
+            }
+            PipelineStage::Mentions => {
+                let mut users = Vec::new();
+                let mut roles = Vec::new();
+                for caps in MENTION_REGEX.captures_iter(content) {
+                    let Ok(id) = caps[2].parse::<u64>() else {
+                        continue;
+                    };
+                    let ids = if &caps[1] == "&" { &mut roles } else { &mut users };
+                    if !ids.contains(&id) {
+                        ids.push(id);
+                    }
+                }
+                let count = users.len() + roles.len();
+                details.set_item("count", count)?;
+                details.set_item("users", users)?;
+                details.set_item("roles", roles)?;
+                details.set_item("everyone", content.contains("@everyone") || content.contains("@here"))?;
+                over(count, self.max_mentions)
+            }
+            PipelineStage::Emoji => {
+                let custom = CUSTOM_EMOJI_REGEX.find_iter(content).count();
+                let unicode = content
+                    .graphemes(true)
+                    .filter(|g| g.chars().next().is_some_and(is_emoji_char))
+                    .count();
+                details.set_item("count", custom + unicode)?;
+                details.set_item("custom", custom)?;
+                over(custom + unicode, self.max_emoji)
+            }
+            PipelineStage::Links => {
+                let urls: Vec<&str> = LINK_REGEX.find_iter(content).map(|m| m.as_str()).collect();
+                details.set_item("count", urls.len())?;
+                let flagged = over(urls.len(), self.max_links);
+                details.set_item("urls", urls)?;
+                flagged
+            }
+        };
+        Ok((flagged, details))
+    }
+}
+
+// ============================================
+// Scheduled actions (unmutes, expiring punishments)
+// ============================================
+
+/// Format version written by `ActionScheduler.export`.
+const SCHEDULER_STATE_VERSION: u32 = 1;
+
+/// Heap entry; ordered so the earliest `fire_at` (then lowest `seq`) pops first.
+struct ScheduledEntry {
+    fire_at: f64,
+    seq: u64,
+    action_id: u64,
+}
+
+impl PartialEq for ScheduledEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for ScheduledEntry {}
+
+impl PartialOrd for ScheduledEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Reversed: BinaryHeap is a max-heap
+        other
+            .fire_at
+            .total_cmp(&self.fire_at)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// A live scheduled action.
+struct PendingAction {
+    seq: u64,
+    fire_at: f64,
+    payload: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduledActionRecord {
+    action_id: u64,
+    fire_at: f64,
+    payload: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SchedulerSnapshot {
+    version: u32,
+    actions: Vec<ScheduledActionRecord>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    heap: std::collections::BinaryHeap<ScheduledEntry>,
+    pending: HashMap<u64, PendingAction>,
+    next_seq: u64,
+}
+
+impl SchedulerState {
+    fn schedule(&mut self, action_id: u64, fire_at: f64, payload: String) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(ScheduledEntry { fire_at, seq, action_id });
+        self.pending.insert(action_id, PendingAction { seq, fire_at, payload });
+        self.compact();
+    }
+
+    /// Rebuild the heap once cancelled/replaced entries dominate it.
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.pending.len() + 64 {
+            let pending = &self.pending;
+            self.heap.retain(|entry| {
+                pending
+                    .get(&entry.action_id)
+                    .is_some_and(|action| action.seq == entry.seq)
+            });
+        }
+    }
+}
+
+/// Persistent-friendly scheduler for timed moderation actions.
+/// Actions are keyed by `action_id` (rescheduling an id replaces it) and fire
+/// in (fire time, scheduling order) order. Cancelled entries are skipped lazily.
+#[pyclass]
+struct ActionScheduler {
+    state: Mutex<SchedulerState>,
+}
+
+#[pymethods]
+impl ActionScheduler {
+    #[new]
+    fn new() -> Self {
+        ActionScheduler {
+            state: Mutex::new(SchedulerState::default()),
+        }
+    }
+
+    /// Schedule (or reschedule) an action to fire at `fire_at_ts`.
+    fn schedule(&self, action_id: u64, fire_at_ts: f64, payload: String) -> PyResult<()> {
+        check_fire_at(action_id, fire_at_ts)?;
+        self.lock().schedule(action_id, fire_at_ts, payload);
+        Ok(())
+    }
+
+    /// Cancel an action. Returns true if it was pending.
+    fn cancel(&self, action_id: u64) -> bool {
+        self.lock().pending.remove(&action_id).is_some()
+    }
+
+    /// Pop every action due at `now_ts` as (action_id, payload), earliest first.
+    /// Each action is returned at most once.
+    fn due(&self, now_ts: f64) -> Vec<(u64, String)> {
+        let mut state = self.lock();
+        let mut fired = Vec::new();
+        while state.heap.peek().is_some_and(|top| top.fire_at <= now_ts) {
+            let Some(entry) = state.heap.pop() else { break };
+            let live = state
+                .pending
+                .get(&entry.action_id)
+                .is_some_and(|action| action.seq == entry.seq);
+            if live {
+                if let Some(action) = state.pending.remove(&entry.action_id) {
+                    fired.push((entry.action_id, action.payload));
+                }
+            }
+        }
+        fired
+    }
+
+    /// Fire time of a pending action, if any.
+    fn fire_time(&self, action_id: u64) -> Option<f64> {
+        self.lock().pending.get(&action_id).map(|action| action.fire_at)
+    }
+
+    /// Serialize pending actions as versioned JSON, in firing order.
+    fn export(&self) -> PyResult<String> {
+        let state = self.lock();
+        let mut actions: Vec<(&u64, &PendingAction)> = state.pending.iter().collect();
+        actions.sort_by(|a, b| a.1.fire_at.total_cmp(&b.1.fire_at).then(a.1.seq.cmp(&b.1.seq)));
+        let snapshot = SchedulerSnapshot {
+            version: SCHEDULER_STATE_VERSION,
+            actions: actions
+                .into_iter()
+                .map(|(&action_id, action)| ScheduledActionRecord {
+                    action_id,
+                    fire_at: action.fire_at,
+                    payload: action.payload.clone(),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&snapshot).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!("Failed to export schedule: {}", e))
+        })
+    }
+
+    /// Load actions from `export` output, keeping their relative order.
+    /// Returns the number of actions scheduled. Nothing is scheduled if any
+    /// action is invalid.
+    fn load(&self, data: &str) -> PyResult<usize> {
+        let probe: SnapshotVersion = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid schedule: {}", e))
+        })?;
+        if probe.version != SCHEDULER_STATE_VERSION {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unsupported schedule version {} (expected {})",
+                probe.version, SCHEDULER_STATE_VERSION
+            )));
+        }
+        let snapshot: SchedulerSnapshot = serde_json::from_str(data).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid schedule: {}", e))
+        })?;
+        for record in &snapshot.actions {
+            check_fire_at(record.action_id, record.fire_at)?;
+        }
+        let mut state = self.lock();
+        let count = snapshot.actions.len();
+        for record in snapshot.actions {
+            state.schedule(record.action_id, record.fire_at, record.payload);
+        }
+        Ok(count)
+    }
+
+    fn __len__(&self) -> usize {
+        self.lock().pending.len()
+    }
+}
+
+/// NaN would sort ahead of every real time and block `due()` forever.
+fn check_fire_at(action_id: u64, fire_at_ts: f64) -> PyResult<()> {
+    if fire_at_ts.is_finite() {
+        Ok(())
+    } else {
+        Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+            "fire_at_ts for action {} must be a finite timestamp",
+            action_id
+        )))
+    }
+}
+
+impl ActionScheduler {
+    fn lock(&self) -> std::sync::MutexGuard<'_, SchedulerState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ============================================
+// Conversation history (chat prompt context)
+// ============================================
+
+/// One remembered message.
+struct HistoryMessage {
+    user_id: u64,
+    username: String,
+    content: String,
+    ts: f64,
+}
+
+impl HistoryMessage {
+    /// Rough footprint: the struct plus its string contents.
+    fn approx_bytes(&self) -> usize {
+        size_of::<HistoryMessage>() + self.username.len() + self.content.len()
+    }
+}
+
+/// A channel's recent messages, oldest first.
+#[derive(Default)]
+struct ChannelHistory {
+    messages: VecDeque<HistoryMessage>,
+    bytes: usize,
+    /// Activity tick of the latest push; the channel's key in `by_activity`.
+    last_active: u64,
+}
+
+#[derive(Default)]
+struct HistoryState {
+    channels: HashMap<u64, ChannelHistory>,
+    /// Activity tick -> channel, least recently active first.
+    by_activity: BTreeMap<u64, u64>,
+    tick: u64,
+    messages: usize,
+    bytes: usize,
+}
+
+impl HistoryState {
+    fn remove_channel(&mut self, channel_id: u64) -> bool {
+        let Some(channel) = self.channels.remove(&channel_id) else {
+            return false;
+        };
+        self.by_activity.remove(&channel.last_active);
+        self.messages -= channel.messages.len();
+        self.bytes -= channel.bytes;
+        true
+    }
+}
+
+/// Per-channel ring buffers of recent messages for the chat prompt.
+/// Each channel keeps at most `per_channel` messages; with `max_bytes`, the
+/// least recently active channels are evicted once the estimated total
+/// goes over budget.
+#[pyclass]
+struct ConversationHistory {
+    per_channel: usize,
+    max_bytes: Option<usize>,
+    state: Mutex<HistoryState>,
+}
+
+#[pymethods]
+impl ConversationHistory {
+    #[new]
+    #[pyo3(signature = (per_channel = 50, max_bytes = None))]
+    fn new(per_channel: usize, max_bytes: Option<usize>) -> PyResult<Self> {
+        if per_channel == 0 || max_bytes == Some(0) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "per_channel and max_bytes must be positive",
+            ));
+        }
+        Ok(ConversationHistory {
+            per_channel,
+            max_bytes,
+            state: Mutex::new(HistoryState::default()),
+        })
+    }
+
+    /// Remember a message, dropping the channel's oldest past `per_channel`
+    /// and then whole idle channels past `max_bytes`.
+    fn push(&self, channel_id: u64, user_id: u64, username: String, content: String, ts: f64) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        state.tick += 1;
+        let tick = state.tick;
+        let channel = state.channels.entry(channel_id).or_default();
+        state.by_activity.remove(&channel.last_active);
+        channel.last_active = tick;
+        state.by_activity.insert(tick, channel_id);
+
+        let message = HistoryMessage { user_id, username, content, ts };
+        let added = message.approx_bytes();
+        channel.messages.push_back(message);
+        channel.bytes += added;
+        state.messages += 1;
+        state.bytes += added;
+        while channel.messages.len() > self.per_channel {
+            if let Some(old) = channel.messages.pop_front() {
+                channel.bytes -= old.approx_bytes();
+                state.messages -= 1;
+                state.bytes -= old.approx_bytes();
+            }
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            while state.bytes > max_bytes {
+                match state.by_activity.first_key_value() {
+                    Some((_, &oldest)) if oldest != channel_id => {
+                        state.remove_channel(oldest);
+                    }
+                    // Only this channel is left: trim it, keeping the newest message
+                    _ => {
+                        let Some(channel) = state.channels.get_mut(&channel_id) else { break };
+                        if channel.messages.len() <= 1 {
+                            break;
+                        }
+                        if let Some(old) = channel.messages.pop_front() {
+                            channel.bytes -= old.approx_bytes();
+                            state.messages -= 1;
+                            state.bytes -= old.approx_bytes();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recent messages as (user_id, username, content, ts), oldest first:
+    /// the newest `max_messages`, limited to those at most `max_age_secs`
+    /// older than `now_ts` (default: the channel's newest message).
+    #[pyo3(signature = (channel_id, max_messages = None, max_age_secs = None, now_ts = None))]
+    fn get<'py>(
+        &self,
+        py: Python<'py>,
+        channel_id: u64,
+        max_messages: Option<usize>,
+        max_age_secs: Option<f64>,
+        now_ts: Option<f64>,
+    ) -> &'py PyList {
+        let state = self.lock();
+        let Some(channel) = state.channels.get(&channel_id) else {
+            return PyList::empty(py);
+        };
+        let newest = channel.messages.back().map_or(0.0, |m| m.ts);
+        let cutoff = max_age_secs.map(|age| now_ts.unwrap_or(newest) - age);
+        let recent = channel
+            .messages
+            .iter()
+            .rev()
+            .take_while(|m| cutoff.is_none_or(|cutoff| m.ts >= cutoff))
+            .take(max_messages.unwrap_or(usize::MAX))
+            .count();
+        let skip = channel.messages.len() - recent;
+        PyList::new(
+            py,
+            channel
+                .messages
+                .iter()
+                .skip(skip)
+                .map(|m| (m.user_id, m.username.as_str(), m.content.as_str(), m.ts).to_object(py)),
+        )
+    }
+
+    /// Forget one channel, or every channel when `channel_id` is None.
+    /// Returns whether anything was removed.
+    #[pyo3(signature = (channel_id = None))]
+    fn clear(&self, channel_id: Option<u64>) -> bool {
+        let mut state = self.lock();
+        match channel_id {
+            Some(channel_id) => state.remove_channel(channel_id),
+            None => {
+                let had_any = !state.channels.is_empty();
+                *state = HistoryState::default();
+                had_any
+            }
+        }
+    }
+
+    /// Stored channels and messages. `approx_bytes` counts each message's
+    /// struct and string contents; container overhead is ignored.
+    fn stats(&self) -> HashMap<&'static str, usize> {
+        let state = self.lock();
+        HashMap::from([
+            ("channels", state.channels.len()),
+            ("messages", state.messages),
+            ("approx_bytes", state.bytes),
+        ])
+    }
+
+    /// Number of messages stored across all channels.
+    fn __len__(&self) -> usize {
+        self.lock().messages
+    }
+}
+
+impl ConversationHistory {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HistoryState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ============================================
+// Transcription dedup
+// ============================================
+
+/// Similarity of two strings in [0, 1]: one minus their character edit
+/// distance over the longer length. Returns 0.0 early once the lengths alone
+/// rule out reaching `threshold`.
+fn text_similarity(a: &[char], b: &[char], threshold: f64) -> f64 {
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    if 1.0 - (a.len().abs_diff(b.len()) as f64) / (longest as f64) < threshold {
+        return 0.0;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, &ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != cb);
+            current[j + 1] = (previous[j] + cost).min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+/// Recent (ts, normalized content) for one user, oldest first.
+type RecentTranscripts = VecDeque<(f64, Vec<char>)>;
+
+/// Flags transcriptions that nearly repeat one the same user said moments
+/// ago (voice receiver replays). Content is compared after `normalize_text`,
+/// and utterances shorter than `min_chars` are never flagged so short
+/// replies like "yeah" can repeat freely.
+#[pyclass]
+struct TranscriptionDeduper {
+    min_chars: usize,
+    max_per_user: usize,
+    recent: DashMap<(u64, u64), RecentTranscripts>,
+}
+
+#[pymethods]
+impl TranscriptionDeduper {
+    #[new]
+    #[pyo3(signature = (min_chars = 8, max_per_user = 32))]
+    fn new(min_chars: usize, max_per_user: usize) -> PyResult<Self> {
+        if max_per_user == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "max_per_user must be at least 1",
+            ));
+        }
+        Ok(TranscriptionDeduper {
+            min_chars,
+            max_per_user,
+            recent: DashMap::new(),
+        })
+    }
+
+    /// True if `content` is at least `similarity` similar to something the
+    /// user said within `window_secs`; otherwise it is remembered and False
+    /// is returned. Entries older than the window are pruned on each call.
+    #[pyo3(signature = (guild_id, user_id, content, now_ts, window_secs = 20.0, similarity = 0.9))]
+    fn is_duplicate(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        content: &str,
+        now_ts: f64,
+        window_secs: f64,
+        similarity: f64,
+    ) -> PyResult<bool> {
+        if !(0.0..=1.0).contains(&similarity) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "similarity must be between 0 and 1",
+            ));
+        }
+        let normalized: Vec<char> = normalize_str(content, true, true, true, false).chars().collect();
+        let mut recent = self.recent.entry((guild_id, user_id)).or_default();
+        let cutoff = now_ts - window_secs;
+        while recent.front().is_some_and(|(ts, _)| *ts < cutoff) {
+            recent.pop_front();
+        }
+        if normalized.len() < self.min_chars {
+            return Ok(false);
+        }
+        let duplicate = recent
+            .iter()
+            .any(|(_, earlier)| text_similarity(earlier, &normalized, similarity) >= similarity);
+        if !duplicate {
+            if recent.len() == self.max_per_user {
+                recent.pop_front();
+            }
+            recent.push_back((now_ts, normalized));
+        }
+        Ok(duplicate)
+    }
+
+    /// Drop users with nothing newer than `max_age_secs`. Returns the number removed.
+    #[pyo3(signature = (now_ts, max_age_secs = 60.0))]
+    fn cleanup(&self, now_ts: f64, max_age_secs: f64) -> usize {
+        let cutoff = now_ts - max_age_secs;
+        let before = self.recent.len();
+        self.recent
+            .retain(|_, recent| recent.back().is_some_and(|(ts, _)| *ts >= cutoff));
+        before.saturating_sub(self.recent.len())
+    }
+
+    /// Number of (guild, user) pairs tracked.
+    fn __len__(&self) -> usize {
+        self.recent.len()
+    }
+}
+
+// ============================================
+// Transcript search index
+// ============================================
+
+/// Index terms of `text`: accent-stripped, case-folded Unicode words.
+fn index_terms(text: &str) -> Vec<String> {
+    normalize_str(text, true, true, true, true)
+        .unicode_words()
+        .map(str::to_owned)
+        .collect()
+}
+
+struct IndexedDoc {
+    user_id: u64,
+    ts: f64,
+    seq: u64,
+    terms: Vec<String>,
+}
+
+#[derive(Default)]
+struct IndexState {
+    docs: HashMap<u64, IndexedDoc>,
+    /// Term -> doc id -> occurrences.
+    postings: HashMap<String, HashMap<u64, u32>>,
+    /// Insertion seq -> doc id, oldest first.
+    by_age: BTreeMap<u64, u64>,
+    seq: u64,
+    newest_ts: f64,
+}
+
+impl IndexState {
+    fn remove(&mut self, id: u64) -> bool {
+        let Some(doc) = self.docs.remove(&id) else {
+            return false;
+        };
+        self.by_age.remove(&doc.seq);
+        for term in &doc.terms {
+            if let Some(posting) = self.postings.get_mut(term) {
+                posting.remove(&id);
+                if posting.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        true
+    }
+
+    /// Matching docs as (id, score), best first. Every query term must
+    /// occur; score is log term frequency weighted by IDF, plus a bonus when
+    /// the terms appear contiguously in query order and a recency boost that
+    /// halves for each hour older than the newest document.
+    fn search(&self, query: &[String], user_id: Option<u64>, limit: usize) -> Vec<(u64, f64)> {
+        let mut unique: Vec<&String> = query.iter().collect();
+        unique.sort();
+        unique.dedup();
+        let mut postings = Vec::with_capacity(unique.len());
+        for term in unique {
+            match self.postings.get(term) {
+                Some(posting) => postings.push(posting),
+                None => return Vec::new(),
+            }
+        }
+        postings.sort_by_key(|posting| posting.len());
+        let Some((rarest, rest)) = postings.split_first() else {
+            return Vec::new();
+        };
+        let total = self.docs.len() as f64;
+        let mut hits: Vec<(u64, f64, f64)> = rarest
+            .keys()
+            .filter(|id| rest.iter().all(|posting| posting.contains_key(id)))
+            .filter_map(|id| self.docs.get(id).map(|doc| (*id, doc)))
+            .filter(|(_, doc)| user_id.is_none_or(|user| doc.user_id == user))
+            .map(|(id, doc)| {
+                let relevance: f64 = postings
+                    .iter()
+                    .map(|posting| {
+                        let tf = f64::from(posting[&id]);
+                        (1.0 + tf.ln()) * (1.0 + total / posting.len() as f64).ln()
+                    })
+                    .sum();
+                let phrase = if doc.terms.windows(query.len()).any(|window| window == query) {
+                    1.0
+                } else {
+                    0.0
+                };
+                let age_hours = ((self.newest_ts - doc.ts) / 3600.0).max(0.0);
+                (id, relevance + phrase + 0.5f64.powf(age_hours), doc.ts)
+            })
+            .collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.total_cmp(&a.2)).then(b.0.cmp(&a.0)));
+        hits.truncate(limit);
+        hits.into_iter().map(|(id, score, _)| (id, score)).collect()
+    }
+}
+
+/// In-memory inverted index over recent transcriptions for quote lookups.
+/// Holds at most `max_docs` documents, evicting the oldest added first.
+/// Searches run without the GIL and may overlap each other; adds wait for
+/// searches in progress.
+#[pyclass]
+struct TranscriptIndex {
+    max_docs: usize,
+    state: RwLock<IndexState>,
+}
+
+impl TranscriptIndex {
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, IndexState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, IndexState> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[pymethods]
+impl TranscriptIndex {
+    #[new]
+    #[pyo3(signature = (max_docs = 50_000))]
+    fn new(max_docs: usize) -> PyResult<Self> {
+        if max_docs == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "max_docs must be at least 1",
+            ));
+        }
+        Ok(TranscriptIndex {
+            max_docs,
+            state: RwLock::new(IndexState::default()),
+        })
+    }
+
+    /// Index `content` under `id`, replacing any document already there.
+    fn add(&self, py: Python<'_>, id: u64, user_id: u64, content: &str, ts: f64) {
+        let terms = index_terms(content);
+        py.allow_threads(|| {
+            let mut state = self.write();
+            state.remove(id);
+            for term in &terms {
+                *state.postings.entry(term.clone()).or_default().entry(id).or_insert(0) += 1;
+            }
+            state.seq += 1;
+            let seq = state.seq;
+            state.by_age.insert(seq, id);
+            state.newest_ts = state.newest_ts.max(ts);
+            state.docs.insert(id, IndexedDoc { user_id, ts, seq, terms });
+            while state.docs.len() > self.max_docs {
+                let Some((_, oldest)) = state.by_age.pop_first() else {
+                    break;
+                };
+                state.remove(oldest);
+            }
+        });
+    }
+
+    /// Documents containing every word of `phrase`, as (id, score) best
+    /// first. Equal scores order newer documents first, then higher ids.
+    #[pyo3(signature = (phrase, user_id = None, limit = 10))]
+    fn search(&self, py: Python<'_>, phrase: &str, user_id: Option<u64>, limit: usize) -> Vec<(u64, f64)> {
+        let query = index_terms(phrase);
+        py.allow_threads(|| self.read().search(&query, user_id, limit))
+    }
+
+    /// Remove a document. Returns whether it was indexed.
+    fn remove(&self, id: u64) -> bool {
+        self.write().remove(id)
+    }
+
+    fn clear(&self) {
+        *self.write() = IndexState::default();
+    }
+
+    fn __len__(&self) -> usize {
+        self.read().docs.len()
+    }
+}
+
+// ============================================
+// LRU cache
+// ============================================
+
+/// Cache key: a Python int or str. As in a dict, `True` and `1` are the same key.
+#[derive(FromPyObject, Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Int(i128),
+    Str(String),
+}
+
+#[derive(Default)]
+struct LruState {
+    /// Value and the tick it was last used at.
+    entries: HashMap<CacheKey, (Py<PyAny>, u64)>,
+    /// Use tick -> key, least recently used first.
+    by_use: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: usize,
+    misses: usize,
+    evictions: usize,
+}
+
+impl LruState {
+    fn touch(&mut self, key: &CacheKey) -> Option<&Py<PyAny>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.by_use.remove(used);
+        *used = tick;
+        self.by_use.insert(tick, key.clone());
+        Some(value)
+    }
+}
+
+/// Least-recently-used cache of Python objects keyed by int or str.
+/// Lookups refresh recency; inserting past `capacity` evicts the least
+/// recently used entry. Values are released after the lock is dropped, so a
+/// `__del__` that touches the cache cannot deadlock it.
+#[pyclass]
+struct LruCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+#[pymethods]
+impl LruCache {
+    #[new]
+    fn new(capacity: usize) -> PyResult<Self> {
+        if capacity == 0 {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "capacity must be at least 1",
+            ));
+        }
+        Ok(LruCache {
+            capacity,
+            state: Mutex::new(LruState::default()),
+        })
+    }
+
+    /// Cached value for `key` (marking it most recently used), else `default`.
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, py: Python<'_>, key: CacheKey, default: Option<Py<PyAny>>) -> Option<Py<PyAny>> {
+        let mut state = self.lock();
+        match state.touch(&key).map(|value| value.clone_ref(py)) {
+            Some(value) => {
+                state.hits += 1;
+                Some(value)
+            }
+            None => {
+                state.misses += 1;
+                default
+            }
+        }
+    }
+
+    /// Insert or replace `key`, evicting the least recently used entry if full.
+    fn put(&self, key: CacheKey, value: Py<PyAny>) {
+        let mut released = Vec::new();
+        {
+            let mut state = self.lock();
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some((old, used)) = state.entries.insert(key.clone(), (value, tick)) {
+                state.by_use.remove(&used);
+                released.push(old);
+            }
+            state.by_use.insert(tick, key);
+            while state.entries.len() > self.capacity {
+                let Some((_, oldest)) = state.by_use.pop_first() else { break };
+                if let Some((old, _)) = state.entries.remove(&oldest) {
+                    state.evictions += 1;
+                    released.push(old);
+                }
+            }
+        }
+        drop(released);
+    }
+
+    /// Remove `key`, returning its value (or `default` if absent).
+    #[pyo3(signature = (key, default = None))]
+    fn pop(&self, key: CacheKey, default: Option<Py<PyAny>>) -> Option<Py<PyAny>> {
+        let mut state = self.lock();
+        match state.entries.remove(&key) {
+            Some((value, used)) => {
+                state.by_use.remove(&used);
+                Some(value)
+            }
+            None => default,
+        }
+    }
+
+    fn clear(&self) {
+        let entries = {
+            let mut state = self.lock();
+            state.by_use.clear();
+            std::mem::take(&mut state.entries)
+        };
+        drop(entries);
+    }
+
+    /// `hits`, `misses`, `evictions`, `size`, and `capacity`.
+    fn stats(&self) -> HashMap<&'static str, usize> {
+        let state = self.lock();
+        HashMap::from([
+            ("hits", state.hits),
+            ("misses", state.misses),
+            ("evictions", state.evictions),
+            ("size", state.entries.len()),
+            ("capacity", self.capacity),
+        ])
+    }
+
+    /// Membership test; does not count as a use.
+    fn __contains__(&self, key: CacheKey) -> bool {
+        self.lock().entries.contains_key(&key)
+    }
+
+    fn __len__(&self) -> usize {
+        self.lock().entries.len()
+    }
+}
+
+impl LruCache {
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// ============================================
+// TTL cache
+// ============================================
+
+/// Expiry heap entry; ordered so the earliest `expires_at` (then lowest `seq`) pops first.
+struct ExpiryEntry {
+    expires_at: f64,
+    seq: u64,
+    key: CacheKey,
+}
+
+impl PartialEq for ExpiryEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for ExpiryEntry {}
+
+impl PartialOrd for ExpiryEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ExpiryEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Reversed: BinaryHeap is a max-heap
+        other
+            .expires_at
+            .total_cmp(&self.expires_at)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// A live cached value.
+struct TtlEntry {
+    value: Py<PyAny>,
+    expires_at: f64,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct TtlState {
+    heap: std::collections::BinaryHeap<ExpiryEntry>,
+    entries: HashMap<CacheKey, TtlEntry>,
+    next_seq: u64,
+}
+
+impl TtlState {
+    /// Remove every entry expired at `now_ts`, returning their values.
+    fn purge(&mut self, now_ts: f64) -> Vec<Py<PyAny>> {
+        let mut expired = Vec::new();
+        while self.heap.peek().is_some_and(|top| top.expires_at <= now_ts) {
+            let Some(entry) = self.heap.pop() else { break };
+            let live = self
+                .entries
+                .get(&entry.key)
+                .is_some_and(|cached| cached.seq == entry.seq);
+            if live {
+                if let Some(cached) = self.entries.remove(&entry.key) {
+                    expired.push(cached.value);
+                }
+            }
+        }
+        expired
+    }
+
+    /// Rebuild the heap once replaced or popped entries dominate it.
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.entries.len() + 64 {
+            let entries = &self.entries;
+            self.heap
+                .retain(|entry| entries.get(&entry.key).is_some_and(|cached| cached.seq == entry.seq));
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, for callers that omit `now_ts`.
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+/// Time-based cache of Python objects keyed by int or str. Each entry has its
+/// own TTL (falling back to `default_ttl_secs`); expired entries read as
+/// missing and are removed on `get`, on `put`, and by `purge_expired()`.
+/// `now_ts` defaults to the system clock.
+#[pyclass]
+struct TtlCache {
+    default_ttl_secs: Option<f64>,
+    state: Mutex<TtlState>,
+}
+
+#[pymethods]
+impl TtlCache {
+    #[new]
+    #[pyo3(signature = (default_ttl_secs = None))]
+    fn new(default_ttl_secs: Option<f64>) -> PyResult<Self> {
+        if let Some(ttl) = default_ttl_secs {
+            check_ttl(ttl)?;
+        }
+        Ok(TtlCache {
+            default_ttl_secs,
+            state: Mutex::new(TtlState::default()),
+        })
+    }
+
+    /// Insert or replace `key`, expiring `ttl_secs` after `now_ts`.
+    #[pyo3(signature = (key, value, ttl_secs = None, now_ts = None))]
+    fn put(&self, key: CacheKey, value: Py<PyAny>, ttl_secs: Option<f64>, now_ts: Option<f64>) -> PyResult<()> {
+        let ttl = ttl_secs.or(self.default_ttl_secs).ok_or_else(|| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>("ttl_secs is required without a default_ttl_secs")
+        })?;
+        check_ttl(ttl)?;
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let released = {
+            let mut state = self.lock();
+            let mut released = state.purge(now_ts);
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            let expires_at = now_ts + ttl;
+            state.heap.push(ExpiryEntry { expires_at, seq, key: key.clone() });
+            if let Some(old) = state.entries.insert(key, TtlEntry { value, expires_at, seq }) {
+                released.push(old.value);
+            }
+            state.compact();
+            released
+        };
+        drop(released);
+        Ok(())
+    }
+
+    /// Value for `key` if it has not expired at `now_ts`; an expired entry is removed.
+    #[pyo3(signature = (key, now_ts = None, default = None))]
+    fn get(&self, py: Python<'_>, key: CacheKey, now_ts: Option<f64>, default: Option<Py<PyAny>>) -> Option<Py<PyAny>> {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let expired = {
+            let mut state = self.lock();
+            match state.entries.get(&key) {
+                Some(cached) if cached.expires_at > now_ts => return Some(cached.value.clone_ref(py)),
+                Some(_) => state.entries.remove(&key),
+                None => None,
+            }
+        };
+        drop(expired);
+        default
+    }
+
+    /// Seconds until `key` expires, or None if it is missing or expired.
+    #[pyo3(signature = (key, now_ts = None))]
+    fn ttl(&self, key: CacheKey, now_ts: Option<f64>) -> Option<f64> {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let state = self.lock();
+        state
+            .entries
+            .get(&key)
+            .map(|cached| cached.expires_at - now_ts)
+            .filter(|remaining| *remaining > 0.0)
+    }
+
+    /// Remove `key`, returning its value (or `default` if absent). Expiry is
+    /// not checked.
+    #[pyo3(signature = (key, default = None))]
+    fn pop(&self, key: CacheKey, default: Option<Py<PyAny>>) -> Option<Py<PyAny>> {
+        self.lock().entries.remove(&key).map(|cached| cached.value).or(default)
+    }
+
+    /// Remove everything expired at `now_ts`. Returns the number removed.
+    #[pyo3(signature = (now_ts = None))]
+    fn purge_expired(&self, now_ts: Option<f64>) -> usize {
+        let now_ts = now_ts.unwrap_or_else(unix_now);
+        let expired = {
+            let mut state = self.lock();
+            let expired = state.purge(now_ts);
+            state.compact();
+            expired
+        };
+        expired.len()
+    }
+
+    fn clear(&self) {
+        let entries = {
+            let mut state = self.lock();
+            state.heap.clear();
+            std::mem::take(&mut state.entries)
+        };
+        drop(entries);
+    }
+
+    /// Number of stored entries, including expired ones not yet purged.
+    fn __len__(&self) -> usize {
+        self.lock().entries.len()
+    }
+}
+
+impl TtlCache {
+    fn lock(&self) -> std::sync::MutexGuard<'_, TtlState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn check_ttl(ttl: f64) -> PyResult<()> {
+    if ttl.is_nan() || ttl <= 0.0 {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            "ttl_secs must be positive",
+        ));
+    }
+    Ok(())
+}
+
+// ============================================
+// Core metrics (process-wide counters for scrapes)
+// ============================================
+
+/// Monotonic counters reported by `core_metrics`. Names are stable, end in
+/// `_total`, and map 1:1 to Prometheus counters.
+#[derive(Clone, Copy)]
+enum CoreCounter {
+    SpamChecks,
+    SpamFlags,
+    SpamDecayChecks,
+    SpamDecayFlags,
+    AttachmentChecks,
+    AttachmentFlags,
+    RaidFlags,
+    ChatMessages,
+    ChatTriggers,
+    ChatCooldownSuppressed,
+    PipelineMessages,
+    PipelineOverruns,
+    DbOpsQueued,
+    DbOpsCommitted,
+    DbOpsFailed,
+    DbOpsDropped,
+}
+
+impl CoreCounter {
+    const ALL: [CoreCounter; 16] = [
+        CoreCounter::SpamChecks,
+        CoreCounter::SpamFlags,
+        CoreCounter::SpamDecayChecks,
+        CoreCounter::SpamDecayFlags,
+        CoreCounter::AttachmentChecks,
+        CoreCounter::AttachmentFlags,
+        CoreCounter::RaidFlags,
+        CoreCounter::ChatMessages,
+        CoreCounter::ChatTriggers,
+        CoreCounter::ChatCooldownSuppressed,
+        CoreCounter::PipelineMessages,
+        CoreCounter::PipelineOverruns,
+        CoreCounter::DbOpsQueued,
+        CoreCounter::DbOpsCommitted,
+        CoreCounter::DbOpsFailed,
+        CoreCounter::DbOpsDropped,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            CoreCounter::SpamChecks => "spam_checks_total",
+            CoreCounter::SpamFlags => "spam_flags_total",
+            CoreCounter::SpamDecayChecks => "spam_decay_checks_total",
+            CoreCounter::SpamDecayFlags => "spam_decay_flags_total",
+            CoreCounter::AttachmentChecks => "attachment_checks_total",
+            CoreCounter::AttachmentFlags => "attachment_flags_total",
+            CoreCounter::RaidFlags => "raid_flags_total",
+            CoreCounter::ChatMessages => "chat_messages_total",
+            CoreCounter::ChatTriggers => "chat_triggers_total",
+            CoreCounter::ChatCooldownSuppressed => "chat_cooldown_suppressed_total",
+            CoreCounter::PipelineMessages => "pipeline_messages_total",
+            CoreCounter::PipelineOverruns => "pipeline_overruns_total",
+            CoreCounter::DbOpsQueued => "db_ops_queued_total",
+            CoreCounter::DbOpsCommitted => "db_ops_committed_total",
+            CoreCounter::DbOpsFailed => "db_ops_failed_total",
+            CoreCounter::DbOpsDropped => "db_ops_dropped_total",
+        }
+    }
+}
+
+/// Counter values, indexed by `CoreCounter`. Relaxed atomics, so hot paths
+/// never lock; a scrape may see counters a few increments apart.
+static CORE_COUNTERS: [AtomicU64; CoreCounter::ALL.len()] = [const { AtomicU64::new(0) }; CoreCounter::ALL.len()];
+
+fn bump(counter: CoreCounter, n: u64) {
+    CORE_COUNTERS[counter as usize].fetch_add(n, Ordering::Relaxed);
+}
+
+/// One cheap snapshot of the Rust core for a metrics scrape:
+/// `{"counters": {...}, "gauges": {...}}`. Counters (see `CoreCounter`) are
+/// process-wide across all trackers, pipelines, and DB writers and only go
+/// up, except through `reset_counters`. Gauges are current tracker map sizes.
+/// Reading never resets anything.
+#[pyfunction]
+fn core_metrics(py: Python<'_>) -> PyResult<&PyDict> {
+    let counters = PyDict::new(py);
+    for counter in CoreCounter::ALL {
+        counters.set_item(counter.name(), CORE_COUNTERS[counter as usize].load(Ordering::Relaxed))?;
+    }
+    let gauges = PyDict::new(py);
+    gauges.set_item("tracked_users", SPAM_TIMESTAMPS.len())?;
+    gauges.set_item("spam_decay_users", SPAM_DECAY.len())?;
+    gauges.set_item("attachment_users", ATTACHMENTS.len())?;
+    gauges.set_item("violation_users", VIOLATIONS.len())?;
+    gauges.set_item("spam_exempt", SPAM_EXEMPT.len())?;
+    gauges.set_item("tracked_guilds", CHAT_ACTIVITY.len())?;
+    gauges.set_item("tracked_channels", CHANNEL_ACTIVITY.len())?;
+    gauges.set_item("cooldown_entries", CHAT_COOLDOWNS.len() + CHANNEL_COOLDOWNS.len())?;
+    gauges.set_item("slowmode_channels", SLOWMODE_LEVELS.len())?;
+    gauges.set_item("join_guilds", GUILD_JOINS.len())?;
+    gauges.set_item("content_guilds", GUILD_CONTENT.len())?;
+    gauges.set_item("quiet_hours_guilds", QUIET_HOURS.len())?;
+    let metrics = PyDict::new(py);
+    metrics.set_item("counters", counters)?;
+    metrics.set_item("gauges", gauges)?;
+    Ok(metrics)
+}
+
+/// Zero every `core_metrics` counter. Separate from `core_metrics` so a
+/// scrape can never reset by accident; Prometheus treats the drop as a
+/// counter reset.
+#[pyfunction]
+fn reset_counters() {
+    for counter in &CORE_COUNTERS {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+// ============================================
+// Build info and feature probing
+// ============================================
+
+/// An optional capability compiled into this build. `name` is the cargo
+/// feature that gates it; build.rs fails the build when a feature tested in
+/// a `cfg` has no entry here.
+struct Feature {
+    name: &'static str,
+    summary: &'static str,
+}
+
+/// Registry behind `has_feature()`; each entry only exists in builds with
+/// its feature enabled.
+static FEATURES: &[Feature] = &[
+    #[cfg(feature = "sqlite")]
+    Feature { name: "sqlite", summary: "native SQLite writes for DatabaseWriter(db_path=...)" },
+    #[cfg(feature = "asyncio")]
+    Feature { name: "asyncio", summary: "DatabaseWriter.flush_async() and close_async()" },
+];
+
+/// Whether the optional capability `name` (see `build_info()["capabilities"]`)
+/// was compiled in. Unknown names are simply False, so callers can probe for
+/// capabilities newer than the installed module.
+#[pyfunction]
+fn has_feature(name: &str) -> bool {
+    FEATURES.iter().any(|feature| feature.name == name)
+}
+
+/// What the loaded module was built from, to catch a stale extension:
+/// crate `version`, `git_commit` (with `-dirty` for uncommitted changes, or
+/// "unknown" outside git), `build_timestamp` (unix seconds), `profile`,
+/// cargo `features`, `capabilities` (name to summary, as `has_feature()`
+/// sees them), `pyo3_version`, and the Python it was built for
+/// (`python_version`, `python_implementation`, `abi3`).
+#[pyfunction]
+fn build_info(py: Python<'_>) -> PyResult<&PyDict> {
+    let info = PyDict::new(py);
+    info.set_item("version", env!("CARGO_PKG_VERSION"))?;
+    info.set_item("git_commit", env!("GUILDEST_GIT_COMMIT"))?;
+    info.set_item("build_timestamp", env!("GUILDEST_BUILD_TIMESTAMP").parse::<u64>().ok())?;
+    info.set_item("profile", if cfg!(debug_assertions) { "debug" } else { "release" })?;
+    let features: Vec<&str> = env!("GUILDEST_FEATURES").split(',').filter(|f| !f.is_empty()).collect();
+    info.set_item("features", features)?;
+    let capabilities = PyDict::new(py);
+    for feature in FEATURES {
+        capabilities.set_item(feature.name, feature.summary)?;
+    }
+    info.set_item("capabilities", capabilities)?;
+    info.set_item("pyo3_version", env!("GUILDEST_PYO3_VERSION"))?;
+    info.set_item("python_version", env!("GUILDEST_PYTHON_VERSION"))?;
+    info.set_item("python_implementation", env!("GUILDEST_PYTHON_IMPLEMENTATION"))?;
+    info.set_item("abi3", env!("GUILDEST_ABI3") == "true")?;
+    Ok(info)
+}
+
+/// Python module definition
+#[pymodule]
+fn guildest_core(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(truncate, m)?)?;
+    m.add_function(wrap_pyfunction!(truncate_smart, m)?)?;
+    m.add_function(wrap_pyfunction!(pack_embed_fields, m)?)?;
+    m.add_function(wrap_pyfunction!(fits_embed, m)?)?;
+    m.add_function(wrap_pyfunction!(display_length, m)?)?;
+    m.add_function(wrap_pyfunction!(normalize_text, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_tokens, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_tokens_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(trim_to_token_budget, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_duration_secs, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_duration_checked, m)?)?;
+    m.add_function(wrap_pyfunction!(text_contains_phrase, m)?)?;
+    m.add_function(wrap_pyfunction!(text_matches_glob, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_script, m)?)?;
+    m.add_function(wrap_pyfunction!(content_fingerprint, m)?)?;
+    m.add_function(wrap_pyfunction!(hmac_fingerprint, m)?)?;
+    m.add_function(wrap_pyfunction!(char_shingles, m)?)?;
+    m.add_function(wrap_pyfunction!(shingle_jaccard, m)?)?;
+    m.add_function(wrap_pyfunction!(text_contains_phrase_approx, m)?)?;
+    m.add_function(wrap_pyfunction!(find_phrase_approx, m)?)?;
+    m.add_function(wrap_pyfunction!(weighted_choice, m)?)?;
+    m.add_function(wrap_pyfunction!(build_info, m)?)?;
+    m.add_function(wrap_pyfunction!(has_feature, m)?)?;
+    m.add_function(wrap_pyfunction!(snowflake_timestamp, m)?)?;
+    m.add_function(wrap_pyfunction!(core_metrics, m)?)?;
+    m.add_function(wrap_pyfunction!(reset_counters, m)?)?;
+    m.add_class::<ActivityTrackerRust>()?;
+    m.add_class::<EconomyEngine>()?;
+    m.add_class::<DatabaseWriter>()?;
+    m.add_class::<TranscriptionRecord>()?;
+    m.add_class::<RateLimiter>()?;
+    m.add_class::<SlidingWindowLimiter>()?;
+    m.add_class::<ActionScheduler>()?;
+    m.add_class::<PhraseMatcher>()?;
+    m.add_class::<WordFilter>()?;
+    m.add_class::<ResponsePicker>()?;
+    m.add_class::<ConversationHistory>()?;
+    m.add_class::<SpamVerdict>()?;
+    m.add_class::<PunishmentLadder>()?;
+    m.add_class::<JoinGate>()?;
+    m.add_class::<MessagePipeline>()?;
+    m.add_class::<TranscriptionDeduper>()?;
+    m.add_class::<TranscriptIndex>()?;
+    m.add_class::<LruCache>()?;
+    m.add_class::<TtlCache>()?;
+    py.import("atexit")?.call_method1("register", (wrap_pyfunction!(close_writers_at_exit, m)?,))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tracker state lives in process-wide maps and tests run in parallel,
+    // so every test uses its own guild ids and holds `shared_maps()`. Tests
+    // of whole-map methods (`retain_guilds`, `clear_all`) hold `all_maps()`.
+    static TRACKED_STATE: RwLock<()> = RwLock::new(());
+
+    fn shared_maps() -> std::sync::RwLockReadGuard<'static, ()> {
+        TRACKED_STATE.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn all_maps() -> std::sync::RwLockWriteGuard<'static, ()> {
+        TRACKED_STATE.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A tracker with the constructor defaults.
+    fn tracker() -> ActivityTrackerRust {
+        ActivityTrackerRust::new(
+            1.0, 1.0, None, 60.0, 10, 604_800.0, None, 3600.0, 10.0, 20.0, 0.75, 45.0, None, 60.0, 1000, 15.0, 10, 4,
+            1000,
+        )
+    }
+
+    /// Five messages from three users in the five seconds before `ts`: on a
+    /// quiet guild, one short of the default thresholds (6 messages, 3 users).
+    /// Rolls are forced to fail so the chatter itself never triggers.
+    fn chatter(tracker: &ActivityTrackerRust, guild_id: u64, channel_id: u64, ts: f64) {
+        for (i, user_id) in [1, 2, 3, 1, 2].into_iter().enumerate() {
+            tracker.force_next_roll(1.0);
+            let decision = tracker.chat_decision(guild_id, channel_id, user_id, ts - 5.0 + i as f64, false, false);
+            assert!(!decision.triggered());
+            // Rolls not taken (inactive or on cooldown) are dropped again
+            tracker.rng.lock().unwrap().forced_rolls.clear();
+        }
+    }
+
+    // ---- Chat trigger ----
+
+    #[test]
+    fn chat_trigger_needs_messages_and_users() {
+        let _maps = shared_maps();
+        let t = tracker();
+        let guild = 290_001;
+        // Plenty of messages but only two users
+        for i in 0..10 {
+            assert_eq!(t.chat_decision(guild, 1, 1 + i % 2, 100.0 + i as f64, false, false), ChatDecision::Inactive);
+        }
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 110.0, false, false), ChatDecision::Activity);
+    }
+
+    #[test]
+    fn chat_trigger_follows_the_roll() {
+        let _maps = shared_maps();
+        let t = tracker();
+        let guild = 290_002;
+        chatter(&t, guild, 1, 100.0);
+        t.force_next_roll(0.35);
+        assert_eq!(t.chat_decision(guild, 1, 3, 101.0, false, false), ChatDecision::Chance);
+        t.force_next_roll(0.349);
+        assert_eq!(t.chat_decision(guild, 1, 3, 102.0, false, false), ChatDecision::Activity);
+        assert_eq!(t.last_chat_trigger(guild), Some(102.0));
+    }
+
+    #[test]
+    fn chat_trigger_channel_cooldown() {
+        let _maps = shared_maps();
+        let t = tracker();
+        let guild = 290_003;
+        chatter(&t, guild, 1, 100.0);
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 101.0, false, false), ChatDecision::Activity);
+        chatter(&t, guild, 1, 145.0);
+        // The cooldown is checked before rolling, so the forced roll stays queued
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 145.9, false, false), ChatDecision::ChannelCooldown);
+        assert!((t.chat_cooldown_remaining(guild, Some(145.9), 1) - 0.1).abs() < 1e-9);
+        // Another channel in the same guild is not held back
+        assert_eq!(t.chat_decision(guild, 2, 3, 146.0, false, false), ChatDecision::Activity);
+        chatter(&t, guild, 1, 200.0);
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 201.0, false, false), ChatDecision::Activity);
+    }
+
+    #[test]
+    fn chat_batch_keeps_channels_apart() {
+        let _maps = shared_maps();
+        let t = tracker();
+        let guild = 312_001;
+        chatter(&t, guild, 1, 100.0);
+        t.force_next_roll(0.0);
+        t.force_next_roll(0.0);
+        // #general triggering does not hold back #gaming
+        let results = t.record_chat_activity_batch(vec![(guild, 1, 3, 101.0), (guild, 2, 3, 102.0)]);
+        assert_eq!(results, vec![true, true]);
+        assert!(t.chat_cooldown_remaining(guild, Some(103.0), 1) > 0.0);
+        assert_eq!(t.chat_cooldown_remaining(guild, Some(103.0), 0), 0.0);
+    }
+
+    #[test]
+    fn chat_trigger_guild_cooldown_caps_unsolicited_replies() {
+        let _maps = shared_maps();
+        let mut t = tracker();
+        t.guild_cooldown_secs = Some(120.0);
+        let guild = 290_004;
+        chatter(&t, guild, 1, 100.0);
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 101.0, false, false), ChatDecision::Activity);
+        chatter(&t, guild, 2, 150.0);
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 2, 3, 150.0, false, false), ChatDecision::GuildCooldown);
+        // Direct address ignores the guild cap
+        assert_eq!(t.chat_decision(guild, 2, 3, 151.0, true, false), ChatDecision::Mention);
+        assert_eq!(t.chat_decision(guild, 3, 3, 152.0, false, true), ChatDecision::Reply);
+    }
+
+    #[test]
+    fn chat_trigger_certain_chance_skips_the_roll() {
+        let _maps = shared_maps();
+        let t = tracker();
+        let guild = 290_005;
+        chatter(&t, guild, 1, 100.0);
+        // mention_trigger_chance defaults to 1.0, so even the worst roll is not used
+        t.force_next_roll(0.999);
+        assert_eq!(t.chat_decision(guild, 1, 3, 101.0, true, false), ChatDecision::Mention);
+        assert_eq!(t.roll(), 0.999);
+    }
+
+    #[test]
+    fn chat_trigger_seeded_rolls_repeat() {
+        let a = tracker();
+        let b = tracker();
+        a.set_rng_seed(42);
+        b.set_rng_seed(42);
+        let rolls_a: Vec<f64> = (0..100).map(|_| a.roll()).collect();
+        let rolls_b: Vec<f64> = (0..100).map(|_| b.roll()).collect();
+        assert_eq!(rolls_a, rolls_b);
+        a.set_rng_seed(43);
+        assert_ne!(rolls_a[0], a.roll());
+    }
+
+    #[test]
+    fn chat_rolls_are_uniform() {
+        let t = tracker();
+        t.set_rng_seed(0x5eed);
+        const ROLLS: usize = 100_000;
+        const BINS: usize = 10;
+        let mut bins = [0usize; BINS];
+        let mut below_chance = 0;
+        for _ in 0..ROLLS {
+            let roll = t.roll();
+            assert!((0.0..1.0).contains(&roll));
+            bins[(roll * BINS as f64) as usize] += 1;
+            below_chance += (roll < t.chat_trigger_chance) as usize;
+        }
+        let expected = (ROLLS / BINS) as f64;
+        let chi_squared: f64 = bins.iter().map(|&n| (n as f64 - expected).powi(2) / expected).sum();
+        // 9 degrees of freedom: 27.88 is the p = 0.001 critical value
+        assert!(chi_squared < 27.88, "chi-squared {} over {:?}", chi_squared, bins);
+        let rate = below_chance as f64 / ROLLS as f64;
+        assert!((rate - 0.35).abs() < 0.005, "trigger rate {}", rate);
+    }
+
+    #[test]
+    fn chat_chance_bounds() {
+        let _maps = shared_maps();
+        let mut t = tracker();
+        let guild = 291_001;
+        t.chat_trigger_chance = 0.0;
+        chatter(&t, guild, 1, 100.0);
+        t.force_next_roll(0.0);
+        assert_eq!(t.chat_decision(guild, 1, 3, 101.0, false, false), ChatDecision::Chance);
+        t.chat_trigger_chance = 1.0;
+        t.force_next_roll(0.999_999);
+        assert_eq!(t.chat_decision(guild, 1, 3, 102.0, false, false), ChatDecision::Activity);
+    }
+
+    // ---- Tracked maps ----
+
+    /// One record for `user_id` in `guild_id` at `ts` in every tracked map.
+    fn fill_tracked_maps(tracker: &ActivityTrackerRust, guild_id: u64, user_id: u64, ts: f64) {
+        SPAM_TIMESTAMPS.entry((guild_id, user_id)).or_default().push(ts);
+        SPAM_DECAY.insert((guild_id, user_id), (1.0, ts));
+        ATTACHMENTS.entry((guild_id, user_id)).or_default().push_back((ts, 1));
+        VIOLATIONS.insert(user_id, (1.0, ts));
+        CHAT_ACTIVITY.entry(guild_id).or_default().push_back((ts, user_id));
+        CHANNEL_ACTIVITY.entry((guild_id, 1)).or_default().push_back((ts, user_id));
+        SLOWMODE_LEVELS.insert((guild_id, 1), 2);
+        CHAT_COOLDOWNS.insert(guild_id, ts);
+        CHANNEL_COOLDOWNS.insert((guild_id, 1), ts);
+        GUILD_JOINS.entry(guild_id).or_default().push_back((ts, user_id, 0.0));
+        let quiet = QuietHours { start_hour: 22, end_hour: 6, utc_offset_minutes: 0 };
+        QUIET_HOURS.insert(guild_id, quiet);
+        tracker.record_fingerprint(guild_id, user_id, 0xf00d, ts);
+    }
+
+    /// Records per tracked map, counting events in logs like the bulk methods do.
+    fn tracked_records() -> Vec<(&'static str, usize)> {
+        fn events<K: Eq + Hash, T>(map: &DashMap<K, VecDeque<T>>) -> usize {
+            map.iter().map(|log| log.len()).sum()
+        }
+        vec![
+            ("SPAM_TIMESTAMPS", SPAM_TIMESTAMPS.iter().map(|timestamps| timestamps.len()).sum()),
+            ("SPAM_DECAY", SPAM_DECAY.len()),
+            ("ATTACHMENTS", events(&ATTACHMENTS)),
+            ("VIOLATIONS", VIOLATIONS.len()),
+            ("CHAT_ACTIVITY", events(&CHAT_ACTIVITY)),
+            ("CHANNEL_ACTIVITY", events(&CHANNEL_ACTIVITY)),
+            ("SLOWMODE_LEVELS", SLOWMODE_LEVELS.len()),
+            ("CHAT_COOLDOWNS", CHAT_COOLDOWNS.len()),
+            ("CHANNEL_COOLDOWNS", CHANNEL_COOLDOWNS.len()),
+            ("GUILD_JOINS", events(&GUILD_JOINS)),
+            ("QUIET_HOURS", QUIET_HOURS.len()),
+            (
+                "GUILD_CONTENT",
+                GUILD_CONTENT.iter().map(|content| content.hashes.values().map(|entry| entry.posts.len()).sum::<usize>()).sum(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn tracked_maps_cover_every_map() {
+        let _maps = all_maps();
+        let t = tracker();
+        let (kept_guild, left_guild, banned, member) = (356_001, 356_002, 356_101, 356_102);
+        t.clear_all();
+        assert_eq!(TRACKED_MAPS.len(), tracked_records().len());
+        assert!(tracked_records().iter().all(|&(_, n)| n == 0), "{:?}", tracked_records());
+
+        fill_tracked_maps(&t, kept_guild, member, 100.0);
+        fill_tracked_maps(&t, left_guild, member, 100.0);
+        let members_only = tracked_records();
+        fill_tracked_maps(&t, left_guild, banned, 100.0);
+        fill_tracked_maps(&t, left_guild, banned, 101.0);
+
+        // The 100.0 timestamp, attachment, chat and channel event, join and post;
+        // scores and keyed entries were updated at 101.0
+        assert_eq!(t.prune_user_before(banned, 100.5), 6);
+        // What is left: three spam entries, the violation score and four events
+        assert_eq!(t.clear_users(vec![banned]), 8);
+        assert_eq!(tracked_records(), members_only);
+
+        // Every map has one entry for the guild, except the per-user violation score
+        assert_eq!(t.retain_guilds(vec![kept_guild]), TRACKED_MAPS.len() - 1);
+        assert!(!CHAT_ACTIVITY.contains_key(&left_guild) && !GUILD_CONTENT.contains_key(&left_guild));
+        assert_eq!(t.clear_guild(kept_guild), TRACKED_MAPS.len() - 1);
+        assert_eq!(t.cleanup(Some(1e12)), 1);
+        assert!(tracked_records().iter().all(|&(_, n)| n == 0), "{:?}", tracked_records());
+    }
+
+    // ---- Durable state ----
+
+    fn durable_snapshot(quiet_hours: &str, score: f64, gate_flag: &str) -> String {
+        format!(
+            r#"{{"version": {}, "cooldowns": [{{"guild_id": 345001, "last_trigger_ts": 999.0}}],
+            "channel_cooldowns": [{{"guild_id": 345001, "channel_id": 7, "last_trigger_ts": 999.0}}],
+            "violations": [{{"user_id": 345002, "score": {}, "updated_ts": 1000.0}}],
+            "spam_exempt": [345003], "quiet_hours": [{}],
+            "join_gate": {{"min_account_age_secs": 60, "raid_mode_min_age_secs": 120, "flags": ["{}"], "raid_mode": true}}}}"#,
+            DURABLE_STATE_VERSION, score, quiet_hours, gate_flag
+        )
+    }
+
+    #[test]
+    fn durable_state_load_is_all_or_nothing() {
+        let _maps = shared_maps();
+        pyo3::prepare_freethreaded_python();
+        let tracker = tracker();
+        let good_quiet = r#"{"guild_id": 345001, "start_hour": 22, "end_hour": 6, "utc_offset_minutes": 0}"#;
+        let bad_quiet = r#"{"guild_id": 345001, "start_hour": 5, "end_hour": 5, "utc_offset_minutes": 0}"#;
+        Python::with_gil(|py| {
+            let gate = Py::new(py, JoinGate::new("7d", "30d", Vec::new()).unwrap()).unwrap();
+            let load = |data: &str| tracker.load_durable_state(data, Some(1000.0), None, Some(gate.borrow(py)));
+            let nothing_applied = || {
+                assert!(!CHAT_COOLDOWNS.contains_key(&345_001));
+                assert!(!CHANNEL_COOLDOWNS.contains_key(&(345_001, 7)));
+                assert!(!VIOLATIONS.contains_key(&345_002));
+                assert!(!SPAM_EXEMPT.contains(&345_003));
+                assert!(!QUIET_HOURS.contains_key(&345_001));
+                assert!(!gate.borrow(py).lock().raid_mode);
+            };
+
+            assert!(load(&durable_snapshot(bad_quiet, 5.0, "kick_young")).is_err());
+            nothing_applied();
+            assert!(load(&durable_snapshot(good_quiet, -1.0, "kick_young")).is_err());
+            nothing_applied();
+            let error = load(&durable_snapshot(good_quiet, 5.0, "ban_everyone")).unwrap_err();
+            assert!(error.to_string().contains("Unknown join gate flag"), "{}", error);
+            nothing_applied();
+
+            assert_eq!(load(&durable_snapshot(good_quiet, 5.0, "kick_young")).unwrap(), 6);
+            assert_eq!(CHAT_COOLDOWNS.get(&345_001).map(|ts| *ts), Some(999.0));
+            assert!(QUIET_HOURS.contains_key(&345_001) && SPAM_EXEMPT.contains(&345_003));
+            let gate = gate.borrow(py);
+            let settings = gate.lock();
+            assert!(settings.raid_mode && settings.min_account_age_secs == 60);
+        });
+    }
+
+    // ---- Text length and truncation ----
+
+    const FAMILY: &str = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+
+    #[test]
+    fn length_modes_on_tricky_strings() {
+        use LengthMode::{Byte, Char, Grapheme};
+        // (text, bytes, chars, graphemes)
+        let cases = [
+            ("abc", 3, 3, 3),
+            ("e\u{301}", 3, 2, 1),
+            (FAMILY, 18, 5, 1),
+            ("\u{1F1EE}\u{1F1F1}", 8, 2, 1),
+            ("\u{05E9}\u{05DC}\u{05D5}\u{05DD}", 8, 4, 4),
+            ("", 0, 0, 0),
+        ];
+        for (text, bytes, chars, graphemes) in cases {
+            assert_eq!((Byte.len(text), Char.len(text), Grapheme.len(text)), (bytes, chars, graphemes), "{:?}", text);
+        }
+        assert_eq!(display_length(FAMILY), 1);
+    }
+
+    #[test]
+    fn truncate_modes_compared() {
+        use LengthMode::{Byte, Char, Grapheme};
+        let text = format!("{0}{0}abc", FAMILY);
+        assert_eq!(truncate_str(&text, 4, Byte, ""), "\u{1F468}");
+        assert_eq!(truncate_str(&text, 4, Char, ""), "\u{1F468}\u{200D}\u{1F469}\u{200D}");
+        assert_eq!(truncate_str(&text, 4, Grapheme, ""), format!("{0}{0}ab", FAMILY));
+        // Fits in graphemes, not in chars or bytes
+        assert_eq!(truncate_str(&text, 5, Grapheme, "..."), text);
+        assert_ne!(truncate_str(&text, 5, Char, "..."), text);
+        // Byte mode backs off to a character boundary
+        assert_eq!(truncate_str("h\u{E9}llo", 2, Byte, ""), "h");
+        // Grapheme mode keeps a ZWJ sequence whole
+        assert_eq!(truncate_str(&FAMILY.repeat(3), 2, Grapheme, "\u{2026}"), format!("{}\u{2026}", FAMILY));
+        assert!(truncate("x", 1, "words", "").is_err());
+    }
+
+    #[test]
+    fn truncate_suffix_edge_cases() {
+        use LengthMode::{Byte, Char, Grapheme};
+        // A suffix that leaves no room for text gives a plain cut
+        assert_eq!(truncate_str("abcdefgh", 3, Byte, "[...]"), "abc");
+        assert_eq!(truncate_str("abcdefgh", 3, Char, "..."), "abc");
+        assert_eq!(truncate_str("abcdefgh", 0, Grapheme, "..."), "");
+        // An empty suffix is a plain cut at the limit
+        assert_eq!(truncate_str("abcdefgh", 5, Byte, ""), "abcde");
+        assert_eq!(truncate_str("abcdefgh", 8, Byte, "..."), "abcdefgh");
+        // "…" is three bytes but one char
+        assert_eq!(truncate_str("abcdefgh", 5, Byte, "\u{2026}"), "ab\u{2026}");
+        assert_eq!(truncate_str("abcdefgh", 5, Char, "\u{2026}"), "abcd\u{2026}");
+        assert_eq!(truncate_str("abcdefgh", 5, Grapheme, "\u{2026}"), "abcd\u{2026}");
+        assert_eq!(truncate_str("abcdefgh", 3, Byte, "\u{2026}"), "abc");
+        // The suffix itself never gets cut
+        assert_eq!(truncate_str("\u{05E9}\u{05DC}\u{05D5}\u{05DD}!", 7, Byte, "\u{2026}"), "\u{05E9}\u{05DC}\u{2026}");
+        assert_eq!(truncate("abcdefgh", 4, "char", "\u{2026}").unwrap(), "abc\u{2026}");
+    }
+
+    // ---- Text normalization ----
+
+    #[test]
+    fn normalize_text_flags_one_at_a_time() {
+        // Fullwidth C, a decomposed accent, the "fi" ligature, and capital sharp s
+        let text = "  \u{FF23}afe\u{301} \u{FB01}ne\t\t\u{1E9E}  ";
+        let cases = [
+            ((false, false, false, false), text),
+            ((true, false, false, false), "  Caf\u{E9} fine\t\t\u{1E9E}  "),
+            ((false, true, false, false), "  \u{FF43}afe\u{301} fine\t\tss  "),
+            ((false, false, true, false), "\u{FF23}afe\u{301} \u{FB01}ne \u{1E9E}"),
+            ((false, false, false, true), "  \u{FF23}afe \u{FB01}ne\t\t\u{1E9E}  "),
+            ((true, true, true, false), "caf\u{E9} fine ss"),
+            ((true, true, true, true), "cafe fine ss"),
+        ];
+        for (flags, expected) in cases {
+            let (nfkc, casefold, collapse_ws, strip_accents) = flags;
+            assert_eq!(normalize_text(text, nfkc, casefold, collapse_ws, strip_accents), expected, "{:?}", flags);
+        }
+        assert_eq!(normalize_text("STRASSE Stra\u{DF}e", true, true, true, false), "strasse strasse");
+        assert_eq!(normalize_text("\u{3000}a\u{A0}\u{A0}b\n", false, false, true, false), "a b");
+        assert_eq!(normalize_text(" \t\n ", true, true, true, true), "");
+    }
+
+    #[test]
+    fn ascii_phrase_search_across_chunks() {
+        let filler = "the quick brown fox jumps over the lazy dog ".repeat(100);
+        assert!(!contains_casefolded(&filler, "absolutely not there"));
+        // Matches straddling every position around the first chunk boundary
+        for offset in ASCII_CHUNK - 25..=ASCII_CHUNK + 5 {
+            let text = format!("{}Free NITRO giveaway{}", &filler[..offset], &filler[..100]);
+            assert!(contains_casefolded(&text, "free nitro GIVEAWAY"), "offset {}", offset);
+            assert!(!contains_casefolded(&text, "free nitro giveaways!"), "offset {}", offset);
+        }
+        // Phrases too long for the stack buffer are searched in place
+        let long = "Spam ".repeat(ASCII_PHRASE_MAX);
+        assert!(contains_casefolded(&format!("{}{}!", filler, long.to_uppercase()), &long));
+        assert!(!contains_casefolded(&format!("{}{}!", filler, &long[1..]), &long));
+        assert!(contains_casefolded("abc", "") && contains_casefolded("", ""));
+        assert!(!contains_casefolded("ab", "abc"));
+    }
+
+    #[test]
+    fn phrase_search_casefolds_special_letters() {
+        pyo3::prepare_freethreaded_python();
+        let cases = [
+            // Sharp s folds to "ss", from either case
+            ("Die STRASSE", "stra\u{DF}e", true),
+            ("stra\u{DF}e", "STRASSE", true),
+            ("Stra\u{DF}e", "sse", true),
+            ("\u{1E9E}", "ss", true),
+            ("stra\u{DF}e", "stra\u{DF}en", false),
+            // Final, medial, and capital sigma are one letter
+            ("\u{39F}\u{394}\u{39F}\u{3A3}", "\u{3BF}\u{3B4}\u{3BF}\u{3C2}", true),
+            ("\u{3BF}\u{3B4}\u{3BF}\u{3C2}", "\u{3BF}\u{3B4}\u{3BF}\u{3C3}", true),
+            ("\u{3C3}\u{3C2}", "\u{3A3}\u{3A3}", true),
+            // Dotted capital I folds to "i" plus a combining dot, not plain "i"
+            ("\u{130}stanbul", "i\u{307}stanbul", true),
+            ("\u{130}stanbul", "stanbul", true),
+            ("\u{130}stanbul", "istanbul", false),
+            ("istanbul", "\u{130}STANBUL", false),
+            // Dotless i only matches itself
+            ("\u{131}sparta", "\u{131}SPARTA", true),
+            ("\u{131}sparta", "ISPARTA", false),
+            ("ISPARTA", "\u{131}sparta", false),
+        ];
+        Python::with_gil(|py| {
+            let python_contains = py_fn(py, "lambda text, phrase: phrase.casefold() in text.casefold()");
+            for (text, phrase, expected) in cases {
+                assert_eq!(text_contains_phrase(text, phrase), expected, "{:?} in {:?}", phrase, text);
+                let python: bool = python_contains.call1(py, (text, phrase)).unwrap().extract(py).unwrap();
+                assert_eq!(python, expected, "Python disagrees on {:?} in {:?}", phrase, text);
+            }
+        });
+    }
+
+    // ---- Token estimates ----
+
+    // Reference counts from the cl100k_base tokenizer (tiktoken)
+    /// English chat and announcements: within 10%.
+    const ENGLISH_PROSE: &[(&str, usize)] = &[
+        (
+            "The quick brown fox jumps over the lazy dog. Meanwhile, the server kept logging warnings about rate limits, and nobody on the moderation team could figure out why the bot had stopped answering questions in the general channel.",
+            42,
+        ),
+        (
+            "Welcome to the server! Please read the rules before posting. Be respectful to other members, keep discussions on topic, and don't share personal information. If you have any questions, feel free to ping a moderator or open a ticket in the support channel.",
+            51,
+        ),
+        (
+            "I think the new update is pretty good overall, but the voice transcription still misses words when people talk over each other. Also, could we get a setting to turn off the daily reminders? They're kind of annoying when you're in a different timezone.",
+            51,
+        ),
+        (
+            "Yesterday we played for almost six hours straight. It was chaotic, but honestly it was one of the best sessions we've had in months. Next week we're planning to try the new raid, so if anyone wants to join, let me know before Thursday.",
+            52,
+        ),
+    ];
+
+    /// Hebrew, Cyrillic, and Arabic: within 25%.
+    const OTHER_SCRIPT_PROSE: &[(&str, usize)] = &[
+        (
+            "שלום לכולם, ברוכים הבאים לשרת. אנא קראו את החוקים לפני שאתם כותבים בערוצים, ושמרו על שיח מכבד. אם יש לכם שאלות, פנו לאחד המנהלים.",
+            120,
+        ),
+        (
+            "אתמול שיחקנו כמעט שש שעות ברצף. היה בלגן, אבל זה היה אחד המשחקים הכי טובים שהיו לנו כבר הרבה זמן.",
+            92,
+        ),
+        (
+            "Привет всем! Пожалуйста, прочитайте правила перед тем, как писать в каналах, и относитесь друг к другу с уважением.",
+            52,
+        ),
+        (
+            "مرحبا بالجميع، يرجى قراءة القواعد قبل النشر في القنوات والتعامل مع الآخرين باحترام.",
+            60,
+        ),
+    ];
+
+    /// Short messages: within two tokens.
+    const SHORT_MESSAGES: &[(&str, usize)] = &[
+        ("lol", 1),
+        ("ok thanks!!", 3),
+        ("good morning everyone :)", 4),
+        ("brb", 2),
+        ("see you at 9pm", 6),
+        ("gg wp", 2),
+        ("WHAT IS HAPPENING", 5),
+        ("can someone help me?", 5),
+    ];
+
+    #[test]
+    fn token_estimates_match_the_calibration_set() {
+        for (set, max_error) in [(ENGLISH_PROSE, 0.10), (OTHER_SCRIPT_PROSE, 0.25)] {
+            for &(text, actual) in set {
+                let estimate = estimate_tokens(text);
+                let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+                assert!(error <= max_error, "{} estimated for {} tokens: {:?}", estimate, actual, text);
+            }
+        }
+        for &(text, actual) in SHORT_MESSAGES {
+            assert!(estimate_tokens(text).abs_diff(actual) <= 2, "{:?}", text);
+        }
+        let texts: Vec<&str> = SHORT_MESSAGES.iter().map(|(text, _)| *text).collect();
+        assert_eq!(estimate_tokens_batch(texts.clone()), texts.into_iter().map(estimate_tokens).collect::<Vec<_>>());
+    }
+
+    // ---- Durations ----
+
+    /// What `parse_duration` should say, worked out independently in u128.
+    fn expected_duration(text: &str) -> Result<u64, DurationError> {
+        let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+        let Some((digits, unit)) = unsigned.char_indices().last().map(|(i, unit)| (&unsigned[..i], unit)) else {
+            return Err(DurationError::Format);
+        };
+        let multiplier = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(DurationError::Format),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(DurationError::Format);
+        }
+        if unsigned.len() != text.len() {
+            return Err(DurationError::Signed);
+        }
+        let significant = digits.trim_start_matches('0');
+        if significant.len() > 20 {
+            return Err(DurationError::Overflow);
+        }
+        let secs = significant.parse::<u128>().unwrap_or(0) * multiplier;
+        match u64::try_from(secs) {
+            Ok(0) => Err(DurationError::Zero),
+            Ok(secs) => Ok(secs),
+            Err(_) => Err(DurationError::Overflow),
+        }
+    }
+
+    #[test]
+    fn duration_edge_cases() {
+        let cases = [
+            ("10m", Ok(600)),
+            ("1w", Ok(604_800)),
+            ("007s", Ok(7)),
+            ("0s", Err(DurationError::Zero)),
+            ("000d", Err(DurationError::Zero)),
+            ("-5m", Err(DurationError::Signed)),
+            ("+5m", Err(DurationError::Signed)),
+            ("-0s", Err(DurationError::Signed)),
+            ("18446744073709551615s", Ok(u64::MAX)),
+            ("18446744073709551616s", Err(DurationError::Overflow)),
+            ("30500568904943662w", Err(DurationError::Overflow)),
+            ("99999999999999999999999999h", Err(DurationError::Overflow)),
+            ("", Err(DurationError::Format)),
+            ("m", Err(DurationError::Format)),
+            ("5", Err(DurationError::Format)),
+            ("5 m", Err(DurationError::Format)),
+            ("5M", Err(DurationError::Format)),
+            ("--5m", Err(DurationError::Format)),
+            ("\u{0665}m", Err(DurationError::Format)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_duration(text), expected, "{:?}", text);
+            assert_eq!(expected_duration(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn duration_parsing_never_panics() {
+        pyo3::prepare_freethreaded_python();
+        const ALPHABET: &[char] = &['0', '1', '5', '9', '+', '-', 's', 'm', 'h', 'd', 'w', 'x', ' ', '.', '\u{0665}'];
+        let mut rng = SmallRng::seed_from_u64(349);
+        for _ in 0..20_000 {
+            let text: String = if rng.gen_bool(0.5) {
+                let sign = ["", "", "+", "-"][rng.gen_range(0..4)];
+                let digits: String = (0..rng.gen_range(0..26)).map(|_| char::from(b'0' + rng.gen_range(0..10))).collect();
+                let unit = ["s", "m", "h", "d", "w", ""][rng.gen_range(0..6)];
+                format!("{}{}{}", sign, digits, unit)
+            } else {
+                (0..rng.gen_range(0..8)).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())]).collect()
+            };
+            let parsed = parse_duration(&text);
+            assert_eq!(parsed, expected_duration(&text), "{:?}", text);
+            assert_eq!(parse_duration_secs(&text), parsed.ok(), "{:?}", text);
+            match parse_duration_checked(&text) {
+                Ok(secs) => assert_eq!(Ok(secs), parsed),
+                Err(error) => assert!(error.to_string().contains(parsed.unwrap_err().message()), "{}", error),
+            }
+        }
+    }
+
+    // ---- Fingerprints ----
+
+    #[test]
+    fn content_fingerprint_golden_values() {
+        assert_eq!(content_fingerprint("Hello, world!"), Some(2485665350410671572));
+        assert_eq!(content_fingerprint("Free Nitro!! https://x"), Some(11916147255833967660));
+        // Only the normalized words are hashed
+        assert_eq!(content_fingerprint("  HELLO   w\u{F6}rld "), content_fingerprint("Hello, world!"));
+        assert_eq!(content_fingerprint(" \t\n"), None);
+        assert_eq!(char_shingles("abcd", 3, 512).unwrap(), vec![9309506725489486733, 12193023970943911490]);
+        assert_eq!(char_shingles("ab", 3, 512).unwrap(), vec![3327067032081750716]);
+    }
+
+    #[test]
+    fn hmac_fingerprint_golden_values() {
+        pyo3::prepare_freethreaded_python();
+        assert_eq!(
+            hmac_fingerprint("Hello, world!", b"key").unwrap().as_deref(),
+            Some("0ba06f1f9a6300461e43454535dc3c4223e47b1d357073d7536eae90ec095be1")
+        );
+        // A key longer than the 64-byte block is hashed first
+        let long_key: Vec<u8> = (0..100).collect();
+        assert_eq!(
+            hmac_fingerprint("Hello, world!", &long_key).unwrap().as_deref(),
+            Some("3450b7b0ed70c606ca8da5875062d9b904f53e0853b4d6e9b9700a3c53545db2")
+        );
+        assert_eq!(hmac_fingerprint(" ", b"key").unwrap(), None);
+        assert!(hmac_fingerprint("Hello", b"").is_err());
+    }
+
+    #[test]
+    fn hmac_sha256_rfc_4231_vectors() {
+        let long_key = [0xaa; 131];
+        // Test case 5 (truncated output) is left out
+        let cases: [(&[u8], &[u8], &str); 6] = [
+            (&[0x0b; 20], b"Hi There", "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (&[0xaa; 20], &[0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            (
+                &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25],
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &long_key,
+                b"This is a test using a larger than block-size key and a larger than block-size data. \
+                  The key needs to be hashed before being used by the HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hmac_sha256_hex(key, message), expected);
+        }
+    }
+
+    // ---- RateLimiter ----
+
+    #[test]
+    fn rate_limiter_rejects_bad_token_costs() {
+        let limiter = RateLimiter::new(2.0, 1.0, 600.0).unwrap();
+        for tokens in [-100.0, 0.0, f64::NAN, f64::INFINITY, -f64::NAN] {
+            assert!(limiter.try_acquire(1, 0.0, tokens).is_err(), "tokens={}", tokens);
+            assert!(limiter.time_until_available(1, 0.0, tokens).is_err(), "tokens={}", tokens);
+        }
+        // Nothing was minted: the bucket still holds exactly its capacity
+        assert!(limiter.try_acquire(1, 0.0, 1.0).unwrap());
+        assert!(limiter.try_acquire(1, 0.0, 1.0).unwrap());
+        assert!(!limiter.try_acquire(1, 0.0, 1.0).unwrap());
+        assert_eq!(limiter.time_until_available(1, 0.0, 0.5).unwrap(), 0.5);
+    }
+
+    #[test]
+    fn rate_limiter_rejects_bad_idle_ttl() {
+        for ttl in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(2.0, 1.0, ttl).is_err(), "idle_ttl_secs={}", ttl);
+        }
+    }
+
+    // ---- TranscriptionRecord ----
+
+    fn full_record() -> TranscriptionRecord {
+        TranscriptionRecord::new(
+            1, 2, 3, "h\u{e9}llo \"there\"\n".to_string(), "n\u{f6}ck".to_string(), 4.25, Some(1_700_000_000.5),
+            Some("de".to_string()), Some(0.875),
+        )
+        .unwrap()
+    }
+
+    fn bare_record() -> TranscriptionRecord {
+        TranscriptionRecord::new(u64::MAX, 0, 7, String::new(), String::new(), 0.0, None, None, None).unwrap()
+    }
+
+    fn assert_same_record(a: &TranscriptionRecord, b: &TranscriptionRecord) {
+        assert_eq!((a.guild_id, a.channel_id, a.user_id), (b.guild_id, b.channel_id, b.user_id));
+        assert_eq!((&a.content, &a.username), (&b.content, &b.username));
+        assert_eq!(a.duration_secs.to_bits(), b.duration_secs.to_bits());
+        assert_eq!(a.message_ts.map(f64::to_bits), b.message_ts.map(f64::to_bits));
+        assert_eq!(a.language, b.language);
+        assert_eq!(a.confidence.map(f64::to_bits), b.confidence.map(f64::to_bits));
+    }
+
+    #[test]
+    fn transcription_record_json_round_trip() {
+        for record in [full_record(), bare_record()] {
+            let json = record.to_json().unwrap();
+            assert_same_record(&TranscriptionRecord::from_json(&json).unwrap(), &record);
+        }
+        let json = bare_record().to_json().unwrap();
+        assert!(json.contains("\"message_ts\":null"), "{}", json);
+        assert!(TranscriptionRecord::from_json(&json.replace("\"confidence\":null", "\"confidence\":1.5")).is_err());
+        // Records journaled before the optional fields existed still load
+        let old = TranscriptionRecord::from_json(
+            r#"{"guild_id":1,"channel_id":2,"user_id":3,"content":"x","username":"u","duration_secs":1.0}"#,
+        )
+        .unwrap();
+        assert_eq!((old.message_ts, old.language.as_deref(), old.confidence), (None, None, None));
+    }
+
+    #[test]
+    fn transcription_op_journal_round_trip() {
+        let mut bytes = String::new();
+        bytes += &Journal::op_line(1, 3, &DbWriteOp::Transcription(full_record())).unwrap();
+        bytes += &Journal::op_line(2, -1, &DbWriteOp::Transcription(bare_record())).unwrap();
+        assert!(bytes.contains("\"kind\":\"transcription\""), "{}", bytes);
+        let (live, skipped) = Journal::live_ops(bytes.as_bytes());
+        assert_eq!(skipped, 0);
+        assert_eq!(live.len(), 2);
+        for ((seq, priority, op), (want_seq, want_priority, want)) in
+            live.iter().zip([(1, 3, full_record()), (2, -1, bare_record())])
+        {
+            assert_eq!((*seq, *priority), (want_seq, want_priority));
+            match op {
+                DbWriteOp::Transcription(record) => assert_same_record(record, &want),
+                other => panic!("replayed a {} op", other.kind()),
+            }
+        }
+    }
+
+    // ---- DatabaseWriter ----
+
+    /// A Python callable from a lambda expression.
+    fn py_fn(py: Python<'_>, source: &str) -> Py<PyAny> {
+        py.eval(source, None, None).unwrap().into()
+    }
+
+    /// A writer with the given generic handler, one attempt per op, and no linger.
+    fn writer(py: Python<'_>, generic_handler: Option<Py<PyAny>>, workers: usize) -> DatabaseWriter {
+        DatabaseWriter::new(
+            py, None, generic_handler, None, 256, 0.0, None, "block", 5.0, 1, 0.0, None, None, workers, None, false,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flush_fails_fast_when_workers_are_dead() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let writer = writer(py, None, 1);
+            // Stop the worker, then strand a write behind it as a crash would
+            writer.begin_close(true);
+            assert!(py.allow_threads(|| join_workers(&writer.shared, &writer.workers, None)));
+            writer.shared.start(&DbWriteOp::Generic { table: "t".to_string(), data: "{}".to_string() });
+            let started = Instant::now();
+            let error = writer.flush(py, Some(5.0), None).unwrap_err();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            assert!(error.to_string().contains("1 of 1 DB writer workers are not running"), "{}", error);
+        });
+    }
+
+    #[test]
+    fn huge_timeouts_wait_forever() {
+        assert!(deadline_after("timeout_secs", Some(1e19)).unwrap().is_none());
+        assert!(deadline_after("timeout_secs", Some(1e300)).unwrap().is_none());
+        assert!(deadline_after("timeout_secs", Some(1.0)).unwrap().is_some());
+        assert!(deadline_after("timeout_secs", Some(-1.0)).is_err());
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let writer = writer(py, Some(py_fn(py, "lambda table, data: None")), 1);
+            writer.queue_write(py, "t".to_string(), "{}".to_string(), 0, None).unwrap();
+            assert!(writer.flush(py, Some(1e19), None).unwrap());
+            assert!(writer.flush(py, Some(1e300), None).unwrap());
+            assert!(writer.close(py, true, 1e300).unwrap());
+        });
+    }
+
+    #[test]
+    fn flush_times_out_on_a_slow_worker() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let writer = writer(py, Some(py_fn(py, "lambda table, data: __import__('time').sleep(0.5)")), 1);
+            writer.queue_write(py, "t".to_string(), "{}".to_string(), 0, None).unwrap();
+            assert!(!writer.flush(py, Some(0.05), None).unwrap());
+            assert!(writer.flush(py, Some(10.0), None).unwrap());
+        });
+    }
+
+    #[test]
+    fn huge_linger_waits_for_a_full_batch() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let writer = DatabaseWriter::new(
+                py, None, Some(py_fn(py, "lambda table, data: None")), None, 2, 1e300, None, "block", 5.0, 1, 0.0,
+                None, None, 1, None, false, None,
+            )
+            .unwrap();
+            for _ in 0..2 {
+                writer.queue_write(py, "t".to_string(), "{}".to_string(), 0, None).unwrap();
+            }
+            assert!(writer.flush(py, Some(10.0), None).unwrap());
+            assert_eq!(writer.batches_committed(), 1);
+            assert!(writer.close(py, true, 10.0).unwrap());
+        });
+    }
+
+    #[test]
+    fn huge_block_timeout_waits_for_room() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let handler = py_fn(py, "lambda table, data: __import__('time').sleep(0.01)");
+            let writer = DatabaseWriter::new(
+                py, None, Some(handler), None, 1, 0.0, Some(1), "block", 1e300, 1, 0.0, None, None, 1, None, false,
+                None,
+            )
+            .unwrap();
+            for _ in 0..5 {
+                writer.queue_write(py, "t".to_string(), "{}".to_string(), 0, None).unwrap();
+            }
+            assert!(writer.flush(py, Some(10.0), None).unwrap());
+            assert_eq!(writer.shared.dropped_count.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    fn queued(priority: i32, n: u32) -> QueuedOp {
+        let op = DbWriteOp::Generic { table: "t".to_string(), data: n.to_string() };
+        QueuedOp { op, priority, queued_at: Instant::now(), seq: None }
+    }
+
+    fn data(queued: QueuedOp) -> String {
+        match queued.op {
+            DbWriteOp::Generic { data, .. } => data,
+            _ => panic!("expected a generic op"),
+        }
+    }
+
+    #[test]
+    fn priority_ops_order() {
+        let mut ops = PriorityOps::default();
+        for (priority, n) in [(0, 1), (10, 2), (-5, 3), (10, 4), (0, 5), (-5, 6)] {
+            ops.push(queued(priority, n));
+        }
+        assert_eq!((ops.len(), ops.least_priority()), (6, Some(-5)));
+        // Oldest of the lowest level goes first when sacrificing
+        assert_eq!(data(ops.pop_least().unwrap()), "3");
+        // Highest level first, FIFO within a level
+        let order: Vec<String> = std::iter::from_fn(|| ops.pop_next()).map(data).collect();
+        assert_eq!(order, ["2", "4", "1", "5", "6"]);
+        assert!(ops.is_empty() && ops.least_priority().is_none());
+    }
+
+    #[test]
+    fn drop_oldest_never_evicts_higher_priorities() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let threading = py.import("threading").unwrap();
+            let (started, release) = (threading.call_method0("Event").unwrap(), threading.call_method0("Event").unwrap());
+            let seen = pyo3::types::PyList::empty(py);
+            let handler = py
+                .eval(
+                    "lambda started, release, seen: lambda table, data: (seen.append(data), started.set(), release.wait(10))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .call1((started, release, seen))
+                .unwrap();
+            let writer = DatabaseWriter::new(
+                py, None, Some(handler.into()), None, 1, 0.0, Some(3), "drop_oldest", 5.0, 1, 0.0, None, None, 1, None,
+                false, None,
+            )
+            .unwrap();
+            let queue = |data: &str, priority: i32| {
+                writer.queue_write(py, "t".to_string(), format!("{:?}", data), priority, None).unwrap();
+            };
+            // Hold the worker on the first write so the rest stay queued
+            queue("inflight", 0);
+            assert!(started.call_method1("wait", (10.0,)).unwrap().is_true().unwrap());
+            for data in ["mod0", "mod1", "mod2"] {
+                queue(data, 10);
+            }
+            // Everything queued outranks it, so the new write is the one dropped
+            queue("bulk", 0);
+            assert_eq!(writer.dropped_count(), 1);
+            // Outranks the queue, so the oldest of the lowest level makes room
+            queue("urgent", 20);
+            assert_eq!(writer.dropped_count(), 2);
+            release.call_method0("set").unwrap();
+            assert!(writer.flush(py, Some(10.0), None).unwrap());
+            let written: Vec<String> = seen.extract().unwrap();
+            assert_eq!(written, ["inflight", "urgent", "mod1", "mod2"]);
+            assert_eq!(writer.pending_writes(), 0);
+        });
+    }
+
+    #[test]
+    fn huge_dedup_window_saturates() {
+        let window = DedupWindow::new(1e300);
+        assert!(!window.check(1));
+        assert!(window.check(1));
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let writer = DatabaseWriter::new(
+                py, None, None, None, 256, 0.0, None, "block", 5.0, 1, 0.0, None, None, 1, None, false, Some(1e300),
+            );
+            assert!(writer.is_ok());
+        });
+    }
+
+    #[test]
+    fn flush_holding_the_gil_drains_python_handlers() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let seen = pyo3::types::PyList::empty(py);
+            let handler = py.eval("lambda seen: lambda table, data: seen.append(table)", None, None).unwrap();
+            let writer = writer(py, Some(handler.call1((seen,)).unwrap().into()), 2);
+            for i in 0..100 {
+                writer.queue_write(py, format!("t{}", i % 7), "{}".to_string(), i % 3, None).unwrap();
+            }
+            assert!(writer.flush(py, Some(30.0), None).unwrap());
+            assert_eq!(writer.pending_writes(), 0);
+            assert_eq!(seen.len(), 100);
+        });
+    }
+
+    #[test]
+    fn flush_holding_the_gil_after_evictions() {
+        pyo3::prepare_freethreaded_python();
+        let journal = std::env::temp_dir().join(format!("guildest-333-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&journal);
+        Python::with_gil(|py| {
+            let handler = py_fn(py, "lambda table, data: __import__('time').sleep(0.001)");
+            let on_error = py_fn(py, "lambda *args: None");
+            let writer = DatabaseWriter::new(
+                py, None, Some(handler), None, 256, 0.0, Some(4), "drop_oldest", 5.0, 1, 0.0, Some(on_error), None, 1,
+                Some(journal.to_string_lossy().into_owned()), false, None,
+            )
+            .unwrap();
+            for i in 0..100 {
+                writer.queue_write(py, "t".to_string(), format!("{{\"n\":{}}}", i), 0, None).unwrap();
+            }
+            assert!(writer.flush(py, Some(30.0), None).unwrap());
+            assert_eq!(writer.pending_writes(), 0);
+            assert!(writer.shared.dropped_count.load(Ordering::SeqCst) > 0);
+            assert!(writer.close(py, true, 10.0).unwrap());
+        });
+        let _ = fs::remove_file(&journal);
+    }
+
+    #[test]
+    fn pending_count_survives_concurrent_producers() {
+        pyo3::prepare_freethreaded_python();
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 500;
+        const TOTAL: usize = THREADS * PER_THREAD;
+        let writer = Python::with_gil(|py| writer(py, Some(py_fn(py, "lambda table, data: None")), 4));
+        let producing = AtomicUsize::new(THREADS);
+        thread::scope(|scope| {
+            for index in 0..THREADS {
+                let (writer, producing) = (&writer, &producing);
+                scope.spawn(move || {
+                    for i in 0..PER_THREAD {
+                        Python::with_gil(|py| {
+                            let table = format!("t{}", (index + i) % 5);
+                            writer.queue_write(py, table, "{}".to_string(), (i % 3) as i32, None).unwrap();
+                        });
+                    }
+                    producing.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            // A drifting counter would wrap past the number of writes ever queued
+            scope.spawn(|| {
+                while producing.load(Ordering::SeqCst) > 0 {
+                    assert!(writer.pending_writes() <= TOTAL);
+                    assert!(writer.pending_writes_detailed().values().all(|&n| n <= TOTAL));
+                    thread::yield_now();
+                }
+            });
+        });
+        assert!(Python::with_gil(|py| writer.flush(py, Some(30.0), None)).unwrap());
+        assert_eq!(writer.pending_writes(), 0);
+        assert!(writer.pending_writes_detailed().values().all(|&n| n == 0));
+        assert_eq!(writer.shared.total_queued.load(Ordering::SeqCst), TOTAL);
+        assert_eq!(writer.shared.total_committed.load(Ordering::SeqCst), TOTAL);
+        assert_eq!(writer.failed_writes(), 0);
+    }
+
+    #[test]
+    fn journal_skips_corrupt_records() {
+        let op = |n: u32| DbWriteOp::Generic { table: "t".to_string(), data: format!("{{\"n\":{}}}", n) };
+        let mut bytes = String::new();
+        bytes += &Journal::op_line(1, 0, &op(1)).unwrap();
+        bytes += "{\"seq\": 2, garbage\n";
+        bytes += &Journal::op_line(3, 5, &op(3)).unwrap();
+        bytes += "\n";
+        bytes += &Journal::op_line(4, 0, &op(4)).unwrap();
+        bytes += "{\"done\":[1]}\n";
+        // Torn by a crash mid-write: no newline, not counted
+        bytes += &Journal::op_line(5, 0, &op(5)).unwrap()[..20];
+        let (live, skipped) = Journal::live_ops(bytes.as_bytes());
+        assert_eq!(skipped, 2);
+        let seqs: Vec<(u64, i32)> = live.iter().map(|(seq, priority, _)| (*seq, *priority)).collect();
+        assert_eq!(seqs, vec![(3, 5), (4, 0)]);
+    }
+
+    // ---- ActionScheduler ----
+
+    #[test]
+    fn scheduler_rejects_non_finite_fire_times() {
+        let scheduler = ActionScheduler::new();
+        scheduler.schedule(1, 10.0, "a".to_string()).unwrap();
+        for fire_at in [f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(scheduler.schedule(2, fire_at, "bad".to_string()).is_err());
+        }
+        assert_eq!(scheduler.due(10.0), vec![(1, "a".to_string())]);
+        assert_eq!(scheduler.__len__(), 0);
+    }
+
+    #[test]
+    fn scheduler_load_is_all_or_nothing() {
+        let scheduler = ActionScheduler::new();
+        let snapshot = format!(
+            r#"{{"version":{},"actions":[{{"action_id":1,"fire_at":5.0,"payload":"a"}},{{"action_id":2,"fire_at":1e999,"payload":"b"}}]}}"#,
+            SCHEDULER_STATE_VERSION
+        );
+        assert!(scheduler.load(&snapshot).is_err());
+        assert_eq!(scheduler.__len__(), 0);
+    }
 }
@@ -0,0 +1,107 @@
+"""DatabaseWriter behavior at interpreter shutdown, with writes still queued.
+
+Each scenario runs in a fresh interpreter, since finalization is what is
+under test. A worker that takes the GIL while the interpreter finalizes
+aborts the process with "Fatal Python error", so every run must exit 0
+with no such line on stderr.
+
+Build the extension first (``maturin develop``), then run:
+
+    python -m unittest discover -s tests
+"""
+
+from __future__ import annotations
+
+import os
+import subprocess
+import sys
+import tempfile
+import textwrap
+import unittest
+
+try:
+    import guildest_core  # noqa: F401
+except ImportError:
+    guildest_core = None
+
+WRITES = 500
+
+# Slow enough (5s of handler time) that the 2s drain at exit can't finish
+SCRIPT = textwrap.dedent(
+    """
+    import atexit, sys, time
+
+    done = []
+    # Registered before the import, so it runs after the module's exit hook
+    atexit.register(lambda: print("handled", len(done), file=sys.stderr))
+
+    import guildest_core
+
+    def handler(table, data):
+        time.sleep(0.01)
+        done.append(data)
+
+    mode, journal = sys.argv[1], sys.argv[2] or None
+    writer = guildest_core.DatabaseWriter(generic_handler=handler, journal_path=journal, max_batch=8)
+    print("replayed", writer.replayed_count(), file=sys.stderr)
+    if mode == "replay":
+        print("flushed", writer.flush(timeout_secs=60.0), file=sys.stderr)
+        writer.close()
+    else:
+        for i in range(%d):
+            writer.queue_write("t", '{"i": %%d}' %% i)
+        if mode == "atexit":
+            atexit.register(lambda: print("closed", writer.close(timeout_secs=0.1), file=sys.stderr))
+    """
+    % WRITES
+)
+
+
+def run(mode: str, journal: str = "") -> tuple[dict[str, str], str]:
+    """Run SCRIPT in a child interpreter; return its `name value` stderr lines and all of stderr."""
+    result = subprocess.run(
+        [sys.executable, "-c", SCRIPT, mode, journal],
+        capture_output=True,
+        text=True,
+        timeout=120,
+        env={**os.environ, "PYTHONFAULTHANDLER": "1"},
+    )
+    message = f"exit {result.returncode}\n{result.stderr}"
+    assert result.returncode == 0, message
+    assert "Fatal Python error" not in result.stderr, message
+    lines = (line.split(" ", 1) for line in result.stderr.splitlines())
+    return {parts[0]: parts[1] for parts in lines if len(parts) == 2}, result.stderr
+
+
+@unittest.skipIf(guildest_core is None, "guildest_core is not built")
+class ShutdownTest(unittest.TestCase):
+    def test_close_from_atexit_with_writes_queued(self) -> None:
+        report, stderr = run("atexit")
+        # The 0.1s close can't drain 500 slow writes
+        self.assertEqual(report["closed"], "False")
+        self.assertLess(int(report["handled"]), WRITES)
+        self.assertIn("writes skipped at interpreter shutdown", stderr)
+
+    def test_exit_without_close(self) -> None:
+        report, stderr = run("noclose")
+        self.assertLess(int(report["handled"]), WRITES)
+        self.assertIn("writes skipped at interpreter shutdown", stderr)
+
+    def test_journal_replays_writes_skipped_at_exit(self) -> None:
+        with tempfile.TemporaryDirectory() as tmp:
+            journal = os.path.join(tmp, "writes.journal")
+            first, stderr = run("noclose", journal)
+            self.assertIn("kept in the journal for replay", stderr)
+            second, _ = run("replay", journal)
+            self.assertEqual(second["flushed"], "True")
+            replayed = int(second["replayed"])
+            self.assertGreater(replayed, 0)
+            self.assertEqual(int(second["handled"]), replayed)
+            # Done marks aren't synced, so a committed write may come back; none may be lost
+            self.assertGreaterEqual(int(first["handled"]) + replayed, WRITES)
+            third, _ = run("replay", journal)
+            self.assertEqual(third["replayed"], "0")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -6,12 +6,17 @@ from typing import Optional
 from dataclasses import dataclass
 
 from config import GROQ_API_KEY
+from db.engine import get_db_path, using_postgres
 from db.transcriptions import save_transcription, start_voice_session, end_voice_session
 
 # Try to use Rust database writer if available
 try:
     from guildest_core import DatabaseWriter
-    _db_writer: Optional["DatabaseWriter"] = DatabaseWriter()
+    # Native SQLite inserts keep the GIL free; Postgres still goes through Python
+    _db_writer: Optional["DatabaseWriter"] = DatabaseWriter(
+        transcription_handler=save_transcription,
+        db_path=None if using_postgres() else str(get_db_path("transcriptions.db")),
+    )
     _USE_RUST_DB = True
 except ImportError:
     _db_writer = None
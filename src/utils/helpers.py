@@ -32,12 +32,24 @@ def truncate(text: str, limit: int = 1700) -> str:
 
 
 def parse_duration(duration: str) -> Optional[timedelta]:
-    """Parse a duration string like '10m', '2h', '1d' into a timedelta."""
+    """Parse a duration string like '10m', '2h', '1d' into a timedelta.
+
+    Zero, signed, and too-large durations return None.
+    """
     if _USE_RUST:
         secs = _rust_parse_duration_secs(duration)
-        return timedelta(seconds=secs) if secs is not None else None
+    else:
+        secs = _parse_duration_secs(duration)
+    if not secs:
+        return None
+    try:
+        return timedelta(seconds=secs)
+    except OverflowError:
+        return None
+
 
-    match = re.match(r"^(\d+)([smhdw])$", duration)
+def _parse_duration_secs(duration: str) -> Optional[int]:
+    match = re.match(r"^([0-9]+)([smhdw])$", duration)
     if not match:
         return None
 
@@ -52,12 +64,11 @@ def parse_duration(duration: str) -> Optional[timedelta]:
         "w": 60 * 60 * 24 * 7,
     }
 
-    seconds = value * multipliers[unit]
-    return timedelta(seconds=seconds)
+    return value * multipliers[unit]
 
 
 def text_contains_phrase(text: str | None, phrase: str) -> bool:
     """Check if text contains phrase (case-insensitive)."""
     if _USE_RUST and text is not None:
         return _rust_text_contains_phrase(text, phrase)
-    return text is not None and phrase in text.lower()
+    return text is not None and phrase.casefold() in text.casefold()